@@ -0,0 +1,462 @@
+//! Alert rules on the readings, running a command when an alert fires and when it clears.
+//!
+//! Each rule has a limit to fire and a clear limit, for hysteresis,
+//! and fires or clears only after its condition holds for `debounce` consecutive readings,
+//! so that a noisy reading does not repeat the alert.
+use super::burst::is_error_code;
+use super::events::EventLog;
+use chrono::prelude::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Condition of an alert rule.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Condition {
+    /// Load at or above the limit, in kg.
+    Above,
+    /// Load at or below the limit, in kg.
+    Below,
+    /// Absolute rate of change between consecutive valid readings at or above the limit, in kg/min.
+    Rate,
+    /// Consecutive error codes at or above the limit.
+    Errors,
+    /// Seconds without valid reading at or above the limit, checked every second.
+    Stale,
+}
+
+impl std::str::FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Condition, String> {
+        match s.to_lowercase().as_str() {
+            "above" => Ok(Condition::Above),
+            "below" => Ok(Condition::Below),
+            "rate" => Ok(Condition::Rate),
+            "errors" => Ok(Condition::Errors),
+            "stale" => Ok(Condition::Stale),
+            c => Err(format!(
+                "invalid alert condition {}, use above, below, rate, errors, or stale",
+                c
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Condition::Above => "above",
+            Condition::Below => "below",
+            Condition::Rate => "rate",
+            Condition::Errors => "errors",
+            Condition::Stale => "stale",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Alert rule read from the rules file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub name: String,
+    /// Device name, or * for all the devices.
+    pub device: String,
+    pub condition: Condition,
+    pub limit: f64,
+    /// Limit to clear the alert, past the limit to fire it on the safe side.
+    pub clear: f64,
+    /// Consecutive readings to fire or clear the alert, at least one.
+    pub debounce: u32,
+    /// Shell command, run with the alert details in the environment, none to only write the event.
+    pub command: Option<String>,
+}
+
+/// Read the alert rules from a csv file with name, device, condition, limit, clear limit,
+/// debounce, and command, e.g., `tipped,lysimeter1,below,500,600,3,/usr/local/bin/notify.sh`.
+/// The clear limit defaults to the limit and the debounce to one;
+/// the command is the rest of the line and can contain commas.
+/// Empty lines and lines starting with # are ignored.
+pub fn read_alert_rules<P>(fin: P) -> Vec<Rule>
+where
+    P: AsRef<Path>,
+{
+    let file = File::open(fin).unwrap();
+    let buf = BufReader::new(file);
+    let mut rules: Vec<Rule> = Vec::new();
+    for l in buf.lines() {
+        let l_unwrap = l.unwrap();
+        let l_trim = l_unwrap.trim();
+        if l_trim.is_empty() || l_trim.starts_with('#') {
+            continue;
+        }
+        rules
+            .push(parse_rule(l_trim).unwrap_or_else(|e| panic!("{} in alert line: {}", e, l_trim)));
+    }
+    rules
+}
+
+fn parse_rule(line: &str) -> Result<Rule, String> {
+    let fields: Vec<&str> = line.splitn(7, ',').map(|f| f.trim()).collect();
+    if fields.len() < 4 {
+        return Err(String::from(
+            "expected name, device, condition, limit, clear limit, debounce, and command",
+        ));
+    }
+    let number = |s: &str| {
+        s.parse::<f64>()
+            .map_err(|e| format!("invalid limit {}, {}", s, e))
+    };
+    let limit = number(fields[3])?;
+    let clear = match fields.get(4) {
+        Some(c) if !c.is_empty() => number(c)?,
+        _ => limit,
+    };
+    let debounce = match fields.get(5) {
+        Some(d) if !d.is_empty() => d
+            .parse::<u32>()
+            .map_err(|e| format!("invalid debounce {}, {}", d, e))?
+            .max(1),
+        _ => 1,
+    };
+    let condition = fields[2].parse::<Condition>()?;
+    let safe = match condition {
+        Condition::Below => clear >= limit,
+        _ => clear <= limit,
+    };
+    if !safe {
+        return Err(format!(
+            "clear limit {} on the alert side of the limit {}",
+            clear, limit
+        ));
+    }
+    Ok(Rule {
+        name: String::from(fields[0]),
+        device: String::from(fields[1]),
+        condition,
+        limit,
+        clear,
+        debounce,
+        command: fields
+            .get(6)
+            .filter(|c| !c.is_empty())
+            .map(|c| String::from(*c)),
+    })
+}
+
+/// Alert that fired or cleared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alert {
+    pub name: String,
+    pub device: String,
+    pub condition: Condition,
+    /// True when fired, false when cleared.
+    pub active: bool,
+    pub value: f64,
+    pub limit: f64,
+    pub datetime: DateTime<Local>,
+}
+
+/// State of a rule for one device.
+#[derive(Debug)]
+struct RuleState {
+    rule: Rule,
+    device: String,
+    active: bool,
+    /// Consecutive readings toward the change of state.
+    pending: u32,
+    last_valid: Option<(DateTime<Local>, f64)>,
+    errors: u32,
+    since: DateTime<Local>,
+}
+
+impl RuleState {
+    /// Value of the rule for the reading, none if it does not apply.
+    fn value(&mut self, dtr: DateTime<Local>, w: f64) -> Option<f64> {
+        let valid = !is_error_code(w);
+        let previous = self.last_valid;
+        if valid {
+            self.last_valid = Some((dtr, w));
+            self.errors = 0;
+        } else {
+            self.errors += 1;
+        }
+        match self.rule.condition {
+            Condition::Above | Condition::Below if valid => Some(w),
+            Condition::Rate if valid => previous.and_then(|(t, p)| {
+                let minutes = (dtr - t).num_milliseconds() as f64 / 60000.;
+                if minutes > 0. {
+                    Some((w - p).abs() / minutes)
+                } else {
+                    None
+                }
+            }),
+            Condition::Errors => Some(self.errors as f64),
+            _ => None,
+        }
+    }
+
+    /// Seconds without valid reading, since the start if none.
+    fn stale(&self, now: DateTime<Local>) -> f64 {
+        let since = self.last_valid.map(|(t, _)| t).unwrap_or(self.since);
+        (now - since).num_milliseconds() as f64 / 1000.
+    }
+
+    /// Count the value toward the change of state, return the new state if it changed.
+    fn evaluate(&mut self, value: f64, debounce: u32) -> Option<bool> {
+        let rule = &self.rule;
+        let (fire, clear) = match rule.condition {
+            Condition::Below => (value <= rule.limit, value > rule.clear),
+            _ => (value >= rule.limit, value < rule.clear),
+        };
+        if (self.active && clear) || (!self.active && fire) {
+            self.pending += 1;
+        } else {
+            self.pending = 0;
+        }
+        if self.pending >= debounce {
+            self.active = !self.active;
+            self.pending = 0;
+            return Some(self.active);
+        }
+        None
+    }
+
+    fn alert(&self, value: f64, datetime: DateTime<Local>) -> Alert {
+        Alert {
+            name: self.rule.name.clone(),
+            device: self.device.clone(),
+            condition: self.rule.condition,
+            active: self.active,
+            value,
+            limit: if self.active {
+                self.rule.limit
+            } else {
+                self.rule.clear
+            },
+            datetime,
+        }
+    }
+}
+
+/// Alert rules of the devices, shared by the logger threads and the watcher of the stale rules.
+/// The empty alerts, the default, have no rules.
+#[derive(Debug, Clone, Default)]
+pub struct Alerts {
+    states: Option<Arc<Mutex<Vec<RuleState>>>>,
+    device: Option<String>,
+    events: EventLog,
+}
+
+impl Alerts {
+    /// Alerts of the rules for the devices, the rules for * apply to each device.
+    pub fn new(
+        rules: &[Rule],
+        devices: &[String],
+        events: EventLog,
+        now: DateTime<Local>,
+    ) -> Alerts {
+        let mut states = Vec::new();
+        for rule in rules.iter() {
+            let matching = devices
+                .iter()
+                .filter(|d| rule.device == "*" || rule.device == **d);
+            for device in matching {
+                states.push(RuleState {
+                    rule: rule.clone(),
+                    device: device.clone(),
+                    active: false,
+                    pending: 0,
+                    last_valid: None,
+                    errors: 0,
+                    since: now,
+                });
+            }
+        }
+        Alerts {
+            states: Some(Arc::new(Mutex::new(states))),
+            device: None,
+            events,
+        }
+    }
+
+    /// Alerts without rules.
+    pub fn none() -> Alerts {
+        Alerts::default()
+    }
+
+    /// Same alerts, checking the readings of the device.
+    pub fn for_device(&self, device: &str) -> Alerts {
+        Alerts {
+            states: self.states.clone(),
+            device: Some(String::from(device)),
+            events: self.events.clone(),
+        }
+    }
+
+    /// Check the rules of the device with the reading written for the scheduled time,
+    /// running the commands of the alerts that fired or cleared.
+    pub fn reading(&self, dtr: DateTime<Local>, w: f64, raw_reading: &str) -> Vec<Alert> {
+        let (states, device) = match (&self.states, &self.device) {
+            (Some(s), Some(d)) => (s, d),
+            _ => return Vec::new(),
+        };
+        let mut alerts = Vec::new();
+        for state in states.lock().unwrap().iter_mut() {
+            if state.device != *device {
+                continue;
+            }
+            if let Some(value) = state.value(dtr, w) {
+                if state.evaluate(value, state.rule.debounce).is_some() {
+                    let alert = state.alert(value, dtr);
+                    self.notify(
+                        &alert,
+                        state.rule.command.as_deref(),
+                        Some((w, raw_reading)),
+                    );
+                    alerts.push(alert);
+                }
+            }
+        }
+        alerts
+    }
+
+    /// Check the stale rules of all the devices, without debounce.
+    pub fn tick(&self, now: DateTime<Local>) -> Vec<Alert> {
+        let states = match &self.states {
+            Some(s) => s,
+            None => return Vec::new(),
+        };
+        let mut alerts = Vec::new();
+        for state in states.lock().unwrap().iter_mut() {
+            if state.rule.condition != Condition::Stale {
+                continue;
+            }
+            let value = state.stale(now);
+            if state.evaluate(value, 1).is_some() {
+                let alert = state.alert(value, now);
+                self.notify(&alert, state.rule.command.as_deref(), None);
+                alerts.push(alert);
+            }
+        }
+        alerts
+    }
+
+    /// Check the stale rules every second, in a background thread.
+    pub fn watch(&self) {
+        let alerts = self.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(Duration::from_secs(1));
+            alerts.tick(Local::now());
+        });
+    }
+
+    /// Print and write the alert to the event log, then run its command, if any,
+    /// in the background with the details in the FLINTEC_* environment variables.
+    fn notify(&self, alert: &Alert, command: Option<&str>, reading: Option<(f64, &str)>) {
+        let state = if alert.active { "fired" } else { "cleared" };
+        let datetime = alert.datetime.to_rfc3339_opts(SecondsFormat::Secs, false);
+        println!(
+            "alert {} {} for device {}, {} {} with limit {} at {}",
+            alert.name, state, alert.device, alert.condition, alert.value, alert.limit, datetime
+        );
+        let fields = [
+            ("alert", alert.name.as_str().into()),
+            ("device", alert.device.as_str().into()),
+            ("condition", alert.condition.to_string().into()),
+            ("value", alert.value.into()),
+            ("limit", alert.limit.into()),
+            ("datetime", datetime.as_str().into()),
+        ];
+        if alert.active {
+            self.events.warning("alert_fired", &fields);
+        } else {
+            self.events.info("alert_cleared", &fields);
+        }
+        let command = match command {
+            Some(c) => c,
+            None => return,
+        };
+        let mut process = std::process::Command::new("sh");
+        process
+            .arg("-c")
+            .arg(command)
+            .env("FLINTEC_ALERT", &alert.name)
+            .env("FLINTEC_ALERT_STATE", state)
+            .env("FLINTEC_DEVICE", &alert.device)
+            .env("FLINTEC_CONDITION", alert.condition.to_string())
+            .env("FLINTEC_VALUE", alert.value.to_string())
+            .env("FLINTEC_LIMIT", alert.limit.to_string())
+            .env("FLINTEC_DATETIME", &datetime);
+        if let Some((w, raw_reading)) = reading {
+            process
+                .env("FLINTEC_LOAD", w.to_string())
+                .env("FLINTEC_RAW_READING", raw_reading);
+        }
+        match process.spawn() {
+            Ok(mut child) => {
+                let name = alert.name.clone();
+                std::thread::spawn(move || match child.wait() {
+                    Ok(s) if s.success() => {}
+                    Ok(s) => println!("alert {} command exited with {}", name, s),
+                    Err(e) => println!("alert {} command failed, error {}", name, e),
+                });
+            }
+            Err(e) => {
+                println!("could not run alert {} command, error {}", alert.name, e);
+                self.events.error(
+                    "alert_command_failed",
+                    &[
+                        ("alert", alert.name.as_str().into()),
+                        ("error", e.to_string().into()),
+                    ],
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ERROR_FLT_NONE;
+
+    #[test]
+    fn fires_and_clears_with_hysteresis_and_debounce() {
+        let rules = vec![
+            parse_rule("tipped,dad,below,500,600,2").unwrap(),
+            parse_rule("frozen,*,errors,3").unwrap(),
+            parse_rule("fast,dad,rate,200,50").unwrap(),
+        ];
+        assert!(parse_rule("tipped,dad,below,500,400,2").is_err());
+        let start = Local.with_ymd_and_hms(2021, 6, 1, 10, 0, 0).unwrap();
+        let names = vec![String::from("dad"), String::from("mum")];
+        let alerts = Alerts::new(&rules, &names, EventLog::disabled(), start);
+        let dad = alerts.for_device("dad");
+        let mut fired = Vec::new();
+        let loads = [1000., 450., 1000., 450., 450., 550., 650., 650.];
+        for (i, w) in loads.iter().enumerate() {
+            let dtr = start + chrono::Duration::minutes(i as i64);
+            for alert in dad.reading(dtr, *w, "") {
+                fired.push((i, alert.name, alert.active));
+            }
+        }
+        // a single low reading is debounced, 550 is within the hysteresis
+        assert_eq!(
+            fired,
+            vec![
+                (1, String::from("fast"), true),
+                (4, String::from("tipped"), true),
+                (4, String::from("fast"), false),
+                (7, String::from("tipped"), false),
+            ]
+        );
+        let mum = alerts.for_device("mum");
+        let errors: Vec<usize> = (0..4)
+            .filter(|_| !mum.reading(start, ERROR_FLT_NONE, "E+999998.").is_empty())
+            .collect();
+        assert_eq!(errors, vec![2]);
+    }
+}
@@ -0,0 +1,89 @@
+use flintec_lpp::console::*;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::Editor;
+
+fn main() {
+    let (endpoint, timeout, transcript_name) = parse_cli_console();
+    let transport = endpoint
+        .connect(timeout)
+        .expect("could not initiate the connection");
+    println!("connected to {}", endpoint);
+    let mut console = Console::new(transport, timeout);
+    let mut transcript = Transcript::open(&transcript_name);
+    transcript.write('#', &format!("connected to {}", endpoint));
+
+    let mut editor: Editor<ConsoleHelper, DefaultHistory> =
+        Editor::new().expect("could not initiate the console");
+    editor.set_helper(Some(ConsoleHelper));
+    println!("type help for the commands, tab to complete, quit or Ctrl-D to exit");
+
+    loop {
+        let line = match editor.readline("dad> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                println!("could not read the line, error {}", e);
+                break;
+            }
+        };
+        let _ = editor.add_history_entry(line.as_str());
+        let query = match Input::parse(&line) {
+            Input::Empty => continue,
+            Input::Help(topic) => {
+                print!("{}", help(topic.as_deref()));
+                continue;
+            }
+            Input::Quit => break,
+            Input::Unknown(l) => {
+                println!(
+                    "unknown command {}, type help for the list, or send {}",
+                    l, l
+                );
+                continue;
+            }
+            Input::Send(query) => query,
+        };
+        if query.needs_confirmation() && !confirm(&mut editor, &query) {
+            println!("cancelled");
+            transcript.write('#', &format!("cancelled {}", query.line()));
+            continue;
+        }
+        transcript.write('>', &query.line());
+        let (discarded, reply) = console.exchange(&query);
+        if discarded > 0 {
+            println!("discarded {} stale byte(s) before the command", discarded);
+            transcript.write('#', &format!("discarded {} stale byte(s)", discarded));
+        }
+        match reply {
+            Ok(reply) => {
+                let decoded = decode(&query, &reply);
+                println!("{}  ->  {}", reply.trim(), decoded);
+                transcript.write('<', &format!("{} | {}", reply.trim(), decoded));
+            }
+            Err(e) => {
+                println!("no reply, {}", e);
+                transcript.write('#', &format!("no reply, {}", e));
+                if let Err(e) = console.reconnect() {
+                    println!("could not reconnect, error {}", e);
+                }
+            }
+        }
+    }
+    transcript.write('#', "closed");
+}
+
+/// Ask for confirmation before sending the query, false unless answered yes.
+fn confirm(editor: &mut Editor<ConsoleHelper, DefaultHistory>, query: &Query) -> bool {
+    let prompt = match query {
+        Query::Command(c) => format!("{} will {}, continue? [y/N] ", c, c.help()),
+        q => format!(
+            "{} may change the amplifier state, continue? [y/N] ",
+            q.line()
+        ),
+    };
+    match editor.readline(&prompt) {
+        Ok(answer) => matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"),
+        Err(_) => false,
+    }
+}
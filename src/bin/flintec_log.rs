@@ -1,187 +1,439 @@
-use chrono::prelude::*;
+use flintec_lpp::alert::Alerts;
+use flintec_lpp::clock::{Clock, SystemClock};
+use flintec_lpp::config::{metadata_log_name, ConfigCheck};
+use flintec_lpp::events::{event_log_name, EventLog};
+use flintec_lpp::integrity::with_hash_column;
 use flintec_lpp::log::*;
-use flintec_lpp::ERROR_FLT_PARSE;
-use flintec_lpp::{ERROR_STR_GENERAL, ERROR_STR_INVALID, ERROR_STR_NONE, ERROR_STR_SKIPPED};
-use std::convert::TryInto;
-use std::io::prelude::*;
-use std::io::Error;
-use std::net::{Ipv4Addr, SocketAddrV4, TcpStream};
+use flintec_lpp::mqtt::{MqttSettings, MqttSink};
+use flintec_lpp::multi::{device_csv_name, wide_csv_header, MultiLogger};
+use flintec_lpp::rotate::{base_name, with_device, RotatingCsv};
+use flintec_lpp::schedule::Schedule;
+use flintec_lpp::session::{session_log_name, SessionLog};
+#[cfg(feature = "sqlite")]
+use flintec_lpp::sink::SqliteSink;
+use flintec_lpp::sink::{CsvSink, SharedSink, Sink};
+use flintec_lpp::spool::Spool;
+use flintec_lpp::status::{serve, Monitor};
+use flintec_lpp::transport::LazyTransport;
+use flintec_lpp::VERSION;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 fn main() {
-    let timeout: Duration = Duration::new(15, 0); // seconds, nanoseconds
-    let connection_retry: Duration = Duration::new(30, 0); // seconds, nanoseconds
-    let write_read_pause: Duration = Duration::new(2, 0); // seconds, nanoseconds
-
     // get CLI arguments
-    let (csv_name, ip, port, mut tcmd_str, minutes, delay, verbose) = parse_cli_log();
-
-    // Init connection with closure that can be used to refresh the connection if needed.
-    // The closures captures the variables in the environment where they are defined,
-    // in this case, it is defined here and it captures: socket and timeout.
-    // Then we can call the closure anywhere, without bringing around also socket and timeout.
-    // This closure takes no arguments because it only needs these environmental variables,
-    // no additional argument (at calling time) is required to init the connection.
-    // It while be called as closure().
-    // Arguments are added |args| when the env variables in the defining env should be combined
-    // with arguments given at calling time, i.e., closure(arg1, arg2, ...).
-    let ipaddr: Ipv4Addr = ip.parse().expect("arg string is not a valid ip address");
-    let socket = SocketAddrV4::new(ipaddr, port);
-    let init_connection = || -> Result<TcpStream, Error> {
-        let connection = std::net::TcpStream::connect(socket.to_string())?;
-        connection.set_nonblocking(false)?;
-        connection.set_read_timeout(Some(timeout))?;
-        connection.set_write_timeout(Some(timeout))?;
-        connection.set_nodelay(true)?;
-        Ok(connection)
+    let LogOptions {
+        csv_name,
+        devices,
+        interval,
+        anchor,
+        delay,
+        verbose,
+        per_device,
+        rotation,
+        compress,
+        burst,
+        timing,
+        http,
+        alert_rules,
+        sqlite,
+        mqtt,
+        acquisition_times,
+        clock_policy,
+        zone,
+        spool_rows,
+        fallback_dir,
+        fsync,
+        integrity,
+        integrity_key,
+        config_check,
+    } = parse_cli_log();
+    let parameters = session_parameters();
+
+    let stop = StopFlag::new();
+    let events = open_events(&csv_name);
+    let forced = ForcedExit::default();
+    handle_signals(stop.clone(), forced.clone(), events.clone());
+    let metadata = open_metadata(&csv_name, config_check);
+    let monitor = match http {
+        Some(address) => {
+            let names: Vec<String> = devices.iter().map(|d| d.name.clone()).collect();
+            let monitor = Monitor::new(&names, SystemClock.now());
+            serve(address, monitor.clone()).expect("could not serve the status");
+            monitor
+        }
+        None => Monitor::disabled(),
+    };
+    let mut sinks = open_sinks(&sqlite, mqtt);
+    let alerts = if alert_rules.is_empty() {
+        Alerts::none()
+    } else {
+        let names: Vec<String> = devices.iter().map(|d| d.name.clone()).collect();
+        let alerts = Alerts::new(&alert_rules, &names, events.clone(), SystemClock.now());
+        alerts.watch();
+        alerts
     };
-    let mut connection = init_connection().expect("could not initiate the connection");
-    println!("connected to socket {}", socket.to_string());
 
-    // wait for delay if any
-    if delay != 0 as u64 {
-        println!("starting with delay of {} minute(s)", delay);
-        std::thread::sleep(std::time::Duration::from_secs(delay * 60 as u64));
-    }
+    let (session, last_logged) = if devices.len() == 1 && !per_device {
+        let device = &devices[0];
 
-    // telnet command
-    tcmd_str.push('\n');
-    let tcmd: [u8; 3] = tcmd_str
-        .as_bytes()
-        .try_into()
-        .expect("bug on the telnet the command");
-
-    let mut csvfile = prepare_csvfile(&csv_name);
-
-    // datetime
-    let minutes_duration: chrono::Duration = chrono::Duration::minutes(minutes as i64);
-    let dt_now: DateTime<Local> = Local::now();
-    let mut dtr: DateTime<Local> = chrono_first_rounded(dt_now, minutes_duration);
-    let mut dtr_str = dtr.to_rfc3339_opts(SecondsFormat::Secs, false);
-    let mut dtr_next = dtr + minutes_duration;
-    let mut dtr_next_str = dtr_next.to_rfc3339_opts(SecondsFormat::Secs, false);
-    println!(
-        "starting at: {}, and then repeating from {} every {} minute(s)",
-        dtr_str, dtr_next_str, minutes
-    );
-
-    // wait for the starting time
-    let mut wait = dtr - Local::now();
-    let mut sleep_duration = wait
-        .to_std()
-        .expect("error in sleeping duration, negative sleep duration?");
-    std::thread::sleep(sleep_duration);
-    println!("OK, logging ...");
-
-    // init mut variables for tcp logging
-    let mut connection_ok = true;
-    let mut buffer = [0; 32];
-    let mut raw_reading: &str;
-    let mut w: f64;
-
-    loop {
-        match connection.read(&mut buffer) {
-            Ok(b) if b > 0 => println!("warning, found non-empty queue with length: {}", b),
-            _ => {}
+        // Check the existing csv file before connecting, to refuse appending to a different one.
+        let template = with_device(&csv_name, &device.name);
+        let header = csv_header(burst.is_some(), acquisition_times);
+        let header = if integrity {
+            with_hash_column(&header, integrity_key.is_some())
+        } else {
+            header
+        };
+        let (mut csvfile, resume_after) = RotatingCsv::open(
+            &template,
+            &header,
+            rotation,
+            interval,
+            anchor,
+            zone,
+            SystemClock.now(),
+        );
+        csvfile.compress = compress;
+        csvfile.fsync = fsync;
+        if integrity {
+            chain(&mut csvfile, &parameters, integrity_key.clone());
         }
+        let csvfile = spool(
+            csvfile,
+            &template,
+            &header,
+            spool_rows,
+            &fallback_dir,
+            &events,
+        );
+        let mut csv = CsvSink::new(csvfile, &template);
+        csv.acquisition_times = acquisition_times;
+        csv.events = events.clone();
+        sinks.insert(0, Box::new(csv));
+        let session = start_session(&csv_name, &parameters);
 
-        match connection.write(&tcmd) {
-            Ok(b) if b == 3 => {}
-            _ => println!("warning, failed to write command"),
-        }
+        // Init connection, the transport keeps the endpoint and timeout to refresh the connection if needed.
+        let transport = device
+            .endpoint
+            .connect(timing.timeout)
+            .expect("could not initiate the connection");
+        println!("connected to {}", device.endpoint);
 
-        std::thread::sleep(write_read_pause);
+        wait_delay(delay, &stop);
 
-        raw_reading = match connection.read(&mut buffer) {
-            Ok(0) => {
-                println!("{} no data", dtr_str);
-                connection_ok = false;
-                ERROR_STR_NONE
-            }
-            Ok(u) => match std::str::from_utf8(&buffer[0..u]) {
-                Ok(s) => s.trim_end(),
-                Err(e) => {
-                    println!("{} IO error, {}", dtr_str, e);
-                    connection_ok = false;
-                    ERROR_STR_INVALID
-                }
-            },
-            Err(e) => {
-                println!("{} IO error, {}", dtr_str, e);
-                connection_ok = false;
-                ERROR_STR_GENERAL
-            }
+        let mut logger = Logger::new(transport, SystemClock, device.command, interval);
+        logger.amplifier.stop = stop.clone();
+        logger.amplifier.set_timing(&timing);
+        logger.amplifier.session = Some(session.clone());
+        logger.amplifier.events = events.for_device(&device.name);
+        logger.amplifier.monitor = monitor.for_device(0);
+        logger.amplifier.config_check = config_check;
+        logger.amplifier.metadata = metadata.for_device(&device.name);
+        logger.alerts = alerts.for_device(&device.name);
+        logger.device = device.name.clone();
+        logger.sinks = forced.share(&session, sinks);
+        logger.amplifier.burst = burst;
+        logger.schedule.anchor = anchor;
+        logger.schedule.clock_policy = clock_policy;
+        logger.zone = zone;
+        if let Some(b) = burst {
+            logger.schedule.lead = chrono::Duration::from_std(b.lead()).unwrap();
+        }
+        logger.verbose = verbose;
+        logger.resume_after = resume_after;
+        logger.run();
+        (session, logger.last_logged())
+    } else {
+        let names: Vec<String> = devices.iter().map(|d| d.name.clone()).collect();
+        let csv_names: Vec<String> = if per_device && csv_name.contains("{device}") {
+            names.iter().map(|n| with_device(&csv_name, n)).collect()
+        } else if per_device {
+            names
+                .iter()
+                .map(|n| device_csv_name(&csv_name, n))
+                .collect()
+        } else {
+            vec![with_device(&csv_name, "all")]
         };
-
-        w = raw_reading
-            .get(2..)
-            .map(|s| s.parse().ok())
-            .flatten()
-            .unwrap_or(ERROR_FLT_PARSE);
-
-        match write!(&mut csvfile, "{},{},{}\n", dtr_str, w, raw_reading) {
-            Ok(_) => {
-                if verbose {
-                    println!(
-                        "{}, wrote load {} to {}, raw reading {}; next at {}",
-                        dtr_str, w, csv_name, raw_reading, dtr_next_str
-                    );
+        let header = if per_device {
+            csv_header(burst.is_some(), acquisition_times)
+        } else {
+            wide_csv_header(&names, burst.is_some(), acquisition_times)
+        };
+        let header = if integrity {
+            with_hash_column(&header, integrity_key.is_some())
+        } else {
+            header
+        };
+        let (csvfiles, resume_after): (Vec<Box<dyn Sink>>, Vec<_>) = csv_names
+            .iter()
+            .zip(names.iter())
+            .map(|(n, device)| {
+                let (mut csvfile, last) = RotatingCsv::open(
+                    n,
+                    &header,
+                    rotation,
+                    interval,
+                    anchor,
+                    zone,
+                    SystemClock.now(),
+                );
+                csvfile.compress = compress;
+                csvfile.fsync = fsync;
+                if integrity {
+                    chain(&mut csvfile, &parameters, integrity_key.clone());
                 }
-            }
-            Err(e) => println!(
-                "{}, could not write load {} to file {}, raw reading {}; next at {}",
-                dtr_str, w, csv_name, raw_reading, e
-            ),
+                let csvfile = spool(csvfile, n, &header, spool_rows, &fallback_dir, &events);
+                let mut csv = CsvSink::new(csvfile, n);
+                csv.device = if per_device {
+                    Some(device.clone())
+                } else {
+                    None
+                };
+                csv.acquisition_times = acquisition_times;
+                csv.events = events.clone();
+                (Box::new(csv) as Box<dyn Sink>, last)
+            })
+            .unzip();
+        let session = start_session(&csv_name, &parameters);
+
+        // Devices that cannot be reached now are retried by their own thread while logging.
+        let amplifiers: Vec<(String, Amplifier<LazyTransport>)> = devices
+            .iter()
+            .enumerate()
+            .map(|(i, d)| {
+                let transport = LazyTransport::connect(d.endpoint.clone(), timing.timeout);
+                let mut amplifier = Amplifier::new(transport, d.command);
+                amplifier.burst = burst;
+                amplifier.set_timing(&timing);
+                amplifier.session = Some(session.clone());
+                amplifier.events = events.for_device(&d.name);
+                amplifier.monitor = monitor.for_device(i);
+                amplifier.config_check = config_check;
+                amplifier.metadata = metadata.for_device(&d.name);
+                (d.name.clone(), amplifier)
+            })
+            .collect();
+
+        wait_delay(delay, &stop);
+
+        let mut logger = MultiLogger::new(amplifiers, SystemClock, interval, stop.clone());
+        logger.events = events.clone();
+        logger.monitor = monitor.clone();
+        logger.alerts = alerts.clone();
+        sinks.splice(0..0, csvfiles);
+        logger.sinks = forced.share(&session, sinks);
+        logger.session = Some(session.clone());
+        logger.schedule.anchor = anchor;
+        logger.schedule.clock_policy = clock_policy;
+        logger.zone = zone;
+        if let Some(b) = burst {
+            logger.schedule.lead = chrono::Duration::from_std(b.lead()).unwrap();
         }
+        logger.verbose = verbose;
+        logger.resume_after = resume_after;
+        logger.run();
+        (session, logger.last_logged())
+    };
 
-        // recover connection
-        while connection_ok == false {
-            println!("trying to refresh the connection");
-            match init_connection() {
-                Ok(c) => {
-                    println!("connection successful, resume logging");
-                    connection = c;
-                    connection_ok = true;
-                }
-                Err(e) => {
-                    println!("connection failed, error {}, trying again ...", e);
-                    std::thread::sleep(connection_retry);
-                }
+    let last = match last_logged {
+        Some(dtr) => dtr.to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        None => String::from("none"),
+    };
+    let reason = stop.reason().unwrap_or_default();
+    session.stop(&format!("{}, last reading at {}", reason, last));
+    let fields = [("reason", reason.into()), ("last_reading", last.into())];
+    if stop.is_failed() {
+        events.error("stopped", &fields);
+    } else {
+        events.info("stopped", &fields);
+    }
+    if stop.is_failed() {
+        std::process::exit(1);
+    }
+}
+
+/// Keep the rows that cannot be written in memory, and in the fallback directory if any.
+fn spool(
+    csvfile: RotatingCsv,
+    template: &str,
+    header: &str,
+    max_rows: usize,
+    fallback_dir: &Option<String>,
+    events: &EventLog,
+) -> Spool<RotatingCsv> {
+    let name = base_name(template);
+    let mut spool = Spool::new(csvfile, &name, header);
+    spool.max_rows = max_rows;
+    spool.events = events.clone();
+    spool.fallback = fallback_dir.as_ref().map(|dir| {
+        let stem = Path::new(&name).file_stem().unwrap_or_default();
+        let fallback = format!("{}_fallback.csv", stem.to_string_lossy());
+        Path::new(dir).join(fallback).to_string_lossy().into_owned()
+    });
+    spool
+}
+
+/// Open the other destinations of the readings.
+fn open_sinks(sqlite: &Option<String>, mqtt: Option<MqttSettings>) -> Vec<Box<dyn Sink>> {
+    let mut sinks = Vec::new();
+    if let Some(name) = sqlite {
+        sinks.push(open_sqlite(name));
+    }
+    if let Some(settings) = mqtt {
+        println!(
+            "publishing the readings also to mqtt://{}/{}",
+            settings.broker, settings.topic
+        );
+        sinks.push(Box::new(MqttSink::start(settings)) as Box<dyn Sink>);
+    }
+    sinks
+}
+
+#[cfg(feature = "sqlite")]
+fn open_sqlite(name: &str) -> Box<dyn Sink> {
+    let database = SqliteSink::open(name)
+        .unwrap_or_else(|e| panic!("could not open database {}, error {}", name, e));
+    println!("writing the readings also to database {}", name);
+    Box::new(database)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn open_sqlite(name: &str) -> Box<dyn Sink> {
+    panic!("cannot write to {}, built without the sqlite feature", name);
+}
+
+/// Open the event log next to the csv files, disabled if it cannot be opened.
+fn open_events(csv_name: &str) -> EventLog {
+    let name = event_log_name(&base_name(csv_name));
+    match EventLog::open(&name) {
+        Ok(events) => {
+            println!("writing events to {}", name);
+            events.info(
+                "started",
+                &[("version", VERSION.unwrap_or("unknown").into())],
+            );
+            events
+        }
+        Err(e) => {
+            println!("could not open event log {}, error {}", name, e);
+            EventLog::disabled()
+        }
+    }
+}
+
+/// Parameters of the session: the version, pid, and CLI arguments.
+fn session_parameters() -> String {
+    format!(
+        "flintec_log {} pid {}: {}",
+        VERSION.unwrap_or("unknown"),
+        std::process::id(),
+        std::env::args().collect::<Vec<String>>().join(" ")
+    )
+}
+
+/// Open the metadata log of the amplifier configuration next to the csv files,
+/// disabled if the configuration is not read or the log cannot be opened.
+fn open_metadata(csv_name: &str, config_check: ConfigCheck) -> EventLog {
+    if config_check == ConfigCheck::Off {
+        return EventLog::disabled();
+    }
+    let name = metadata_log_name(&base_name(csv_name));
+    match EventLog::open(&name) {
+        Ok(metadata) => {
+            println!("writing the amplifier configuration to {}", name);
+            metadata
+        }
+        Err(e) => {
+            println!("could not open metadata log {}, error {}", name, e);
+            EventLog::disabled()
+        }
+    }
+}
+
+/// Append the session start marker with the parameters.
+fn start_session(csv_name: &str, parameters: &str) -> SessionLog {
+    SessionLog::start(&session_log_name(&base_name(csv_name)), parameters)
+}
+
+/// Chain the rows of the csv file, panicking if its last hash cannot be read.
+fn chain(csvfile: &mut RotatingCsv, parameters: &str, key: Option<Vec<u8>>) {
+    if let Err(e) = csvfile.chain(parameters, key) {
+        panic!(
+            "could not read the last hash of {}, error {}",
+            csvfile.name(),
+            e
+        );
+    }
+}
+
+/// Time to wait for a sink busy writing before exiting without flushing it.
+const FORCED_SYNC_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Session and sinks to flush and mark when forced to exit by a second signal.
+#[derive(Clone, Default)]
+struct ForcedExit {
+    session: Arc<Mutex<Option<SessionLog>>>,
+    sinks: Arc<Mutex<Vec<SharedSink>>>,
+}
+
+impl ForcedExit {
+    /// Share the sinks of the session with the signal handler, returning them for the logger.
+    fn share(&self, session: &SessionLog, sinks: Vec<Box<dyn Sink>>) -> Vec<Box<dyn Sink>> {
+        let shared: Vec<SharedSink> = sinks.into_iter().map(SharedSink::new).collect();
+        *self.session.lock().unwrap() = Some(session.clone());
+        *self.sinks.lock().unwrap() = shared.clone();
+        shared
+            .into_iter()
+            .map(|s| Box::new(s) as Box<dyn Sink>)
+            .collect()
+    }
+
+    /// Flush the sinks and write the forced stop marker, abandoning the current reading,
+    /// then exit with the status of the signal.
+    fn exit(&self, name: &str, signal: i32, events: &EventLog) -> ! {
+        let reason = format!("forced by {} again, the current reading abandoned", name);
+        for sink in self.sinks.lock().unwrap().iter() {
+            match sink.try_sync(FORCED_SYNC_TIMEOUT) {
+                Ok(_) => println!("flushed {}", sink.name()),
+                Err(e) => println!("could not flush {}, error {}", sink.name(), e),
             }
         }
+        if let Some(session) = self.session.lock().unwrap().as_ref() {
+            session.stop(&reason);
+        }
+        events.info("stopped", &[("reason", reason.into())]);
+        std::process::exit(128 + signal);
+    }
+}
 
-        // recover datetime
-        while dtr_next <= Local::now() {
-            println!(
-                "skipping next reading at {} because it has already passed",
-                dtr_next_str
-            );
-            match write!(&mut csvfile, "{},{}\n", dtr_next_str, ERROR_STR_SKIPPED) {
-                Ok(_) => {
-                    println!(
-                        "datetime {}, wrote skipped value {} to file {}",
-                        dtr_next_str, ERROR_STR_SKIPPED, csv_name,
-                    );
-                }
-                Err(e) => {
-                    println!(
-                        "datetime {}, could not write skipped value {} to file {}, error {}",
-                        dtr_next_str, ERROR_STR_SKIPPED, csv_name, e
-                    );
-                }
+/// Stop after the current reading on the first SIGINT or SIGTERM,
+/// abandon the reading, flush the sinks, and exit on the second one.
+fn handle_signals(stop: StopFlag, forced: ForcedExit, events: EventLog) {
+    let mut signals = Signals::new([SIGINT, SIGTERM]).expect("could not register signal handlers");
+    std::thread::spawn(move || {
+        for signal in signals.forever() {
+            let name = signal_hook::low_level::signal_name(signal).unwrap_or("signal");
+            if stop.is_stopped() {
+                println!("received {} again, exit without waiting", name);
+                forced.exit(name, signal, &events);
             }
-            dtr_next = dtr_next + minutes_duration;
-            dtr_next_str = dtr_next.to_rfc3339_opts(SecondsFormat::Secs, false);
+            println!("received {}, stopping after the current reading ...", name);
+            stop.stop(name);
         }
+    });
+}
 
-        // wait for the next loop
-        wait = dtr_next - Local::now();
-        sleep_duration = wait.to_std().unwrap_or(Duration::from_secs(0));
-        std::thread::sleep(sleep_duration);
-        // prepare for next loop
-        dtr = dtr_next;
-        dtr_next = dtr + minutes_duration;
-        dtr_str = dtr.to_rfc3339_opts(SecondsFormat::Secs, false);
-        dtr_next_str = dtr_next.to_rfc3339_opts(SecondsFormat::Secs, false);
+/// Wait for the delay, if any, unless stopped.
+fn wait_delay(delay: u64, stop: &StopFlag) {
+    if delay != 0 {
+        println!("starting with delay of {} minute(s)", delay);
+        let mut clock = SystemClock;
+        let mut schedule = Schedule::new(chrono::Duration::minutes(delay as i64), clock.now());
+        schedule.advance();
+        schedule.wait_next(&mut clock, stop);
     }
 }
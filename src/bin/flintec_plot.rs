@@ -2,12 +2,12 @@ use flintec_lpp::plot::parse_cli;
 use flintec_lpp::TimeLoad;
 
 fn main() {
-    let (csvin, svgout) = parse_cli();
+    let (csvin, svgout, selection) = parse_cli();
     println!(
         "read data from {} and plot to {}",
         csvin.to_str().unwrap(),
         svgout.to_str().unwrap()
     );
-    let tw = TimeLoad::from_csv(csvin);
+    let tw = TimeLoad::from_path(csvin, &selection);
     tw.plot_datetime(svgout).unwrap();
 }
@@ -3,6 +3,7 @@ use flintec_lpp::make_window;
 use flintec_lpp::mavg;
 use flintec_lpp::process::parse_cli;
 use flintec_lpp::read_bad_datetimes;
+use flintec_lpp::session::{read_sessions, session_log_name};
 use flintec_lpp::TimeLoad;
 
 fn main() {
@@ -19,6 +20,7 @@ fn main() {
         bad_datetimes,
         bad_time_interval,
         timezone,
+        selection,
     ) = parse_cli();
 
     println!(
@@ -26,11 +28,39 @@ fn main() {
         timezone
     );
 
+    let sessions_name = session_log_name(csvin.to_str().unwrap());
+    if std::path::Path::new(&sessions_name).exists() {
+        println!("> logging sessions from {}", sessions_name);
+        for s in read_sessions(&sessions_name) {
+            match s.stop {
+                Some((stop, reason)) => println!(
+                    "  {} to {}, stopped: {}",
+                    s.start.to_rfc3339(),
+                    stop.to_rfc3339(),
+                    reason
+                ),
+                None => println!(
+                    "  {} without stop marker, the logger crashed or was killed",
+                    s.start.to_rfc3339()
+                ),
+            }
+            for outage in s.outages.iter() {
+                println!("    {}", outage);
+            }
+            for jump in s.clock_jumps.iter() {
+                println!("    {}", jump);
+            }
+            for drift in s.config_drifts.iter() {
+                println!("    configuration drift, {}", drift);
+            }
+        }
+    }
+
     println!("> read data from {}", csvin.to_str().unwrap());
-    let mut tw = TimeLoad::from_csv(csvin);
+    let mut tw = TimeLoad::from_path(csvin, &selection);
 
     let timezone_seconds = timezone * 60 * 60;
-    let timezone_fixed_offset = FixedOffset::east(timezone_seconds);
+    let timezone_fixed_offset = FixedOffset::east_opt(timezone_seconds).unwrap();
     tw.time
         .iter_mut()
         .for_each(|t| *t = t.with_timezone(&timezone_fixed_offset));
@@ -43,8 +73,7 @@ fn main() {
     println!("> check that the time series is continuous and ordered");
     ftw.is_ordered_and_continuous();
 
-    if bad_datetimes.is_some() {
-        let bdt = bad_datetimes.unwrap();
+    if let Some(bdt) = bad_datetimes {
         let vec_bad_dateimes = read_bad_datetimes(&bdt);
         println!(
             "> found {} bad datetimes in {}, set them to nan",
@@ -54,8 +83,7 @@ fn main() {
         ftw.replace_bad_datetimes_with_nan(vec_bad_dateimes);
     }
 
-    if bad_time_interval.is_some() {
-        let t = bad_time_interval.unwrap();
+    if let Some(t) = bad_time_interval {
         println!(
             "> consider daily times between {} and {} as invalid, set them to nan",
             t.0, t.1
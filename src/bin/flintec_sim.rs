@@ -0,0 +1,116 @@
+use flintec_lpp::sim::*;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+fn main() {
+    let (address, signal, decimals, fault_prob, faults, verbose) = parse_cli_sim();
+
+    let simulator = Arc::new(Mutex::new(Simulator::new(signal, decimals)));
+    let pending_fault: Arc<Mutex<Option<Fault>>> = Arc::new(Mutex::new(None));
+
+    // read faults on demand from stdin, they apply to the next reply
+    let stdin_fault = Arc::clone(&pending_fault);
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            match line.parse::<Fault>() {
+                Ok(f) => {
+                    println!("next reply with fault {:?}", f);
+                    *stdin_fault.lock().unwrap() = Some(f);
+                }
+                Err(e) => println!("{}, use delay[:seconds], drop, garbage, or error", e),
+            }
+        }
+    });
+
+    let listener = TcpListener::bind(&address).expect("could not bind the address");
+    println!("simulating DAD 141.1 on {}", address);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(s) => {
+                let simulator = Arc::clone(&simulator);
+                let pending_fault = Arc::clone(&pending_fault);
+                let faults = faults.clone();
+                std::thread::spawn(move || {
+                    serve(s, simulator, pending_fault, fault_prob, &faults, verbose)
+                });
+            }
+            Err(e) => println!("failed incoming connection, error {}", e),
+        }
+    }
+}
+
+fn serve(
+    stream: TcpStream,
+    simulator: Arc<Mutex<Simulator>>,
+    pending_fault: Arc<Mutex<Option<Fault>>>,
+    fault_prob: f64,
+    faults: &[Fault],
+    verbose: bool,
+) {
+    let peer = match stream.peer_addr() {
+        Ok(p) => p.to_string(),
+        Err(_) => String::from("unknown"),
+    };
+    println!("connection from {}", peer);
+    let mut writer = match stream.try_clone() {
+        Ok(w) => w,
+        Err(e) => {
+            println!("could not clone the stream for {}, error {}", peer, e);
+            return;
+        }
+    };
+    for line in BufReader::new(stream).lines() {
+        let line = match line {
+            Ok(l) => l,
+            Err(e) => {
+                println!("{} read error, {}", peer, e);
+                break;
+            }
+        };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let reply = {
+            let mut sim = simulator.lock().unwrap();
+            let fault = match pending_fault.lock().unwrap().take() {
+                Some(f) => Some(f),
+                None => sim.draw_fault(fault_prob, faults),
+            };
+            if let Some(f) = fault {
+                println!("{} command {}, injecting fault {:?}", peer, line, f);
+            }
+            sim.reply_with_fault(line, fault)
+        };
+        let bytes = match reply {
+            Reply::Send(b) => b,
+            Reply::Delayed(d, b) => {
+                std::thread::sleep(d);
+                b
+            }
+            Reply::Drop => break,
+        };
+        if verbose {
+            println!(
+                "{} command {}, reply {}",
+                peer,
+                line,
+                String::from_utf8_lossy(&bytes).trim_end()
+            );
+        }
+        if let Err(e) = writer.write_all(&bytes) {
+            println!("{} write error, {}", peer, e);
+            break;
+        }
+    }
+    println!("closed connection from {}", peer);
+}
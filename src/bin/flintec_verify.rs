@@ -0,0 +1,29 @@
+use flintec_lpp::integrity::{parse_cli, read_key, session_parameters, verify};
+
+fn main() {
+    let (csvin, sessions_name, key_name) = parse_cli();
+    let sessions = session_parameters(&sessions_name);
+    let key = key_name.map(|k| {
+        read_key(&k).unwrap_or_else(|e| {
+            panic!(
+                "could not read the key {}, error {}",
+                k.to_str().unwrap(),
+                e
+            )
+        })
+    });
+    println!(
+        "verify the hash chain of {} with {} session(s) from {}",
+        csvin.to_str().unwrap(),
+        sessions.len(),
+        sessions_name.to_str().unwrap()
+    );
+    match verify(&csvin, &sessions, key.as_deref()) {
+        Ok((rows, starts)) => println!("OK, {} row(s) chained, {} session start(s)", rows, starts),
+        Err(broken) => {
+            println!("{}", broken);
+            println!("the file was edited after logging, or a session is missing from the log");
+            std::process::exit(1);
+        }
+    }
+}
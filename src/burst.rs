@@ -0,0 +1,116 @@
+use super::{
+    ERROR_FLT_GENERAL, ERROR_FLT_INVALID, ERROR_FLT_NONE, ERROR_FLT_PARSE, ERROR_FLT_SKIPPED,
+};
+use std::time::Duration;
+
+/// Several readings spread evenly over a window centred on each scheduled time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Burst {
+    pub count: usize,
+    pub window: Duration,
+}
+
+impl Burst {
+    /// Time between the start of two readings of the burst.
+    pub fn step(&self) -> Duration {
+        self.window / self.count as u32
+    }
+
+    /// Time before the scheduled time when the burst starts.
+    pub fn lead(&self) -> Duration {
+        self.window / 2
+    }
+}
+
+/// Header of the statistics columns appended to the csv files in burst mode.
+pub const STATS_HEADER: &str = "median_kg,std_kg,min_kg,max_kg,valid_count";
+
+/// Statistics of the valid readings of one burst.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub mean: f64,
+    pub median: f64,
+    pub std: f64,
+    pub min: f64,
+    pub max: f64,
+    pub valid: usize,
+}
+
+impl Stats {
+    /// Statistics of the readings, skipping the error codes, none if all of them are errors.
+    /// The standard deviation is the sample one, zero for a single valid reading.
+    pub fn from_readings(readings: &[f64]) -> Option<Stats> {
+        let mut valid: Vec<f64> = readings
+            .iter()
+            .copied()
+            .filter(|w| !is_error_code(*w))
+            .collect();
+        if valid.is_empty() {
+            return None;
+        }
+        valid.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let n = valid.len();
+        let mean = valid.iter().sum::<f64>() / n as f64;
+        let median = if n % 2 == 1 {
+            valid[n / 2]
+        } else {
+            (valid[n / 2 - 1] + valid[n / 2]) / 2.
+        };
+        let std = if n > 1 {
+            (valid.iter().map(|w| (w - mean).powi(2)).sum::<f64>() / (n - 1) as f64).sqrt()
+        } else {
+            0.
+        };
+        Some(Stats {
+            mean,
+            median,
+            std,
+            min: valid[0],
+            max: valid[n - 1],
+            valid: n,
+        })
+    }
+
+    /// Statistics columns, with a leading comma.
+    pub fn to_csv(&self) -> String {
+        format!(
+            ",{:.3},{:.3},{},{},{}",
+            self.median, self.std, self.min, self.max, self.valid
+        )
+    }
+}
+
+/// Statistics columns when no reading of the burst is valid, or the burst was skipped.
+pub fn empty_stats_csv() -> String {
+    String::from(",nan,nan,nan,nan,0")
+}
+
+/// True for the values written in place of the load after errors.
+pub fn is_error_code(w: f64) -> bool {
+    [
+        ERROR_FLT_GENERAL,
+        ERROR_FLT_NONE,
+        ERROR_FLT_INVALID,
+        ERROR_FLT_SKIPPED,
+        ERROR_FLT_PARSE,
+    ]
+    .contains(&w)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_statistics_skip_errors() {
+        let stats = Stats::from_readings(&[1236., ERROR_FLT_NONE, 1234., 1235., 1239.]).unwrap();
+        assert_eq!(stats.valid, 4);
+        assert_eq!(stats.mean, 1236.);
+        assert_eq!(stats.median, 1235.5);
+        assert_eq!(stats.min, 1234.);
+        assert_eq!(stats.max, 1239.);
+        assert!((stats.std - 2.160247).abs() < 1e-6);
+        assert_eq!(stats.to_csv(), ",1235.500,2.160,1234,1239,4");
+        assert!(Stats::from_readings(&[ERROR_FLT_PARSE]).is_none());
+    }
+}
@@ -0,0 +1,148 @@
+//! Wall clock of the logger, and detection of its jumps against the monotonic clock.
+use chrono::prelude::*;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Source of the wall-clock time and of the sleeping for the logger.
+pub trait Clock {
+    fn now(&self) -> DateTime<Local>;
+    fn sleep(&mut self, duration: Duration);
+
+    /// Time that never jumps, from an arbitrary origin, to detect the jumps of the wall clock;
+    /// by default the wall clock itself, which never jumps for fake clocks.
+    fn monotonic(&self) -> Duration {
+        Duration::from_millis(self.now().timestamp_millis().max(0) as u64)
+    }
+}
+
+/// The system clock, sleeping the current thread.
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Local> {
+        Local::now()
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+
+    fn monotonic(&self) -> Duration {
+        static ORIGIN: OnceLock<Instant> = OnceLock::new();
+        ORIGIN.get_or_init(Instant::now).elapsed()
+    }
+}
+
+/// Smallest difference between the wall-clock and the monotonic elapsed times taken as a jump.
+pub const CLOCK_JUMP_THRESHOLD: Duration = Duration::from_secs(2);
+
+/// What to do when the wall clock jumps, e.g., when NTP syncs the clock of a board without RTC.
+/// The scheduled times never repeat or go back: after a backward jump,
+/// reanchor and wait do not read until the clock passes the next scheduled time.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ClockPolicy {
+    /// After a forward jump, continue at the next rounded time, without skipped rows for the gap.
+    #[default]
+    Reanchor,
+    /// After a forward jump, write the scheduled times of the gap as skipped.
+    Wait,
+    /// Keep the schedule on the monotonic clock, ignoring the jump,
+    /// so that the readings stay evenly spaced with the times of the clock before the jump.
+    Flag,
+}
+
+impl std::str::FromStr for ClockPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ClockPolicy, String> {
+        match s {
+            "reanchor" => Ok(ClockPolicy::Reanchor),
+            "wait" => Ok(ClockPolicy::Wait),
+            "flag" => Ok(ClockPolicy::Flag),
+            _ => Err(format!(
+                "invalid clock jump policy {}, use reanchor, wait, or flag",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for ClockPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ClockPolicy::Reanchor => "reanchor",
+            ClockPolicy::Wait => "wait",
+            ClockPolicy::Flag => "flag",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Detector of the jumps of the wall clock, against the monotonic clock.
+#[derive(Debug, Clone, Copy)]
+pub struct ClockWatch {
+    wall: DateTime<Local>,
+    monotonic: Duration,
+}
+
+impl ClockWatch {
+    pub fn new<C: Clock>(clock: &C) -> ClockWatch {
+        ClockWatch {
+            wall: clock.now(),
+            monotonic: clock.monotonic(),
+        }
+    }
+
+    /// Jump of the wall clock since the last check, positive forward,
+    /// if longer than `CLOCK_JUMP_THRESHOLD`.
+    pub fn check<C: Clock>(&mut self, clock: &C) -> Option<chrono::Duration> {
+        let (wall, monotonic) = (clock.now(), clock.monotonic());
+        let elapsed = chrono::Duration::from_std(monotonic.saturating_sub(self.monotonic))
+            .unwrap_or_else(|_| chrono::Duration::zero());
+        let jump = wall - self.wall - elapsed;
+        self.wall = wall;
+        self.monotonic = monotonic;
+        if jump.abs().to_std().unwrap_or_default() >= CLOCK_JUMP_THRESHOLD {
+            Some(jump)
+        } else {
+            None
+        }
+    }
+}
+
+/// Jump of the wall clock, as handled by the schedule.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockJump {
+    /// Wall-clock time after the jump.
+    pub at: DateTime<Local>,
+    /// Positive forward.
+    pub jump: chrono::Duration,
+    pub policy: ClockPolicy,
+    /// Next scheduled time after handling the jump.
+    pub next: DateTime<Local>,
+}
+
+impl std::fmt::Display for ClockJump {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let handling = match (self.policy, self.jump > chrono::Duration::zero()) {
+            (ClockPolicy::Flag, _) => "keeping the schedule on the monotonic clock",
+            (ClockPolicy::Reanchor, true) => "re-anchoring the schedule",
+            (ClockPolicy::Wait, true) => "writing the passed times as skipped",
+            (_, false) => "waiting for the clock to pass the next scheduled time",
+        };
+        write!(
+            f,
+            "clock jumped {} by {:.1} s at {}, {}, next reading at {}",
+            if self.jump > chrono::Duration::zero() {
+                "forward"
+            } else {
+                "backward"
+            },
+            self.jump.num_milliseconds().abs() as f64 / 1000.,
+            self.at.to_rfc3339_opts(SecondsFormat::Secs, false),
+            handling,
+            self.next.to_rfc3339_opts(SecondsFormat::Secs, false)
+        )
+    }
+}
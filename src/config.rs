@@ -0,0 +1,128 @@
+//! Configuration and identity of the amplifier, read at the session start and periodically.
+//!
+//! Each check is written to the metadata log next to the csv file, one JSON object per line,
+//! and a change between two checks raises a config_drift event,
+//! so that a sudden step in the loads can be traced back to a swapped amplifier.
+//!
+//! Only the identity and the firmware version are read. The span and zero calibration
+//! (functions 1.x and 2.x), the overload limit (menu 3.1), and the decimals are not:
+//! the commands to read them are not in the documentation available for the DAD 141.1,
+//! and guessed commands could change a setting instead of reading it.
+use super::events::Value;
+use super::log::with_suffix;
+use super::protocol::Setting;
+
+/// Name of the metadata log next to the csv file.
+pub fn metadata_log_name(csv_name: &str) -> String {
+    with_suffix(csv_name, "metadata", Some("jsonl"))
+}
+
+/// When the configuration of the amplifier is read.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ConfigCheck {
+    /// Never, e.g., for amplifiers that do not answer the queries.
+    Off,
+    /// Once, after the first reading of the session.
+    #[default]
+    Start,
+    /// After the first reading and then once per period, after a reading.
+    Every(chrono::Duration),
+}
+
+impl ConfigCheck {
+    /// True if the configuration should be read, given the time of the last check, if any.
+    pub fn is_due(
+        &self,
+        last: Option<chrono::DateTime<chrono::Local>>,
+        now: chrono::DateTime<chrono::Local>,
+    ) -> bool {
+        match (self, last) {
+            (ConfigCheck::Off, _) => false,
+            (_, None) => true,
+            (ConfigCheck::Start, Some(_)) => false,
+            (ConfigCheck::Every(period), Some(l)) => now - l >= *period,
+        }
+    }
+}
+
+impl std::str::FromStr for ConfigCheck {
+    type Err = String;
+
+    /// Parse off, start, or the period in hours.
+    fn from_str(s: &str) -> Result<ConfigCheck, String> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(ConfigCheck::Off),
+            "start" => Ok(ConfigCheck::Start),
+            h => match h.parse::<i64>() {
+                Ok(hours) if hours > 0 => Ok(ConfigCheck::Every(chrono::Duration::hours(hours))),
+                _ => Err(format!(
+                    "invalid config check {}, use off, start, or a period in hours",
+                    h
+                )),
+            },
+        }
+    }
+}
+
+/// Replies of the amplifier to the settings, none if not answered.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    pub values: Vec<(Setting, Option<String>)>,
+}
+
+impl Config {
+    /// True if no setting was answered, e.g., the connection was lost.
+    pub fn is_empty(&self) -> bool {
+        self.values.iter().all(|(_, v)| v.is_none())
+    }
+
+    /// Settings answered in both checks with different values: setting, previous, and new value.
+    pub fn changes(&self, previous: &Config) -> Vec<(Setting, String, String)> {
+        self.values
+            .iter()
+            .filter_map(|(s, v)| {
+                let before = previous.values.iter().find(|(p, _)| p == s)?.1.as_ref()?;
+                let after = v.as_ref()?;
+                (before != after).then(|| (*s, before.clone(), after.clone()))
+            })
+            .collect()
+    }
+
+    /// Fields of the answered settings for the metadata log.
+    pub fn fields(&self) -> Vec<(&'static str, Value)> {
+        self.values
+            .iter()
+            .filter_map(|(s, v)| v.as_ref().map(|v| (s.name(), v.as_str().into())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_changed_settings() {
+        let check = |firmware: Option<&str>| Config {
+            values: vec![
+                (Setting::Identity, Some(String::from("DAD141.1"))),
+                (Setting::Firmware, firmware.map(String::from)),
+            ],
+        };
+        let first = check(Some("1.2"));
+        assert!(check(Some("1.2")).changes(&first).is_empty());
+        assert!(check(None).changes(&first).is_empty());
+        assert_eq!(
+            check(Some("1.3")).changes(&first),
+            vec![(Setting::Firmware, String::from("1.2"), String::from("1.3"))]
+        );
+        assert_eq!(first.fields().len(), 2);
+        assert_eq!("start".parse::<ConfigCheck>(), Ok(ConfigCheck::Start));
+        assert_eq!(ConfigCheck::default(), ConfigCheck::Start);
+        assert_eq!(
+            "24".parse::<ConfigCheck>(),
+            Ok(ConfigCheck::Every(chrono::Duration::hours(24)))
+        );
+        assert!("0".parse::<ConfigCheck>().is_err());
+    }
+}
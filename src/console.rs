@@ -0,0 +1,382 @@
+//! Interactive console to commission the amplifier, instead of a generic telnet client.
+//!
+//! The known commands are completed with tab and their replies decoded,
+//! the state-changing commands are confirmed before sending,
+//! and the commands and replies are appended to a transcript.
+use super::framing::{FrameError, FrameReader};
+use super::log::{endpoint_args, parse_endpoint};
+use super::protocol::{Command, Kind, Reading, ERROR_FRAME, FRAME_LEN};
+use super::transport::{Endpoint, Transport};
+use super::VERSION;
+use chrono::prelude::*;
+use clap::{App, Arg};
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+use std::fs::File;
+use std::io::Write;
+use std::time::Duration;
+
+/// Takes the CLI arguments of the console: endpoint, timeout, and transcript file.
+pub fn parse_cli_console() -> (Endpoint, Duration, String) {
+    let arg_timeout = Arg::with_name("timeout")
+        .help("time to wait for each reply, in seconds")
+        .long("timeout")
+        .takes_value(true)
+        .default_value("3");
+    let arg_transcript = Arg::with_name("transcript")
+        .help("file where the commands and the replies are appended")
+        .long("transcript")
+        .takes_value(true)
+        .default_value("flintec_console.log");
+    let cli_args = App::new("Flintec_console")
+        .version(VERSION.unwrap_or("unknown"))
+        .author("Luca Peruzzo")
+        .about("cli app to send commands to the amplifier interactively")
+        .args(&endpoint_args())
+        .arg(arg_timeout)
+        .arg(arg_transcript)
+        .get_matches();
+    let timeout = cli_args.value_of("timeout").unwrap_or_default();
+    let val_timeout = match timeout.parse::<f64>() {
+        Ok(t) if t > 0. => Duration::from_secs_f64(t),
+        _ => panic!(
+            "invalid timeout {}, use a positive number of seconds",
+            timeout
+        ),
+    };
+    (
+        parse_endpoint(&cli_args),
+        val_timeout,
+        String::from(cli_args.value_of("transcript").unwrap_or_default()),
+    )
+}
+
+/// What is sent to the amplifier.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Query {
+    Command(Command),
+    /// Any other line, sent as typed.
+    Raw(String),
+}
+
+impl Query {
+    /// Line sent to the amplifier, without enter.
+    pub fn line(&self) -> String {
+        match self {
+            Query::Command(c) => String::from(c.mnemonic()),
+            Query::Raw(r) => r.clone(),
+        }
+    }
+
+    /// True if the query may change the state of the amplifier and must be confirmed.
+    pub fn needs_confirmation(&self) -> bool {
+        match self {
+            Query::Command(c) => c.is_state_changing(),
+            Query::Raw(_) => true,
+        }
+    }
+}
+
+/// Line typed in the console.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Input {
+    Empty,
+    /// Help on all the commands or on one.
+    Help(Option<String>),
+    Quit,
+    Send(Query),
+    Unknown(String),
+}
+
+impl Input {
+    /// Parse the line: a command mnemonic, `send` and any line,
+    /// `help` and optionally a command, or `quit`, ignoring capitalization.
+    pub fn parse(line: &str) -> Input {
+        let line = line.trim();
+        let (word, rest) = match line.split_once(char::is_whitespace) {
+            Some((w, r)) => (w, r.trim()),
+            None => (line, ""),
+        };
+        match word.to_lowercase().as_str() {
+            "" => Input::Empty,
+            "help" | "?" if rest.is_empty() => Input::Help(None),
+            "help" | "?" => Input::Help(Some(rest.to_uppercase())),
+            "quit" | "exit" => Input::Quit,
+            "send" if !rest.is_empty() => Input::Send(Query::Raw(String::from(rest))),
+            _ => match find_query(line) {
+                Some(q) => Input::Send(q),
+                None => Input::Unknown(String::from(line)),
+            },
+        }
+    }
+}
+
+/// Known command, ignoring capitalization.
+fn find_query(line: &str) -> Option<Query> {
+    line.parse::<Command>().ok().map(Query::Command)
+}
+
+/// Help on all the commands, or on the given one.
+pub fn help(topic: Option<&str>) -> String {
+    let command = |c: &Command| {
+        let confirm = if c.is_state_changing() {
+            ", asks for confirmation"
+        } else {
+            ""
+        };
+        format!("  {:7} {}{}\n", c.mnemonic(), c.help(), confirm)
+    };
+    if let Some(t) = topic {
+        return match find_query(t) {
+            Some(Query::Command(c)) => command(&c),
+            _ => format!("unknown command {}, type help for the list\n", t),
+        };
+    }
+    let mut text = String::from("commands:\n");
+    Command::ALL.iter().for_each(|c| text.push_str(&command(c)));
+    text.push_str(
+        "console:\n  send    send any line as typed, asks for confirmation\n  \
+        help    help on all the commands or on one, e.g., help SZ\n  \
+        quit    close the connection and exit\n",
+    );
+    text
+}
+
+/// Readable form of the reply to the query.
+pub fn decode(query: &Query, reply: &str) -> String {
+    let reply = reply.trim();
+    if reply == ERROR_FRAME {
+        return String::from(
+            "error, the amplifier refused the command (is the seal switch jumper removed?)",
+        );
+    }
+    if reply.len() == FRAME_LEN {
+        if let Ok(r) = Reading::parse(reply) {
+            let kind = |k: Kind| match k {
+                Kind::Net => String::from("net"),
+                Kind::Gross => String::from("gross"),
+                Kind::Average => String::from("average net"),
+                Kind::Tare => String::from("tare"),
+                Kind::Other(c) => format!("'{}'", c),
+            };
+            let status = if r.status.overload {
+                "overload"
+            } else if r.status.underload {
+                "underload"
+            } else if r.status.motion {
+                "motion"
+            } else {
+                "stable"
+            };
+            let mut text = format!(
+                "{} load {:.*}, {}",
+                kind(r.kind),
+                r.decimals,
+                r.value,
+                status
+            );
+            let expected = match query {
+                Query::Command(c) => c.reply_kind(),
+                _ => None,
+            };
+            if let Some(e) = expected.filter(|e| *e != r.kind) {
+                text.push_str(&format!(
+                    ", expected a {} frame, late reply of a previous command?",
+                    kind(e)
+                ));
+            }
+            return text;
+        }
+    }
+    match query {
+        Query::Command(Command::Status) => format!("status {}", reply),
+        Query::Command(Command::Identify) => format!("identity {}", reply),
+        Query::Command(Command::Version) => format!("firmware version {}", reply),
+        Query::Command(c) if c.is_state_changing() && reply == "OK" => String::from("done"),
+        _ => String::from(reply),
+    }
+}
+
+/// Connection to the amplifier, one query and one reply at a time.
+pub struct Console<T: Transport> {
+    transport: T,
+    frames: FrameReader,
+    timeout: Duration,
+}
+
+impl<T: Transport> Console<T> {
+    /// Wrap the transport, whose read timeout is the given one.
+    pub fn new(transport: T, timeout: Duration) -> Console<T> {
+        Console {
+            transport,
+            frames: FrameReader::new(),
+            timeout,
+        }
+    }
+
+    /// Discard the stale bytes, send the query, and read the reply frame.
+    /// Return the number of discarded bytes and the reply.
+    pub fn exchange(&mut self, query: &Query) -> (usize, Result<String, FrameError>) {
+        let discarded = self.frames.drain(
+            &mut self.transport,
+            Duration::from_millis(100),
+            self.timeout,
+        );
+        let line = format!("{}\n", query.line());
+        if let Err(e) = self.transport.write_all(line.as_bytes()) {
+            return (discarded, Err(FrameError::Io(e)));
+        }
+        (discarded, self.frames.read_frame(&mut self.transport))
+    }
+
+    /// Refresh the connection after an error.
+    pub fn reconnect(&mut self) -> std::io::Result<()> {
+        self.transport.reconnect()
+    }
+}
+
+/// Transcript of the console session, each line with its time.
+/// The transcript is disabled if the file cannot be opened.
+pub struct Transcript {
+    file: Option<File>,
+}
+
+impl Transcript {
+    pub fn open(path: &str) -> Transcript {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path);
+        match file {
+            Ok(f) => {
+                println!("writing the transcript to {}", path);
+                Transcript { file: Some(f) }
+            }
+            Err(e) => {
+                println!("could not open transcript {}, error {}", path, e);
+                Transcript { file: None }
+            }
+        }
+    }
+
+    /// Append the line, marked with > for the sent queries, < for the replies, and # for notes.
+    pub fn write(&mut self, mark: char, text: &str) {
+        if let Some(f) = self.file.as_mut() {
+            let now = Local::now().to_rfc3339_opts(SecondsFormat::Millis, false);
+            if let Err(e) = writeln!(f, "{} {} {}", now, mark, text) {
+                println!("could not write the transcript, error {}", e);
+                self.file = None;
+            }
+        }
+    }
+}
+
+/// Tab completion of the commands and hints with their help.
+pub struct ConsoleHelper;
+
+impl ConsoleHelper {
+    fn words() -> Vec<(String, String)> {
+        let mut words: Vec<(String, String)> = Command::ALL
+            .iter()
+            .map(|c| (String::from(c.mnemonic()), String::from(c.help())))
+            .collect();
+        for (w, h) in [
+            ("help", "help on the commands"),
+            ("send", "send any line"),
+            ("quit", "exit"),
+        ] {
+            words.push((String::from(w), String::from(h)));
+        }
+        words
+    }
+}
+
+impl Completer for ConsoleHelper {
+    type Candidate = Pair;
+
+    /// Complete the first word, or the command after help.
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let start = line[..pos].rfind(' ').map_or(0, |i| i + 1);
+        let before = line[..start].trim().to_lowercase();
+        if !(before.is_empty() || before == "help") {
+            return Ok((start, Vec::new()));
+        }
+        let prefix = line[start..pos].to_uppercase();
+        let candidates = ConsoleHelper::words()
+            .into_iter()
+            .filter(|(w, _)| w.to_uppercase().starts_with(&prefix))
+            .map(|(w, h)| Pair {
+                display: format!("{} ({})", w, h),
+                replacement: w,
+            })
+            .collect();
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+
+    /// Help of the command typed, if known.
+    fn hint(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Option<String> {
+        if pos < line.len() {
+            return None;
+        }
+        let typed = line.trim().to_uppercase();
+        ConsoleHelper::words()
+            .into_iter()
+            .find(|(w, _)| w.to_uppercase() == typed)
+            .map(|(_, h)| format!("  {}", h))
+    }
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_and_decodes() {
+        assert_eq!(
+            Input::parse(" gn "),
+            Input::Send(Query::Command(Command::Net))
+        );
+        assert_eq!(
+            Input::parse("send XY 12"),
+            Input::Send(Query::Raw(String::from("XY 12")))
+        );
+        assert_eq!(
+            Input::parse("help sz"),
+            Input::Help(Some(String::from("SZ")))
+        );
+        assert_eq!(Input::parse("XX"), Input::Unknown(String::from("XX")));
+        assert!(Query::Command(Command::Zero).needs_confirmation());
+        assert!(!Query::Command(Command::Net).needs_confirmation());
+
+        let net = Query::Command(Command::Net);
+        assert_eq!(decode(&net, "NM-0012.34"), "net load -12.34, motion");
+        assert_eq!(
+            decode(&net, "G +01234.5"),
+            "gross load 1234.5, stable, expected a net frame, late reply of a previous command?"
+        );
+        assert!(decode(&net, "ERR").starts_with("error"));
+        assert_eq!(decode(&Query::Command(Command::Tare), "OK"), "done");
+        assert_eq!(
+            decode(&Query::Command(Command::Version), "1.2"),
+            "firmware version 1.2"
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! Structured event log of the logger health.
+//!
+//! Each event is one JSON object per line, with the time, the severity, the kind of event,
+//! and its fields, e.g.,
+//! `{"time":"2021-06-01T10:01:02.345+02:00","severity":"warning","event":"connection_lost","device":"dad","error":"connection closed"}`,
+//! so that months of logging can be searched with grep or loaded as JSON lines.
+use super::log::with_suffix;
+use chrono::prelude::*;
+use std::fs::File;
+use std::io::{Result, Write};
+use std::sync::{Arc, Mutex};
+
+/// Severity of the events.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+/// Value of an event field.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Value {
+        Value::Str(String::from(v))
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Value {
+        Value::Str(v)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Value {
+        Value::Int(v)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(v: usize) -> Value {
+        Value::Int(v as i64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(v: f64) -> Value {
+        Value::Float(v)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Value {
+        Value::Bool(v)
+    }
+}
+
+impl From<DateTime<Local>> for Value {
+    fn from(v: DateTime<Local>) -> Value {
+        Value::Str(v.to_rfc3339_opts(SecondsFormat::Secs, false))
+    }
+}
+
+impl Value {
+    fn to_json(&self) -> String {
+        match self {
+            Value::Str(s) => json_string(s),
+            Value::Int(i) => i.to_string(),
+            Value::Float(f) if f.is_finite() => f.to_string(),
+            Value::Float(_) => String::from("null"),
+            Value::Bool(b) => b.to_string(),
+        }
+    }
+}
+
+/// Quote and escape the string for JSON.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Name of the event log next to the csv file.
+pub fn event_log_name(csv_name: &str) -> String {
+    with_suffix(csv_name, "events", Some("jsonl"))
+}
+
+/// Append-only JSON-lines event log, shared by the threads of the logger.
+/// The disabled log, the default, discards the events.
+#[derive(Debug, Clone, Default)]
+pub struct EventLog {
+    file: Option<Arc<Mutex<File>>>,
+    device: Option<String>,
+}
+
+impl EventLog {
+    /// Open the event log, appending to the existing file.
+    pub fn open(path: &str) -> Result<EventLog> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)?;
+        Ok(EventLog {
+            file: Some(Arc::new(Mutex::new(file))),
+            device: None,
+        })
+    }
+
+    /// Event log that discards the events.
+    pub fn disabled() -> EventLog {
+        EventLog::default()
+    }
+
+    /// Same event log, adding the device name to each event.
+    pub fn for_device(&self, device: &str) -> EventLog {
+        EventLog {
+            file: self.file.clone(),
+            device: Some(String::from(device)),
+        }
+    }
+
+    /// Format the event as a JSON line, without the newline.
+    pub fn format(
+        &self,
+        now: DateTime<Local>,
+        severity: Severity,
+        event: &str,
+        fields: &[(&str, Value)],
+    ) -> String {
+        let mut line = format!(
+            "{{\"time\":{},\"severity\":{},\"event\":{}",
+            json_string(&now.to_rfc3339_opts(SecondsFormat::Millis, false)),
+            json_string(severity.as_str()),
+            json_string(event)
+        );
+        if let Some(device) = &self.device {
+            line.push_str(&format!(",\"device\":{}", json_string(device)));
+        }
+        for (key, value) in fields.iter() {
+            line.push_str(&format!(",{}:{}", json_string(key), value.to_json()));
+        }
+        line.push('}');
+        line
+    }
+
+    /// Append the event with the current time, printing the errors.
+    /// Each line is written at once, so that the lines of several threads do not mix.
+    pub fn emit(&self, severity: Severity, event: &str, fields: &[(&str, Value)]) {
+        let file = match &self.file {
+            Some(f) => f,
+            None => return,
+        };
+        let line = format!("{}\n", self.format(Local::now(), severity, event, fields));
+        let mut file = file.lock().unwrap();
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            println!(
+                "could not write event {} to the event log, error {}",
+                event, e
+            );
+        }
+    }
+
+    pub fn info(&self, event: &str, fields: &[(&str, Value)]) {
+        self.emit(Severity::Info, event, fields);
+    }
+
+    pub fn warning(&self, event: &str, fields: &[(&str, Value)]) {
+        self.emit(Severity::Warning, event, fields);
+    }
+
+    pub fn error(&self, event: &str, fields: &[(&str, Value)]) {
+        self.emit(Severity::Error, event, fields);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_json_lines() {
+        let events = EventLog::disabled().for_device("dad \"1\"");
+        let now = FixedOffset::east_opt(7200)
+            .unwrap()
+            .with_ymd_and_hms(2021, 6, 1, 10, 1, 2)
+            .unwrap()
+            .with_timezone(&Local);
+        let line = events.format(
+            now,
+            Severity::Warning,
+            "parse_error",
+            &[
+                ("frame", "N +01\r".into()),
+                ("bytes", 5usize.into()),
+                ("load", f64::NAN.into()),
+            ],
+        );
+        let time = now.to_rfc3339_opts(SecondsFormat::Millis, false);
+        assert_eq!(
+            line,
+            format!(
+                "{{\"time\":\"{}\",\"severity\":\"warning\",\"event\":\"parse_error\",\
+                \"device\":\"dad \\\"1\\\"\",\"frame\":\"N +01\\r\",\"bytes\":5,\"load\":null}}",
+                time
+            )
+        );
+    }
+}
@@ -0,0 +1,183 @@
+//! Framing of the replies read from the amplifier.
+//!
+//! The replies are terminated by CR LF, but a single read may return part of a frame,
+//! several frames, or the late reply of a previous command.
+//! The frame reader keeps the bytes after the last terminator for the next frame
+//! and discards the stale bytes before sending a new command.
+use super::protocol::FRAME_LEN;
+use super::transport::Transport;
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use std::time::Duration;
+
+/// Longest line kept while waiting for the terminator, longer lines are discarded as garbage.
+pub const MAX_LINE_LEN: usize = 64;
+
+/// Errors while reading a frame.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The connection was closed by the amplifier.
+    Closed,
+    /// IO error, including the timeout without any byte.
+    Io(std::io::Error),
+    /// Bytes without terminator, shorter than a frame, before the timeout.
+    Partial(Vec<u8>),
+    /// Frame that is not valid UTF-8 or too long.
+    Invalid(Vec<u8>),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Closed => write!(f, "connection closed"),
+            FrameError::Io(e) => write!(f, "{}", e),
+            FrameError::Partial(b) => write!(f, "partial frame {:?}", String::from_utf8_lossy(b)),
+            FrameError::Invalid(b) => write!(f, "invalid frame {:?}", String::from_utf8_lossy(b)),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl FrameError {
+    /// True for the timeout without any byte, e.g., a query the amplifier does not answer.
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, FrameError::Io(e) if is_timeout(e))
+    }
+}
+
+fn is_timeout(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+fn is_terminator(b: u8) -> bool {
+    b == b'\r' || b == b'\n'
+}
+
+/// Reads the replies frame by frame.
+#[derive(Debug, Default)]
+pub struct FrameReader {
+    pending: Vec<u8>,
+}
+
+impl FrameReader {
+    pub fn new() -> FrameReader {
+        FrameReader::default()
+    }
+
+    /// Discard the pending bytes and the bytes that arrive within the drain timeout,
+    /// then restore the read timeout. Return the number of discarded bytes, except terminators.
+    pub fn drain<T: Transport>(
+        &mut self,
+        transport: &mut T,
+        drain: Duration,
+        timeout: Duration,
+    ) -> usize {
+        let stale = |bytes: &[u8]| bytes.iter().filter(|b| !is_terminator(**b)).count();
+        let mut discarded = stale(&self.pending);
+        self.pending.clear();
+        if transport.set_timeout(drain).is_err() {
+            return discarded;
+        }
+        let mut buffer = [0; 32];
+        loop {
+            match transport.read(&mut buffer) {
+                Ok(0) | Err(_) => break,
+                Ok(u) => discarded += stale(&buffer[..u]),
+            }
+        }
+        if let Err(e) = transport.set_timeout(timeout) {
+            println!("warning, could not restore the read timeout, error {}", e);
+        }
+        discarded
+    }
+
+    /// Read the next frame, up to the CR or LF terminator, skipping empty lines.
+    /// A frame of the expected length without terminator is accepted at the timeout.
+    pub fn read_frame<R: Read>(&mut self, reader: &mut R) -> Result<String, FrameError> {
+        let mut buffer = [0; 32];
+        loop {
+            if let Some(frame) = self.next_line()? {
+                return Ok(frame);
+            }
+            match reader.read(&mut buffer) {
+                Ok(0) => return Err(FrameError::Closed),
+                Ok(u) => self.pending.extend_from_slice(&buffer[..u]),
+                Err(e) if is_timeout(&e) && self.pending.len() >= FRAME_LEN => {
+                    let frame: Vec<u8> = self.pending.drain(..FRAME_LEN).collect();
+                    return String::from_utf8(frame)
+                        .map_err(|e| FrameError::Invalid(e.into_bytes()));
+                }
+                Err(e) if is_timeout(&e) && !self.pending.is_empty() => {
+                    return Err(FrameError::Partial(std::mem::take(&mut self.pending)));
+                }
+                Err(e) => return Err(FrameError::Io(e)),
+            }
+        }
+    }
+
+    /// Take the first complete line of the pending bytes, if any.
+    fn next_line(&mut self) -> Result<Option<String>, FrameError> {
+        while let Some(end) = self.pending.iter().position(|b| is_terminator(*b)) {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let line = &line[..end];
+            if line.is_empty() {
+                continue;
+            }
+            return match String::from_utf8(line.to_vec()) {
+                Ok(s) => Ok(Some(s)),
+                Err(e) => Err(FrameError::Invalid(e.into_bytes())),
+            };
+        }
+        if self.pending.len() > MAX_LINE_LEN {
+            return Err(FrameError::Invalid(std::mem::take(&mut self.pending)));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::io::{Error, Result};
+
+    /// Reader that returns each chunk in a separate read, then times out.
+    struct Chunks(VecDeque<Vec<u8>>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            match self.0.pop_front() {
+                Some(c) => {
+                    buf[..c.len()].copy_from_slice(&c);
+                    Ok(c.len())
+                }
+                None => Err(Error::new(ErrorKind::WouldBlock, "timeout")),
+            }
+        }
+    }
+
+    fn chunks(c: &[&[u8]]) -> Chunks {
+        Chunks(c.iter().map(|c| c.to_vec()).collect())
+    }
+
+    #[test]
+    fn frames_partial_and_joined_reads() {
+        let mut frames = FrameReader::new();
+        let mut reader = chunks(&[b"N +012", b"34.5\r\nN +01", b"235.5\r\n\r\nG +01236.5"]);
+        assert_eq!(frames.read_frame(&mut reader).unwrap(), "N +01234.5");
+        assert_eq!(frames.read_frame(&mut reader).unwrap(), "N +01235.5");
+        // without terminator, the expected length is enough at the timeout
+        assert_eq!(frames.read_frame(&mut reader).unwrap(), "G +01236.5");
+        let mut reader = chunks(&[b"N +01"]);
+        assert!(matches!(
+            frames.read_frame(&mut reader),
+            Err(FrameError::Partial(_))
+        ));
+        let mut reader = chunks(&[]);
+        assert!(matches!(
+            frames.read_frame(&mut reader),
+            Err(FrameError::Io(_))
+        ));
+    }
+}
@@ -0,0 +1,392 @@
+//! Hash chain over the rows of the csv files, as evidence that they were not edited after logging.
+//!
+//! Each row ends with the SHA-256 of the previous hash and the row, the first row of a file
+//! chains to the hash of the header, and the first row written by each logging session
+//! also covers the session parameters, as written to the session log.
+//!
+//! Anyone who can write the csv file can also recompute a plain SHA-256 chain, so that it only
+//! detects accidental edits; with a secret key, kept away from the data files, the chain is an
+//! HMAC-SHA256 that cannot be recomputed without the key.
+use super::session::{read_sessions, session_log_name};
+use super::VERSION;
+use clap::{App, Arg};
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Name of the last column, with the hash of the row.
+pub const HASH_COLUMN: &str = "sha256";
+
+/// Name of the last column, with the hash of the row keyed with the secret key.
+pub const HMAC_COLUMN: &str = "hmac_sha256";
+
+/// Shortest secret key accepted, in bytes.
+pub const MIN_KEY_LEN: usize = 16;
+
+/// Header with the hash column appended, keyed or not.
+pub fn with_hash_column(header: &str, keyed: bool) -> String {
+    let column = if keyed { HMAC_COLUMN } else { HASH_COLUMN };
+    format!("{},{}\n", header.trim_end(), column)
+}
+
+/// First link of the chain, the hash of the header without the newline.
+pub fn seed(key: Option<&[u8]>, header: &str) -> String {
+    digest(key, &[header.trim_end().as_bytes()])
+}
+
+/// Hash of the row chained to the previous hash and, for the first row of a session,
+/// to the session parameters.
+pub fn link(key: Option<&[u8]>, previous: &str, session: Option<&str>, row: &str) -> String {
+    let mut parts = vec![previous.as_bytes()];
+    if let Some(s) = session {
+        parts.extend_from_slice(&[b"\n", s.as_bytes()]);
+    }
+    parts.extend_from_slice(&[b"\n", row.as_bytes()]);
+    digest(key, &parts)
+}
+
+/// SHA-256 of the parts, or their HMAC-SHA256 (RFC 2104) with the key.
+fn digest(key: Option<&[u8]>, parts: &[&[u8]]) -> String {
+    let key = match key {
+        Some(k) => k,
+        None => {
+            let mut hasher = Sha256::new();
+            parts.iter().for_each(|p| hasher.update(p));
+            return hex(&hasher.finalize());
+        }
+    };
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let pad = |byte: u8| block.iter().map(|b| b ^ byte).collect::<Vec<u8>>();
+    let mut inner = Sha256::new();
+    inner.update(pad(0x36));
+    parts.iter().for_each(|p| inner.update(p));
+    let mut outer = Sha256::new();
+    outer.update(pad(0x5c));
+    outer.update(inner.finalize());
+    hex(&outer.finalize())
+}
+
+/// Secret key of the chain, the bytes of the file without the trailing newline.
+pub fn read_key<P>(fin: P) -> std::io::Result<Vec<u8>>
+where
+    P: AsRef<Path>,
+{
+    let mut key = std::fs::read(&fin)?;
+    while key.last().is_some_and(|b| b.is_ascii_whitespace()) {
+        key.pop();
+    }
+    if key.len() < MIN_KEY_LEN {
+        return Err(std::io::Error::other(format!(
+            "the key {} is shorter than {} bytes",
+            fin.as_ref().display(),
+            MIN_KEY_LEN
+        )));
+    }
+    Ok(key)
+}
+
+/// Whether the files are in the same directory, e.g., the key next to the csv files.
+pub fn in_same_directory<P, Q>(a: P, b: Q) -> bool
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let directory = |p: &Path| {
+        let parent = match p.parent() {
+            Some(d) if d != Path::new("") => d,
+            _ => Path::new("."),
+        };
+        parent.canonicalize().ok()
+    };
+    match (directory(a.as_ref()), directory(b.as_ref())) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// State of the chain of the csv file being written.
+#[derive(Debug, Clone)]
+pub struct HashChain {
+    previous: String,
+    /// Parameters of the session, until its first row is written.
+    session: Option<String>,
+    /// Secret key of the HMAC, if keyed.
+    key: Option<Vec<u8>>,
+}
+
+impl HashChain {
+    /// Continue the chain after the last hash of the file, or start it from the header.
+    pub fn new(
+        header: &str,
+        last: Option<String>,
+        session: &str,
+        key: Option<Vec<u8>>,
+    ) -> HashChain {
+        HashChain {
+            previous: last.unwrap_or_else(|| seed(key.as_deref(), header)),
+            session: Some(String::from(session)),
+            key,
+        }
+    }
+
+    /// Hash of the row, to append to it.
+    pub fn hash(&self, row: &str) -> String {
+        link(
+            self.key.as_deref(),
+            &self.previous,
+            self.session.as_deref(),
+            row,
+        )
+    }
+
+    /// Chain the next rows to the hash of the row, once it is written.
+    pub fn written(&mut self, hash: String) {
+        self.previous = hash;
+        self.session = None;
+    }
+
+    /// Continue with another file, e.g., after rotation.
+    pub fn switch(&mut self, header: &str, last: Option<String>) {
+        self.previous = last.unwrap_or_else(|| seed(self.key.as_deref(), header));
+    }
+}
+
+/// Last hash of the csv file, none if it has no rows.
+pub fn last_hash<P>(fin: P) -> std::io::Result<Option<String>>
+where
+    P: AsRef<Path>,
+{
+    let file = File::open(fin)?;
+    let mut last = None;
+    for l in BufReader::new(file).lines().skip(1) {
+        let l = l?;
+        if let Some((_, hash)) = l.trim_end().rsplit_once(',') {
+            last = Some(String::from(hash));
+        }
+    }
+    Ok(last)
+}
+
+/// First row whose hash does not match.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BrokenLink {
+    /// Line number in the file, the header is line 1.
+    pub line: usize,
+    pub row: String,
+}
+
+impl std::fmt::Display for BrokenLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "broken link at line {}: {}", self.line, self.row)
+    }
+}
+
+/// Walk the chain of the csv file, with the parameters of the sessions that wrote it
+/// and the secret key, if keyed, returning the number of rows and of session starts,
+/// or the first broken link. With the key, a chain that is not keyed is broken,
+/// as anyone could have recomputed it.
+pub fn verify<P>(
+    fin: P,
+    sessions: &[String],
+    key: Option<&[u8]>,
+) -> Result<(usize, usize), BrokenLink>
+where
+    P: AsRef<Path>,
+{
+    let broken = |line: usize, row: &str| BrokenLink {
+        line,
+        row: String::from(row),
+    };
+    let file = File::open(fin).map_err(|e| broken(1, &format!("could not open, error {}", e)))?;
+    let mut lines = BufReader::new(file).lines();
+    let header = match lines.next() {
+        Some(Ok(h)) => h,
+        _ => return Err(broken(1, "no header")),
+    };
+    let column = |c: &str| header.trim_end().ends_with(&format!(",{}", c));
+    match (column(HMAC_COLUMN), key) {
+        (true, None) => {
+            return Err(broken(
+                1,
+                &format!("{} column without the key in {}", HMAC_COLUMN, header),
+            ))
+        }
+        (false, Some(_)) => {
+            return Err(broken(
+                1,
+                &format!("no {} column in {}", HMAC_COLUMN, header),
+            ))
+        }
+        (false, None) if !column(HASH_COLUMN) => {
+            return Err(broken(
+                1,
+                &format!("no {} column in {}", HASH_COLUMN, header),
+            ))
+        }
+        _ => {}
+    }
+    let mut previous = seed(key, &header);
+    let (mut rows, mut starts) = (0, 0);
+    for (i, l) in lines.enumerate() {
+        let line = i + 2;
+        let l = l.map_err(|e| broken(line, &format!("could not read, error {}", e)))?;
+        let (row, hash) = l
+            .trim_end()
+            .rsplit_once(',')
+            .ok_or_else(|| broken(line, &l))?;
+        if link(key, &previous, None, row) != hash {
+            if !sessions
+                .iter()
+                .any(|s| link(key, &previous, Some(s), row) == hash)
+            {
+                return Err(broken(line, &l));
+            }
+            starts += 1;
+        }
+        previous = String::from(hash);
+        rows += 1;
+    }
+    Ok((rows, starts))
+}
+
+/// Takes the CLI arguments of the verification: csv file, session log, and key file, if any.
+pub fn parse_cli() -> (PathBuf, PathBuf, Option<PathBuf>) {
+    let arg_csvin = Arg::with_name("input_csvfile")
+        .help("name for the csv file logged with --integrity")
+        .short("f")
+        .long("csvfile")
+        .takes_value(true)
+        .required(true)
+        .default_value("loadcells.csv");
+    let arg_sessions = Arg::with_name("sessions")
+        .help("session log with the parameters of the sessions, by default next to the csv file")
+        .long_help(
+            "session log with the parameters of the sessions that wrote the csv file, \
+            by default the one named after the csv file; for rotated files, \
+            the one named after the file name without the date",
+        )
+        .long("sessions")
+        .takes_value(true);
+    let arg_key = Arg::with_name("key")
+        .help("file of the secret key, for a csv file logged with --integrity_key")
+        .long("key")
+        .takes_value(true);
+    let cli_args = App::new("Flintec_verify")
+        .version(VERSION.unwrap_or("unknown"))
+        .author("Luca Peruzzo")
+        .about("cli app to verify the hash chain of the csv file")
+        .arg(arg_csvin)
+        .arg(arg_sessions)
+        .arg(arg_key)
+        .get_matches();
+    let csvin = PathBuf::from(cli_args.value_of("input_csvfile").unwrap_or_default());
+    let sessions = match cli_args.value_of("sessions") {
+        Some(s) => PathBuf::from(s),
+        None => PathBuf::from(session_log_name(csvin.to_str().unwrap())),
+    };
+    let key = cli_args.value_of("key").map(PathBuf::from);
+    (csvin, sessions, key)
+}
+
+/// Parameters of the sessions in the session log, none if it does not exist.
+pub fn session_parameters<P>(fin: P) -> Vec<String>
+where
+    P: AsRef<Path>,
+{
+    if !fin.as_ref().exists() {
+        return Vec::new();
+    }
+    read_sessions(fin)
+        .into_iter()
+        .map(|s| s.parameters)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_first_broken_link() {
+        let path = std::env::temp_dir().join("flintec_lpp_chain.csv");
+        let header = with_hash_column("datetime,load_kg,raw_reading", false);
+        let rows = [
+            "2021-06-01T10:00:00+02:00,1234.5,N +01234.5",
+            "2021-06-01T10:01:00+02:00,1234.6,N +01234.6",
+            "2021-06-01T10:02:00+02:00,1234.7,N +01234.7",
+        ];
+        let mut csv = header.clone();
+        let mut chain = HashChain::new(&header, None, "first session", None);
+        for (i, row) in rows.iter().enumerate() {
+            if i == 2 {
+                // restarted
+                chain = HashChain::new(&header, last_hash(&path).unwrap(), "second session", None);
+            }
+            let hash = chain.hash(row);
+            csv.push_str(&format!("{},{}\n", row, hash));
+            std::fs::write(&path, &csv).unwrap();
+            chain.written(hash);
+        }
+        let sessions = vec![
+            String::from("first session"),
+            String::from("second session"),
+        ];
+        let verified = verify(&path, &sessions, None);
+        let without_sessions = verify(&path, &[], None);
+        std::fs::write(&path, csv.replace("1234.6,", "1243.6,")).unwrap();
+        let edited = verify(&path, &sessions, None);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(verified, Ok((3, 2)));
+        assert_eq!(without_sessions.unwrap_err().line, 2);
+        assert_eq!(edited.unwrap_err().line, 3);
+    }
+
+    #[test]
+    fn keys_the_chain() {
+        // RFC 4231, test case 2
+        let hmac = digest(Some(b"Jefe"), &[b"what do ya want ", b"for nothing?"]);
+        assert_eq!(
+            hmac,
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        let path = std::env::temp_dir().join("flintec_lpp_keyed_chain.csv");
+        let key = b"0123456789abcdef".to_vec();
+        let header = with_hash_column("datetime,load_kg,raw_reading", true);
+        let row = "2021-06-01T10:00:00+02:00,1234.5,N +01234.5";
+        let write = |key: Option<Vec<u8>>, header: &str| {
+            let chain = HashChain::new(header, None, "session", key);
+            let csv = format!("{}{},{}\n", header, row, chain.hash(row));
+            std::fs::write(&path, csv).unwrap();
+        };
+        let sessions = vec![String::from("session")];
+        write(Some(key.clone()), &header);
+        let keyed = verify(&path, &sessions, Some(&key));
+        let without_key = verify(&path, &sessions, None);
+        // recomputed without the key, or with another one
+        write(None, &header);
+        let forged = verify(&path, &sessions, Some(&key));
+        write(Some(b"fedcba9876543210".to_vec()), &header);
+        let other_key = verify(&path, &sessions, Some(&key));
+        write(
+            None,
+            &with_hash_column("datetime,load_kg,raw_reading", false),
+        );
+        let not_keyed = verify(&path, &sessions, Some(&key));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(keyed, Ok((1, 1)));
+        assert_eq!(without_key.unwrap_err().line, 1);
+        assert_eq!(forged.unwrap_err().line, 2);
+        assert_eq!(other_key.unwrap_err().line, 2);
+        assert_eq!(not_keyed.unwrap_err().line, 1);
+    }
+}
@@ -1,9 +1,78 @@
+use super::alert::{read_alert_rules, Alerts, Rule};
+use super::burst::{empty_stats_csv, is_error_code, Burst, Stats, STATS_HEADER};
+use super::clock::{Clock, ClockJump, ClockPolicy};
+use super::config::{Config, ConfigCheck};
+use super::events::EventLog;
+use super::framing::{FrameError, FrameReader};
+use super::integrity::{in_same_directory, read_key};
+use super::mqtt::MqttSettings;
+use super::multi::{read_devices, Device};
+use super::protocol::{Command, Reading, Setting, ERROR_FRAME, FRAME_LEN};
+use super::rng::XorShift;
+use super::rotate::{FsyncPolicy, Rotation};
+use super::schedule::{format_interval, Anchor, Schedule, Zone};
+use super::session::SessionLog;
+use super::sink::{Acquisition, Record, Sink, ACQUISITION_HEADER};
+use super::status::Monitor;
+use super::transport::{Endpoint, SerialSettings, Transport};
 use super::VERSION;
+use super::{
+    ERROR_FLT_GENERAL, ERROR_FLT_INVALID, ERROR_FLT_NONE, ERROR_FLT_PARSE, ERROR_FLT_SKIPPED,
+};
+use super::{ERROR_STR_GENERAL, ERROR_STR_INVALID, ERROR_STR_NONE, ERROR_STR_SKIPPED};
 use chrono::prelude::*;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Options of the logging application, from the CLI arguments.
+#[derive(Debug, Clone)]
+pub struct LogOptions {
+    /// Name, or name template when rotating, of the csv file.
+    pub csv_name: String,
+    pub devices: Vec<Device>,
+    pub interval: chrono::Duration,
+    pub anchor: Anchor,
+    /// Delay before connecting, in minutes.
+    pub delay: u64,
+    pub verbose: bool,
+    /// One csv file per device instead of one wide csv file.
+    pub per_device: bool,
+    pub rotation: Rotation,
+    /// Compress the closed csv files when rotating.
+    pub compress: bool,
+    pub burst: Option<Burst>,
+    pub timing: Timing,
+    /// Address of the status endpoint, if served.
+    pub http: Option<SocketAddr>,
+    pub alert_rules: Vec<Rule>,
+    /// SQLite database of the readings, if any.
+    pub sqlite: Option<String>,
+    pub mqtt: Option<MqttSettings>,
+    /// Write the acquisition columns.
+    pub acquisition_times: bool,
+    pub clock_policy: ClockPolicy,
+    pub zone: Zone,
+    /// Rows kept in memory while the csv file cannot be written.
+    pub spool_rows: usize,
+    /// Directory of the fallback files, if any.
+    pub fallback_dir: Option<String>,
+    pub fsync: FsyncPolicy,
+    /// Append the hash chain column.
+    pub integrity: bool,
+    /// Secret key of the hash chain, if keyed.
+    pub integrity_key: Option<Vec<u8>>,
+    pub config_check: ConfigCheck,
+}
 
 /// Takes the CLI arguments to control the logging application.
-pub fn parse_cli_log() -> (String, String, u16, String, u32, u64, bool) {
+pub fn parse_cli_log() -> LogOptions {
     let arg_csvfile = Arg::with_name("csvfile")
         .help("name for the csv file")
         .short("o")
@@ -11,49 +80,282 @@ pub fn parse_cli_log() -> (String, String, u16, String, u32, u64, bool) {
         .takes_value(true)
         .required(true)
         .default_value("loadcells.csv");
-    let arg_ip = Arg::with_name("ip_address")
-        .help("ip address for the telnet connection")
-        .short("t")
-        .long("ip")
-        .takes_value(true)
-        .required(true)
-        .default_value("192.168.0.100");
-    let arg_port = Arg::with_name("port")
-        .help("port for the telnet connection")
-        .short("p")
-        .long("port")
+    let arg_rotate = Arg::with_name("rotate")
+        .help("start a new csv file every day, week, or month")
+        .long_help(
+            "start a new csv file every day, week (from Monday), or month; \
+            the csvfile name is a template where {device} is the device name and {%...} \
+            the start of the period with chrono specifiers, e.g., site_{device}_{%Y-%m}.csv; \
+            without {%...}, the date is added before the extension",
+        )
+        .long("rotate")
         .takes_value(true)
-        .default_value("23");
+        .case_insensitive(true)
+        .possible_values(&["never", "daily", "weekly", "monthly"])
+        .default_value("never");
+    let arg_compress = Arg::with_name("compress")
+        .help("compress the closed csv files with gzip when rotating")
+        .long("compress")
+        .takes_value(false)
+        .required(false);
+    let arg_devices = Arg::with_name("devices")
+        .help("csv file with the devices to log together: name, address, port, command")
+        .long_help(
+            "csv file with the devices to log together, one per line: name, address, port, command; \
+            for serial devices, the address is the tty device and the port the baud rate; \
+            replaces the single connection options",
+        )
+        .long("devices")
+        .takes_value(true);
+    let arg_per_device = Arg::with_name("per_device_files")
+        .help("write one csv file per device instead of one wide csv file")
+        .long("per_device_files")
+        .takes_value(false)
+        .required(false);
     let arg_tcmd = Arg::with_name("tcmd")
         .help("telnet command")
-        .long_help("tcmd is automatically formatted, capitalization and enter; GetNet, GetAverage (128 readings over 1 sec)")
+        .long_help("tcmd is automatically formatted, capitalization and enter; GetNet, GetGross, GetAverage (128 readings over 1 sec)")
         .short("c")
         .long("tcmd")
         .required(true)
-        .possible_values(&["gn", "ga", "GN", "GA"])
+        .case_insensitive(true)
+        .possible_values(&["GN", "GG", "GA"])
         .default_value("gn");
+    let arg_seconds = Arg::with_name("seconds")
+        .help("interlude for the reading times, in seconds, instead of minutes")
+        .short("s")
+        .long("seconds")
+        .overrides_with_all(&["minutes", "hours"])
+        .takes_value(true);
     let arg_minutes = Arg::with_name("minutes")
-        .help("interlude and rounding for the reading times, in minutes")
+        .help("interlude for the reading times, in minutes")
         .short("m")
         .long("minutes")
-        .required_unless("hours")
-        .overrides_with("hours")
+        .overrides_with_all(&["hours", "seconds"])
         .takes_value(true)
-        .possible_values(&["1", "2", "3", "5", "10", "15", "20", "30", "60"])
         .default_value("2");
     let arg_hours = Arg::with_name("hours")
-        .help("interlude and rounding for the reading times, in hours")
+        .help("interlude for the reading times, in hours, instead of minutes")
         .long("hours")
-        .required_unless("minutes")
-        .overrides_with("minutes")
+        .overrides_with_all(&["minutes", "seconds"])
+        .takes_value(true);
+    let arg_anchor = Arg::with_name("anchor")
+        .help("time the reading times are rounded to: local time HH:MM[:SS], UTC time HH:MM[:SS]Z, or RFC 3339 datetime")
+        .long_help(
+            "time the reading times are rounded to, i.e., the readings are at anchor + n * interval; \
+            a local time of the day HH:MM[:SS], a UTC time of the day HH:MM[:SS]Z (e.g., 00:00Z), \
+            or a RFC 3339 datetime (e.g., 2021-06-01T08:30:00+02:00)",
+        )
+        .long("anchor")
+        .takes_value(true)
+        .default_value("00:00");
+    let arg_timezone = Arg::with_name("timezone")
+        .help("time zone of the reading times: local, utc, ±HH:MM, or hours relative to UTC")
+        .long_help(
+            "time zone where the reading times are rounded and written: local, utc, \
+            a fixed offset ±HH:MM, or hours relative to UTC (e.g., -8 for the standard time \
+            of the site all year round); with a fixed offset, the times of the day of the anchor \
+            are in that offset and daylight saving time does not shift the reading times",
+        )
+        .allow_hyphen_values(true)
+        .long("timezone")
         .takes_value(true)
-        .possible_values(&["1", "2", "3", "6", "12", "24"]);
+        .default_value("local");
     let arg_delay = Arg::with_name("delay")
         .help("delay connection and logging, in minutes")
         .short("d")
         .long("delay")
         .required(true)
         .default_value("0");
+    let arg_burst = Arg::with_name("burst")
+        .help("number of readings for each scheduled time, written with their statistics")
+        .long_help(
+            "number of readings spread over the burst window centred on each scheduled time; \
+            the load is their mean and the median, standard deviation, min, max, \
+            and number of valid readings are appended to the csv row",
+        )
+        .long("burst")
+        .takes_value(true)
+        .default_value("1");
+    let arg_burst_window = Arg::with_name("burst_window")
+        .help("window of the burst readings, in seconds, shorter than the interval")
+        .long("burst_window")
+        .takes_value(true)
+        .default_value("60");
+    let arg_timeout = Arg::with_name("timeout")
+        .help("read and write timeout of the connection, in seconds")
+        .long("timeout")
+        .takes_value(true)
+        .default_value("15");
+    let arg_pause = Arg::with_name("pause")
+        .help("pause between the command and the reading of the reply, in seconds")
+        .long("pause")
+        .takes_value(true)
+        .default_value("2");
+    let arg_retry = Arg::with_name("retry")
+        .help("first pause between the attempts to refresh the connection, in seconds")
+        .long_help(
+            "first pause between the attempts to refresh the connection, in seconds; \
+            the pause doubles after each failed attempt up to max_retry, with 20% jitter",
+        )
+        .long("retry")
+        .takes_value(true)
+        .default_value("30");
+    let arg_max_retry = Arg::with_name("max_retry")
+        .help("longest pause between the attempts to refresh the connection, in seconds")
+        .long("max_retry")
+        .takes_value(true)
+        .default_value("600");
+    let arg_max_outage = Arg::with_name("max_outage")
+        .help("longest outage before exiting with an error, in seconds, retry forever if not set")
+        .long_help(
+            "longest outage before exiting with an error, in seconds, so that a supervisor \
+            can restart the logger; retry forever if not set",
+        )
+        .long("max_outage")
+        .takes_value(true);
+    let arg_drain = Arg::with_name("drain")
+        .help("read timeout to discard stale bytes before each command, in seconds")
+        .long("drain")
+        .takes_value(true)
+        .default_value("0.1");
+    let arg_http = Arg::with_name("http")
+        .help("serve the status as JSON on /status and Prometheus metrics on /metrics")
+        .long_help(
+            "address to serve the status of the logger, e.g., 127.0.0.1:9100; \
+            GET /status returns the last reading, the connection state, the error counts, \
+            and the uptime as JSON, and GET /metrics the same for Prometheus",
+        )
+        .long("http")
+        .takes_value(true);
+    let arg_alerts = Arg::with_name("alerts")
+        .help("csv file of alert rules, running a command when an alert fires and clears")
+        .long_help(
+            "csv file with one alert rule per line: name, device (or * for all), condition \
+            (above, below, rate, errors, or stale), limit, clear limit, debounce, and command, \
+            e.g., tipped,lysimeter1,below,500,600,3,/usr/local/bin/notify.sh; \
+            the command runs with the details in the FLINTEC_* environment variables",
+        )
+        .long("alerts")
+        .takes_value(true);
+    let arg_sqlite = Arg::with_name("sqlite")
+        .help("also write the readings to a SQLite database")
+        .long_help(
+            "also write the readings of all the devices to the readings table of a SQLite database, \
+            with device, scheduled time, read time, load, raw reading, and error code",
+        )
+        .long("sqlite")
+        .takes_value(true);
+    let arg_mqtt = Arg::with_name("mqtt")
+        .help("also publish the readings to an MQTT broker, host:port")
+        .long_help(
+            "also publish each reading as JSON to an MQTT broker, host:port, e.g., localhost:1883; \
+            the readings are queued while the broker is not reachable",
+        )
+        .long("mqtt")
+        .takes_value(true);
+    let arg_mqtt_topic = Arg::with_name("mqtt_topic")
+        .help("MQTT topic of the readings, {device} is replaced by the device name")
+        .long("mqtt_topic")
+        .takes_value(true)
+        .default_value("flintec/{device}");
+    let arg_mqtt_qos = Arg::with_name("mqtt_qos")
+        .help("MQTT quality of service, 0 (at most once) or 1 (at least once)")
+        .long("mqtt_qos")
+        .takes_value(true)
+        .possible_values(&["0", "1"])
+        .default_value("1");
+    let arg_mqtt_no_retain = Arg::with_name("mqtt_no_retain")
+        .help("do not ask the broker to retain the last reading of each topic")
+        .long("mqtt_no_retain")
+        .takes_value(false);
+    let arg_mqtt_queue = Arg::with_name("mqtt_queue")
+        .help("readings queued while the broker is not reachable, the oldest are dropped beyond")
+        .long("mqtt_queue")
+        .takes_value(true)
+        .default_value("10000");
+    let arg_acquisition_times = Arg::with_name("acquisition_times")
+        .help("also write when the command was sent, when the reply arrived, and the latency")
+        .long_help(
+            "also write the columns sent_time and received_time, the wall-clock times \
+            when the command was sent and when the reply arrived, with milliseconds, and latency_s, \
+            the seconds between them, including the pause before reading the reply; \
+            in burst mode, from the first command to the last reply",
+        )
+        .long("acquisition_times")
+        .takes_value(false);
+    let arg_clock_jump = Arg::with_name("clock_jump")
+        .help("what to do when the system clock jumps: reanchor, wait, or flag")
+        .long_help(
+            "what to do when the system clock jumps against the monotonic clock, \
+            e.g., when NTP syncs a board without RTC: reanchor continues at the next rounded time \
+            after a forward jump, wait writes the times of a forward jump as skipped, \
+            both wait for the clock after a backward jump; flag keeps the schedule on the \
+            monotonic clock with the times before the jump; the jumps are written to the \
+            session and event logs, and the times never repeat or go back",
+        )
+        .long("clock_jump")
+        .takes_value(true)
+        .possible_values(&["reanchor", "wait", "flag"])
+        .default_value("reanchor");
+    let arg_spool_rows = Arg::with_name("spool_rows")
+        .help(
+            "rows kept in memory while the csv file cannot be written, the oldest are lost beyond",
+        )
+        .long("spool_rows")
+        .takes_value(true)
+        .default_value("10000");
+    let arg_fallback_dir = Arg::with_name("fallback_dir")
+        .help("directory where the rows are also written while the csv file cannot be written")
+        .long_help(
+            "directory where the rows are also written while the csv file cannot be written, \
+            e.g., when the SD card is full, to a file named after the csv file with _fallback; \
+            the rows are written to the csv file in order once writing works again",
+        )
+        .long("fallback_dir")
+        .takes_value(true);
+    let arg_fsync = Arg::with_name("fsync")
+        .help("sync the csv file to the disk after each row: always, never, or at most every n seconds")
+        .long_help(
+            "when the rows are synced to the disk, besides at rotation and exit: always after \
+            each row, at most every n seconds, or never, leaving it to the system; \
+            after a power loss, the rows not synced may be lost and the last line truncated, \
+            it is moved to a file named after the csv file with _truncated at the next start",
+        )
+        .long("fsync")
+        .takes_value(true)
+        .default_value("always");
+    let arg_integrity = Arg::with_name("integrity")
+        .help("append to each row a hash chained to the previous row, checked by flintec_verify")
+        .long_help(
+            "append the column sha256 to each row, the hash of the row chained to the hash \
+            of the previous row, and for the first row of each session to the session parameters \
+            written to the session log; flintec_verify reports the first row edited after logging",
+        )
+        .long("integrity")
+        .takes_value(false);
+    let arg_integrity_key = Arg::with_name("integrity_key")
+        .help("file of the secret key of the hash chain, kept away from the csv files")
+        .long_help(
+            "key the hash chain with the secret key in the file, at least 16 bytes, \
+            appending the column hmac_sha256 instead of sha256, so that the chain cannot be \
+            recomputed after editing the csv file without the key; the key file must not be in \
+            the directory of the csv file, and is needed by flintec_verify --key; implies --integrity",
+        )
+        .long("integrity_key")
+        .takes_value(true);
+    let arg_config_check = Arg::with_name("config_check")
+        .help("read the amplifier configuration: off, start, or every n hours")
+        .long_help(
+            "read the identity and firmware version of the amplifiers \
+            after the first reading of the session and \
+            then every n hours, or only at start, or off; the queries stop before the next reading \
+            and after the first one without reply; each check is written to the metadata \
+            log next to the csv file, and the changes between checks to the event and session logs",
+        )
+        .long("config_check")
+        .takes_value(true)
+        .default_value("start");
     let arg_verbose = Arg::with_name("verbose")
         .help("print verbose information")
         .short("v")
@@ -65,71 +367,1971 @@ pub fn parse_cli_log() -> (String, String, u16, String, u32, u64, bool) {
         .author("Luca Peruzzo")
         .about("cli app to log the load cells")
         .arg(arg_csvfile)
+        .arg(arg_seconds)
         .arg(arg_minutes)
         .arg(arg_hours)
+        .arg(arg_anchor)
+        .arg(arg_timezone)
         .arg(arg_tcmd)
         .arg(arg_delay)
         .arg(arg_verbose)
-        .arg(arg_ip)
-        .arg(arg_port)
+        .args(&endpoint_args())
+        .arg(arg_devices)
+        .arg(arg_per_device)
+        .arg(arg_rotate)
+        .arg(arg_compress)
+        .arg(arg_burst)
+        .arg(arg_burst_window)
+        .arg(arg_acquisition_times)
+        .arg(arg_clock_jump)
+        .arg(arg_timeout)
+        .arg(arg_pause)
+        .arg(arg_retry)
+        .arg(arg_max_retry)
+        .arg(arg_max_outage)
+        .arg(arg_drain)
+        .arg(arg_http)
+        .arg(arg_alerts)
+        .arg(arg_sqlite)
+        .arg(arg_spool_rows)
+        .arg(arg_fallback_dir)
+        .arg(arg_fsync)
+        .arg(arg_integrity)
+        .arg(arg_integrity_key)
+        .arg(arg_config_check)
+        .arg(arg_mqtt)
+        .arg(arg_mqtt_topic)
+        .arg(arg_mqtt_qos)
+        .arg(arg_mqtt_no_retain)
+        .arg(arg_mqtt_queue)
         .get_matches();
     let val_csvfile = String::from(cli_args.value_of("csvfile").unwrap_or_default());
-    let val_ip = String::from(cli_args.value_of("ip_address").unwrap_or_default());
-    let val_port = cli_args
-        .value_of("port")
+    let val_endpoint = parse_endpoint(&cli_args);
+    let val_tcmd = cli_args
+        .value_of("tcmd")
         .unwrap_or_default()
-        .parse::<u16>()
+        .parse::<Command>()
         .unwrap();
-    let val_tcmd = String::from(cli_args.value_of("tcmd").unwrap_or_default().to_uppercase());
     let val_delay = cli_args
         .value_of("delay")
         .unwrap_or_default()
         .parse::<u64>()
         .unwrap();
+    let val_devices: Vec<Device> = match cli_args.value_of("devices") {
+        Some(f) => read_devices(f),
+        None => vec![Device {
+            name: String::from("dad"),
+            endpoint: val_endpoint,
+            command: val_tcmd,
+        }],
+    };
+    let val_per_device: bool = cli_args.is_present("per_device_files");
     let val_verbose: bool = cli_args.is_present("verbose");
+    let val_rotate = cli_args
+        .value_of("rotate")
+        .unwrap_or_default()
+        .parse::<Rotation>()
+        .unwrap();
+    let val_compress: bool = cli_args.is_present("compress");
+    let val_seconds = cli_args.value_of("seconds");
     let val_minutes = cli_args.value_of("minutes");
     let val_hours = cli_args.value_of("hours");
-    let val_interval: u32 = if val_hours.is_some() {
-        val_hours.unwrap().parse::<u32>().unwrap() * 60 as u32
-    } else {
-        val_minutes.unwrap_or_default().parse::<u32>().unwrap()
+    let val_interval = match (val_seconds, val_hours) {
+        (Some(s), _) => chrono::Duration::seconds(s.parse::<i64>().unwrap()),
+        (None, Some(h)) => chrono::Duration::hours(h.parse::<i64>().unwrap()),
+        (None, None) => {
+            chrono::Duration::minutes(val_minutes.unwrap_or_default().parse::<i64>().unwrap())
+        }
+    };
+    assert!(
+        val_interval > chrono::Duration::zero(),
+        "the interval must be positive"
+    );
+    if (chrono::Duration::days(1).num_seconds() % val_interval.num_seconds()) != 0 {
+        println!(
+            "warning, the interval of {} is not a divisor of 1 day, \
+            the reading times will not repeat at the same times every day",
+            format_interval(val_interval)
+        );
+    }
+    let val_zone = cli_args
+        .value_of("timezone")
+        .unwrap_or_default()
+        .parse::<Zone>()
+        .unwrap();
+    let val_anchor = cli_args
+        .value_of("anchor")
+        .unwrap_or_default()
+        .parse::<Anchor>()
+        .unwrap()
+        .in_zone(val_zone);
+    let val_burst_count = cli_args
+        .value_of("burst")
+        .unwrap_or_default()
+        .parse::<usize>()
+        .unwrap();
+    let val_burst_window = cli_args
+        .value_of("burst_window")
+        .unwrap_or_default()
+        .parse::<u64>()
+        .unwrap();
+    let val_burst = match val_burst_count {
+        0 => panic!("the burst needs at least 1 reading"),
+        1 => None,
+        count => {
+            assert!(
+                (val_burst_window as i64) < val_interval.num_seconds(),
+                "the burst window must be shorter than the interval"
+            );
+            Some(Burst {
+                count,
+                window: Duration::from_secs(val_burst_window),
+            })
+        }
+    };
+    let seconds = |name: &str, s: &str| match s.parse::<f64>() {
+        Ok(v) if v >= 0. => Duration::from_secs_f64(v),
+        _ => panic!("invalid {} {}, use a positive number of seconds", name, s),
+    };
+    let option_seconds = |name: &str| seconds(name, cli_args.value_of(name).unwrap_or_default());
+    let val_timing = Timing {
+        timeout: option_seconds("timeout"),
+        write_read_pause: option_seconds("pause"),
+        connection_retry: option_seconds("retry"),
+        max_retry: option_seconds("max_retry"),
+        max_outage: cli_args
+            .value_of("max_outage")
+            .map(|s| seconds("max_outage", s)),
+        drain: option_seconds("drain"),
     };
-    return (
-        val_csvfile,
-        val_ip,
-        val_port,
-        val_tcmd,
-        val_interval,
-        val_delay,
-        val_verbose,
+    assert!(
+        val_timing.timeout > Duration::from_secs(0) && val_timing.drain > Duration::from_secs(0),
+        "the timeout and the drain timeout must be longer than zero"
     );
+    let val_http = cli_args.value_of("http").map(|a| {
+        a.parse::<SocketAddr>()
+            .unwrap_or_else(|_| panic!("invalid http address {}, use ip:port", a))
+    });
+    let val_alerts = match cli_args.value_of("alerts") {
+        Some(f) => read_alert_rules(f),
+        None => Vec::new(),
+    };
+    let spool_rows = cli_args.value_of("spool_rows").unwrap_or_default();
+    let val_spool_rows = match spool_rows.parse::<usize>() {
+        Ok(r) if r > 0 => r,
+        _ => panic!("invalid spool rows {}, use a positive integer", spool_rows),
+    };
+    let val_mqtt = cli_args.value_of("mqtt").map(|broker| {
+        let mut settings = MqttSettings::new(broker);
+        settings.topic = String::from(cli_args.value_of("mqtt_topic").unwrap_or_default());
+        settings.qos = cli_args
+            .value_of("mqtt_qos")
+            .unwrap_or_default()
+            .parse()
+            .unwrap();
+        settings.retain = !cli_args.is_present("mqtt_no_retain");
+        let queue = cli_args.value_of("mqtt_queue").unwrap_or_default();
+        settings.queue_len = match queue.parse::<usize>() {
+            Ok(q) if q > 0 => q,
+            _ => panic!("invalid mqtt queue {}, use a positive integer", queue),
+        };
+        settings
+    });
+    let val_integrity_key = cli_args.value_of("integrity_key").map(|k| {
+        assert!(
+            !in_same_directory(k, &val_csvfile),
+            "the key {} is in the directory of the csv file, keep it away from the data",
+            k
+        );
+        read_key(k).unwrap_or_else(|e| panic!("could not read the key {}, error {}", k, e))
+    });
+    LogOptions {
+        csv_name: val_csvfile,
+        devices: val_devices,
+        interval: val_interval,
+        anchor: val_anchor,
+        delay: val_delay,
+        verbose: val_verbose,
+        per_device: val_per_device,
+        rotation: val_rotate,
+        compress: val_compress,
+        burst: val_burst,
+        timing: val_timing,
+        http: val_http,
+        alert_rules: val_alerts,
+        sqlite: cli_args.value_of("sqlite").map(String::from),
+        mqtt: val_mqtt,
+        acquisition_times: cli_args.is_present("acquisition_times"),
+        clock_policy: cli_args
+            .value_of("clock_jump")
+            .unwrap_or_default()
+            .parse::<ClockPolicy>()
+            .unwrap(),
+        zone: val_zone,
+        spool_rows: val_spool_rows,
+        fallback_dir: cli_args.value_of("fallback_dir").map(String::from),
+        fsync: cli_args
+            .value_of("fsync")
+            .unwrap_or_default()
+            .parse::<FsyncPolicy>()
+            .unwrap(),
+        integrity: cli_args.is_present("integrity") || val_integrity_key.is_some(),
+        integrity_key: val_integrity_key,
+        config_check: cli_args
+            .value_of("config_check")
+            .unwrap_or_default()
+            .parse::<ConfigCheck>()
+            .unwrap(),
+    }
 }
 
-pub fn prepare_csvfile(file: &str) -> std::fs::File {
-    if std::path::Path::new(&file).exists() {
-        println!("csvfile {} already exists, values will be appended", file);
+/// CLI arguments of the connection to one amplifier, over telnet or serial.
+pub fn endpoint_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("ip_address")
+            .help("ip address for the telnet connection")
+            .short("t")
+            .long("ip")
+            .takes_value(true)
+            .required(true)
+            .default_value("192.168.0.100"),
+        Arg::with_name("port")
+            .help("port for the telnet connection")
+            .short("p")
+            .long("port")
+            .takes_value(true)
+            .default_value("23"),
+        Arg::with_name("serial")
+            .help("tty device for the serial connection, used instead of telnet")
+            .long("serial")
+            .takes_value(true),
+        Arg::with_name("baud")
+            .help("baud rate for the serial connection")
+            .long("baud")
+            .takes_value(true)
+            .default_value("9600"),
+        Arg::with_name("parity")
+            .help("parity for the serial connection")
+            .long("parity")
+            .takes_value(true)
+            .possible_values(&["none", "odd", "even"])
+            .default_value("none"),
+        Arg::with_name("stop_bits")
+            .help("stop bits for the serial connection")
+            .long("stop_bits")
+            .takes_value(true)
+            .possible_values(&["1", "2"])
+            .default_value("1"),
+    ]
+}
+
+/// Endpoint of the connection from the CLI arguments of `endpoint_args`.
+pub fn parse_endpoint(cli_args: &ArgMatches) -> Endpoint {
+    match cli_args.value_of("serial") {
+        Some(path) => {
+            let baud_rate = cli_args
+                .value_of("baud")
+                .unwrap_or_default()
+                .parse::<u32>()
+                .unwrap();
+            Endpoint::Serial(SerialSettings::new(
+                path,
+                baud_rate,
+                cli_args.value_of("parity").unwrap_or_default(),
+                cli_args.value_of("stop_bits").unwrap_or_default(),
+            ))
+        }
+        None => {
+            let ip: Ipv4Addr = cli_args
+                .value_of("ip_address")
+                .unwrap_or_default()
+                .parse()
+                .expect("arg string is not a valid ip address");
+            let port = cli_args
+                .value_of("port")
+                .unwrap_or_default()
+                .parse::<u16>()
+                .unwrap();
+            Endpoint::Tcp(SocketAddrV4::new(ip, port))
+        }
+    }
+}
+
+/// Header of the csv file of one device.
+pub const CSV_HEADER: &str = "datetime,load_kg,raw_reading\n";
+
+/// Initiate the csv file or check it to resume logging,
+/// returning the file and the last datetime already written.
+pub fn prepare_csvfile(
+    file: &str,
+    interval: chrono::Duration,
+) -> (std::fs::File, Option<DateTime<Local>>) {
+    prepare_csvfile_with_header(file, CSV_HEADER, interval)
+}
+
+/// Initiate the csv file with the header, or check that the existing file has the same header
+/// and interval, panicking otherwise, to resume logging after the last datetime.
+/// The reading times are rounded to the default anchor, the local midnight.
+pub fn prepare_csvfile_with_header(
+    file: &str,
+    header: &str,
+    interval: chrono::Duration,
+) -> (std::fs::File, Option<DateTime<Local>>) {
+    match open_csvfile(file, header, interval, Anchor::default()) {
+        Ok(opened) => opened,
+        Err(e) => panic!("{}", e),
+    }
+}
+
+/// Initiate the csv file with the header or open the existing one to append,
+/// returning an error if the header or the interval differs, see `check_csvfile`.
+pub fn open_csvfile(
+    file: &str,
+    header: &str,
+    interval: chrono::Duration,
+    anchor: Anchor,
+) -> Result<(std::fs::File, Option<DateTime<Local>>), String> {
+    if Path::new(&file).exists() {
+        match repair_csvfile(file) {
+            Ok(0) => (),
+            Ok(cut) => println!(
+                "csvfile {} ended with a truncated line, moved its {} byte(s) to {}",
+                file,
+                cut,
+                truncated_name(file)
+            ),
+            Err(e) => return Err(format!("could not repair csvfile {}, error: {}", file, e)),
+        }
+    }
+    let empty = std::fs::metadata(file).map_or(true, |m| m.len() == 0);
+    let last = if !empty {
+        match check_csvfile(file, header, interval, anchor) {
+            Ok(Some(last)) => {
+                println!(
+                    "csvfile {} already exists, values will be appended after {}",
+                    file,
+                    last.to_rfc3339_opts(SecondsFormat::Secs, false)
+                );
+                Some(last)
+            }
+            Ok(None) => {
+                println!("csvfile {} already exists, values will be appended", file);
+                None
+            }
+            Err(e) => return Err(format!("refusing to append to csvfile {}: {}", file, e)),
+        }
     } else {
-        match std::fs::write(&file, "datetime,load_kg,raw_reading\n") {
+        match write_header(file, header) {
             Ok(_) => println!("initiated csvfile {}", file),
-            Err(e) => panic!("could not initiate csvfile {}, error: {}", file, e),
+            Err(e) => return Err(format!("could not initiate csvfile {}, error: {}", file, e)),
         }
-    }
+        None
+    };
     let file = std::fs::OpenOptions::new()
         .append(true)
         .create(true)
-        .open(&file)
+        .open(file)
+        .map_err(|e| format!("could not open csvfile {}, error: {}", file, e))?;
+    Ok((file, last))
+}
+
+/// Write the header to the new csv file and sync it, with its directory entry.
+fn write_header(file: &str, header: &str) -> std::io::Result<()> {
+    let mut f = File::create(file)?;
+    f.write_all(header.as_bytes())?;
+    f.sync_all()?;
+    let dir = match Path::new(file).parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    // directories cannot be synced on some systems, the file is synced anyway
+    if let Ok(d) = File::open(dir) {
+        let _ = d.sync_all();
+    }
+    Ok(())
+}
+
+/// Name of the file where the truncated lines cut from the csv file are kept.
+pub fn truncated_name(csv_name: &str) -> String {
+    with_suffix(csv_name, "truncated", Some("txt"))
+}
+
+/// Cut the last line of the csv file if it does not end with a newline,
+/// e.g., half written when the power was lost, so that the next row starts on its own line.
+/// The cut bytes are appended to the file named by `truncated_name` before cutting.
+/// Return the number of bytes cut.
+pub fn repair_csvfile(csv_name: &str) -> std::io::Result<usize> {
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(csv_name)?;
+    let len = file.metadata()?.len();
+    let mut last = [0u8; 1];
+    if len == 0 {
+        return Ok(0);
+    }
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    if last[0] == b'\n' {
+        return Ok(0);
+    }
+    // search the last newline backwards, block by block
+    let mut keep = 0;
+    let mut end = len;
+    let mut block = vec![0u8; 4096];
+    while end > 0 {
+        let start = end.saturating_sub(block.len() as u64);
+        let n = (end - start) as usize;
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut block[..n])?;
+        if let Some(i) = block[..n].iter().rposition(|b| *b == b'\n') {
+            keep = start + i as u64 + 1;
+            break;
+        }
+        end = start;
+    }
+    let mut cut = Vec::with_capacity((len - keep) as usize);
+    file.seek(SeekFrom::Start(keep))?;
+    file.read_to_end(&mut cut)?;
+    let mut quarantine = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(truncated_name(csv_name))?;
+    cut.push(b'\n');
+    quarantine.write_all(&cut)?;
+    quarantine.sync_all()?;
+    file.set_len(keep)?;
+    file.sync_all()?;
+    Ok(cut.len() - 1)
+}
+
+/// Write the row and its newline with a single write, so that a power loss
+/// truncates at most the last line, which `open_csvfile` repairs at the next start.
+pub fn write_line<W: Write + ?Sized>(output: &mut W, row: &str) -> std::io::Result<()> {
+    let mut line = String::with_capacity(row.len() + 1);
+    line.push_str(row);
+    line.push('\n');
+    output.write_all(line.as_bytes())
+}
+
+/// Rows at the end of an existing csv file whose spacing is checked against the interval.
+const CHECKED_ROWS: usize = 16;
+
+/// Check the header of an existing csv file, the most common interval between its last rows,
+/// and that its last datetime is on the grid of the anchor, returning the last datetime, if any.
+/// The most common interval, rather than the last one, accepts the gaps of the clock jumps.
+pub fn check_csvfile<P>(
+    fin: P,
+    header: &str,
+    interval: chrono::Duration,
+    anchor: Anchor,
+) -> Result<Option<DateTime<Local>>, String>
+where
+    P: AsRef<Path>,
+{
+    let file = File::open(fin).map_err(|e| format!("could not open it, error {}", e))?;
+    let mut lines = BufReader::new(file).lines();
+    let found = match lines.next() {
+        Some(Ok(l)) => l,
+        Some(Err(e)) => return Err(format!("could not read the header, error {}", e)),
+        None => return Ok(None),
+    };
+    if found.trim_end() != header.trim_end() {
+        return Err(format!(
+            "header {} differs from {}",
+            found.trim_end(),
+            header.trim_end()
+        ));
+    }
+    let mut last_rows: VecDeque<DateTime<FixedOffset>> = VecDeque::with_capacity(CHECKED_ROWS);
+    for l in lines {
+        let l = match l {
+            Ok(l) => l,
+            Err(e) => {
+                println!("Err, could not read/unwrap line {}", e);
+                continue;
+            }
+        };
+        let datetime = l.split(',').next().unwrap_or_default();
+        if let Ok(d) = DateTime::parse_from_rfc3339(datetime) {
+            if last_rows.len() == CHECKED_ROWS {
+                last_rows.pop_front();
+            }
+            last_rows.push_back(d);
+        }
+    }
+    if let Some(modal) = modal_interval(&last_rows) {
+        if modal != interval {
+            return Err(format!(
+                "interval of {} differs from the most common interval of {} in the file",
+                format_interval(interval),
+                format_interval(modal)
+            ));
+        }
+    }
+    let last = last_rows.back().map(|l| l.with_timezone(&Local));
+    if let Some(l) = last {
+        let remainder = anchor.remainder(l, interval);
+        if remainder != chrono::Duration::zero() {
+            return Err(format!(
+                "last datetime {} is {} off the reading times of the anchor",
+                l.to_rfc3339_opts(SecondsFormat::Secs, false),
+                format_interval(remainder)
+            ));
+        }
+    }
+    Ok(last)
+}
+
+/// Most common interval between the datetimes, the shortest one if several are as common,
+/// none with less than two datetimes.
+fn modal_interval(datetimes: &VecDeque<DateTime<FixedOffset>>) -> Option<chrono::Duration> {
+    let mut counts: Vec<(chrono::Duration, usize)> = Vec::new();
+    for (previous, next) in datetimes.iter().zip(datetimes.iter().skip(1)) {
+        let interval = *next - *previous;
+        match counts.iter_mut().find(|(i, _)| *i == interval) {
+            Some((_, count)) => *count += 1,
+            None => counts.push((interval, 1)),
+        }
+    }
+    counts
+        .into_iter()
+        .max_by(|(i, a), (j, b)| a.cmp(b).then(j.cmp(i)))
+        .map(|(i, _)| i)
+}
+
+/// Flag to stop logging after the current reading, e.g., set by a signal handler.
+#[derive(Debug, Clone, Default)]
+pub struct StopFlag {
+    stopped: Arc<AtomicBool>,
+    failed: Arc<AtomicBool>,
+    reason: Arc<Mutex<String>>,
+}
+
+impl StopFlag {
+    pub fn new() -> StopFlag {
+        StopFlag::default()
+    }
+
+    /// Request to stop, keeping the first reason.
+    pub fn stop(&self, reason: &str) {
+        let mut r = self.reason.lock().unwrap();
+        if !self.stopped.swap(true, Ordering::SeqCst) {
+            *r = String::from(reason);
+        }
+    }
+
+    /// Request to stop because logging cannot continue, e.g., after a too long outage.
+    pub fn fail(&self, reason: &str) {
+        if !self.is_stopped() {
+            self.failed.store(true, Ordering::SeqCst);
+        }
+        self.stop(reason);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// True if stopped by a failure, to exit non-zero.
+    pub fn is_failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Reason given when stopping, if stopped.
+    pub fn reason(&self) -> Option<String> {
+        if self.is_stopped() {
+            Some(self.reason.lock().unwrap().clone())
+        } else {
+            None
+        }
+    }
+}
+
+/// Output that can be flushed down to the disk.
+pub trait SyncWrite: Write {
+    fn sync(&mut self) -> std::io::Result<()>;
+
+    /// Switch to the file of the given scheduled time before writing its row, if rotating.
+    fn rotate(&mut self, _dtr: DateTime<Local>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Write a complete row, leaving none of it in the output if it fails.
+    fn write_row(&mut self, row: &str) -> std::io::Result<()> {
+        write_line(self, row)
+    }
+}
+
+impl SyncWrite for File {
+    fn sync(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        self.sync_all()
+    }
+
+    /// Truncate the part of the row written before an error, as on a full disk.
+    fn write_row(&mut self, row: &str) -> std::io::Result<()> {
+        let len = self.metadata()?.len();
+        write_line(self, row).inspect_err(|_| {
+            let _ = self
+                .set_len(len)
+                .and_then(|_| self.seek(SeekFrom::Start(len)));
+        })
+    }
+}
+
+impl SyncWrite for Vec<u8> {
+    fn sync(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Timing of the protocol, configurable from the CLI.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timing {
+    /// Read and write timeout of the connection.
+    pub timeout: Duration,
+    /// Pause between writing the command and reading the reply.
+    pub write_read_pause: Duration,
+    /// First pause between attempts to refresh the connection, doubled after each failed attempt.
+    pub connection_retry: Duration,
+    /// Longest pause between attempts to refresh the connection.
+    pub max_retry: Duration,
+    /// Longest outage before giving up, none to retry forever.
+    pub max_outage: Option<Duration>,
+    /// Read timeout while discarding stale bytes before each command.
+    pub drain: Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        Timing {
+            timeout: Duration::new(15, 0),
+            write_read_pause: Duration::new(2, 0),
+            connection_retry: Duration::new(30, 0),
+            max_retry: Duration::new(600, 0),
+            max_outage: None,
+            drain: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Connection to one amplifier: send the command, read the reply,
+/// and refresh the connection after errors.
+pub struct Amplifier<T: Transport> {
+    pub transport: T,
+    pub command: Command,
+    /// Read timeout set on the transport, restored after draining.
+    pub timeout: Duration,
+    pub write_read_pause: Duration,
+    pub connection_retry: Duration,
+    pub max_retry: Duration,
+    pub max_outage: Option<Duration>,
+    /// Random fraction of the retry pause added or removed, so that several loggers do not retry together.
+    pub jitter: f64,
+    pub drain: Duration,
+    pub stop: StopFlag,
+    /// Readings around each scheduled time, one reading if none.
+    pub burst: Option<Burst>,
+    /// Session log where the outages are written.
+    pub session: Option<SessionLog>,
+    /// Event log of the connection and reading errors.
+    pub events: EventLog,
+    /// Status of the device, for the HTTP endpoint.
+    pub monitor: Monitor,
+    /// When the configuration is read, written to the metadata log.
+    pub config_check: ConfigCheck,
+    pub metadata: EventLog,
+    connection_ok: bool,
+    frames: FrameReader,
+    rng: XorShift,
+    acquisition: Acquisition,
+    /// Last configuration read and when the check started.
+    config: Option<(DateTime<Local>, Config)>,
+}
+
+impl<T: Transport> Amplifier<T> {
+    /// Initiate with the default timing, 2 seconds between command and reading,
+    /// and 30 seconds to 10 minutes between reconnections, with 20% jitter.
+    pub fn new(transport: T, command: Command) -> Amplifier<T> {
+        let timing = Timing::default();
+        Amplifier {
+            transport,
+            command,
+            timeout: timing.timeout,
+            write_read_pause: timing.write_read_pause,
+            connection_retry: timing.connection_retry,
+            max_retry: timing.max_retry,
+            max_outage: timing.max_outage,
+            jitter: 0.2,
+            drain: timing.drain,
+            stop: StopFlag::new(),
+            burst: None,
+            session: None,
+            events: EventLog::disabled(),
+            monitor: Monitor::disabled(),
+            config_check: ConfigCheck::default(),
+            metadata: EventLog::disabled(),
+            connection_ok: true,
+            frames: FrameReader::new(),
+            rng: XorShift::from_time(),
+            acquisition: Acquisition::default(),
+            config: None,
+        }
+    }
+
+    /// Set the pauses and timeouts, the timeout must be the one of the transport.
+    pub fn set_timing(&mut self, timing: &Timing) {
+        self.timeout = timing.timeout;
+        self.write_read_pause = timing.write_read_pause;
+        self.connection_retry = timing.connection_retry;
+        self.max_retry = timing.max_retry;
+        self.max_outage = timing.max_outage;
+        self.drain = timing.drain;
+    }
+
+    /// False after an IO error or a missing reply, until the connection is recovered.
+    pub fn connection_ok(&self) -> bool {
+        self.connection_ok
+    }
+
+    /// Times of the command and of the reply of the last sample.
+    pub fn acquisition(&self) -> Acquisition {
+        self.acquisition
+    }
+
+    /// Discard the stale bytes, send the command, and read the reply frame,
+    /// returning the raw reading and the load, or the error codes.
+    /// Weight frames of another kind than the expected one are late replies and are discarded.
+    pub fn read_load<C: Clock>(&mut self, clock: &mut C, dtr_str: &str) -> (String, f64) {
+        let discarded = self
+            .frames
+            .drain(&mut self.transport, self.drain, self.timeout);
+        if discarded > 0 {
+            println!(
+                "warning, discarded {} stale byte(s) before the command",
+                discarded
+            );
+            self.events.warning(
+                "stale_bytes",
+                &[("datetime", dtr_str.into()), ("bytes", discarded.into())],
+            );
+        }
+
+        let tcmd_bytes = self.command.to_bytes();
+        let written = self.transport.write_all(&tcmd_bytes);
+        if written.is_ok() && self.acquisition.sent.is_none() {
+            self.acquisition.sent = Some(clock.now());
+        }
+        if let Err(e) = written {
+            println!(
+                "warning, failed to write command {}, error {}",
+                self.command, e
+            );
+            self.events.warning(
+                "command_failed",
+                &[
+                    ("datetime", dtr_str.into()),
+                    ("command", self.command.to_string().into()),
+                    ("error", e.to_string().into()),
+                ],
+            );
+        }
+
+        clock.sleep(self.write_read_pause);
+
+        loop {
+            let frame = match self.frames.read_frame(&mut self.transport) {
+                Ok(f) => {
+                    self.acquisition.received = Some(clock.now());
+                    f
+                }
+                Err(FrameError::Closed) => {
+                    println!("{} no data", dtr_str);
+                    self.connection_lost(dtr_str, &FrameError::Closed);
+                    return (String::from(ERROR_STR_NONE), ERROR_FLT_NONE);
+                }
+                Err(FrameError::Io(e)) => {
+                    println!("{} IO error, {}", dtr_str, e);
+                    self.connection_lost(dtr_str, &FrameError::Io(e));
+                    return (String::from(ERROR_STR_GENERAL), ERROR_FLT_GENERAL);
+                }
+                Err(e) => {
+                    println!("{} {}", dtr_str, e);
+                    self.events.warning(
+                        "invalid_frame",
+                        &[
+                            ("datetime", dtr_str.into()),
+                            ("error", e.to_string().into()),
+                        ],
+                    );
+                    return (String::from(ERROR_STR_INVALID), ERROR_FLT_INVALID);
+                }
+            };
+            match Reading::parse(&frame) {
+                Ok(_) if frame.len() != FRAME_LEN => {
+                    println!(
+                        "{} parse error, frame {} of length {} instead of {}",
+                        dtr_str,
+                        frame,
+                        frame.len(),
+                        FRAME_LEN
+                    );
+                    self.parse_error(dtr_str, &frame, "unexpected frame length");
+                    return (frame, ERROR_FLT_PARSE);
+                }
+                Ok(r) if self.command.reply_kind().is_some_and(|k| k != r.kind) => {
+                    println!("{} discarding stale frame {}", dtr_str, frame);
+                    self.events.warning(
+                        "stale_frame",
+                        &[("datetime", dtr_str.into()), ("frame", frame.into())],
+                    );
+                }
+                Ok(r) => return (frame, r.value),
+                Err(e) => {
+                    println!("{} parse error, {}", dtr_str, e);
+                    self.parse_error(dtr_str, &frame, &e.to_string());
+                    return (frame, ERROR_FLT_PARSE);
+                }
+            }
+        }
+    }
+
+    fn connection_lost(&mut self, dtr_str: &str, e: &FrameError) {
+        if self.connection_ok {
+            self.events.warning(
+                "connection_lost",
+                &[
+                    ("datetime", dtr_str.into()),
+                    ("error", e.to_string().into()),
+                ],
+            );
+        }
+        self.connection_ok = false;
+        self.monitor.connection(false);
+    }
+
+    fn parse_error(&self, dtr_str: &str, frame: &str, error: &str) {
+        self.events.warning(
+            "parse_error",
+            &[
+                ("datetime", dtr_str.into()),
+                ("frame", frame.into()),
+                ("error", error.into()),
+            ],
+        );
+    }
+
+    /// Read the load once, or the burst of readings spread over the window,
+    /// returning the raw reading, the load, and the statistics columns of the burst, if any.
+    /// The load of a burst is the mean of the valid readings and the raw reading the first valid one;
+    /// without valid readings, they are those of the last reading.
+    /// The burst ends early if the connection is lost.
+    /// The times of the first command and of the last reply are kept as the acquisition.
+    pub fn read_sample<C: Clock>(&mut self, clock: &mut C, dtr_str: &str) -> (String, f64, String) {
+        self.acquisition = Acquisition::default();
+        let burst = match self.burst {
+            Some(b) => b,
+            None => {
+                let (raw_reading, w) = self.read_load(clock, dtr_str);
+                return (raw_reading, w, String::new());
+            }
+        };
+        let start = clock.now();
+        let step = chrono::Duration::from_std(burst.step()).unwrap();
+        let mut readings: Vec<(String, f64)> = Vec::with_capacity(burst.count);
+        for i in 0..burst.count {
+            if let Ok(wait) = (start + step * i as i32 - clock.now()).to_std() {
+                clock.sleep(wait);
+            }
+            readings.push(self.read_load(clock, dtr_str));
+            if !self.connection_ok {
+                break;
+            }
+        }
+        let loads: Vec<f64> = readings.iter().map(|(_, w)| *w).collect();
+        match Stats::from_readings(&loads) {
+            Some(stats) => {
+                let (raw_reading, _) = readings.iter().find(|(_, w)| !is_error_code(*w)).unwrap();
+                let mean = (stats.mean * 1000.).round() / 1000.;
+                (raw_reading.clone(), mean, stats.to_csv())
+            }
+            None => {
+                let (raw_reading, w) = readings.pop().unwrap();
+                (raw_reading, w, empty_stats_csv())
+            }
+        }
+    }
+
+    /// Send the query of the setting and read the reply, none if not answered,
+    /// or the error without reply. Error replies and weight frames,
+    /// late replies of the readings, are not answers.
+    fn query<C: Clock>(
+        &mut self,
+        clock: &mut C,
+        setting: Setting,
+    ) -> Result<Option<String>, FrameError> {
+        self.frames
+            .drain(&mut self.transport, self.drain, self.timeout);
+        self.transport
+            .write_all(&setting.to_bytes())
+            .map_err(FrameError::Io)?;
+        clock.sleep(self.write_read_pause);
+        match self.frames.read_frame(&mut self.transport) {
+            Ok(reply) if reply.trim() == ERROR_FRAME || Reading::parse(&reply).is_ok() => Ok(None),
+            Ok(reply) => Ok(Some(String::from(reply.trim()))),
+            Err(e @ FrameError::Closed) | Err(e @ FrameError::Io(_)) => Err(e),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Read the configuration if due, write it to the metadata log,
+    /// and report each setting that changed since the last check.
+    /// Each query waits for the pause and up to the timeout, so the queries are stopped
+    /// when the longest one would end after the deadline, e.g., the next scheduled time,
+    /// or after the first one without reply; the settings not read are left unanswered.
+    pub fn check_config<C: Clock>(
+        &mut self,
+        clock: &mut C,
+        device: &str,
+        deadline: DateTime<Local>,
+    ) {
+        let last = self.config.as_ref().map(|(t, _)| *t);
+        let started = clock.now();
+        if !self.connection_ok || !self.config_check.is_due(last, started) {
+            return;
+        }
+        let longest = chrono::Duration::from_std(self.drain + self.write_read_pause + self.timeout)
+            .unwrap_or(chrono::Duration::MAX);
+        let mut config = Config::default();
+        let mut stopped = None;
+        for setting in Setting::ALL.iter() {
+            let value = if stopped.is_some() {
+                None
+            } else if deadline - clock.now() < longest {
+                stopped = Some("not enough time before the next reading");
+                None
+            } else {
+                match self.query(clock, *setting) {
+                    Ok(value) => value,
+                    Err(e) if e.is_timeout() => {
+                        stopped = Some("no reply");
+                        None
+                    }
+                    Err(e) => {
+                        self.connection_lost("config", &e);
+                        return;
+                    }
+                }
+            };
+            config.values.push((*setting, value));
+        }
+        if let Some(reason) = stopped {
+            let read = config.values.iter().filter(|(_, v)| v.is_some()).count();
+            println!(
+                "warning, {} configuration check stopped, {}, {} of {} setting(s) read",
+                device,
+                reason,
+                read,
+                config.values.len()
+            );
+        }
+        if config.is_empty() {
+            if stopped.is_none() {
+                println!("{} did not answer the configuration queries", device);
+            }
+            self.config = Some((started, config));
+            return;
+        }
+        let mut fields = config.fields();
+        fields.push((
+            "check",
+            if last.is_none() { "start" } else { "periodic" }.into(),
+        ));
+        self.metadata.info("amplifier_config", &fields);
+        if let Some((_, previous)) = &self.config {
+            for (setting, before, after) in config.changes(previous) {
+                let drift = format!(
+                    "{} {} changed from {} to {}",
+                    device,
+                    setting.name(),
+                    before,
+                    after
+                );
+                println!("warning, configuration drift, {}", drift);
+                self.events.warning(
+                    "config_drift",
+                    &[
+                        ("setting", setting.name().into()),
+                        ("before", before.into()),
+                        ("after", after.into()),
+                    ],
+                );
+                if let Some(session) = &self.session {
+                    session.config_drift(&drift);
+                }
+            }
+        }
+        self.config = Some((started, config));
+    }
+
+    /// Pause before the next attempt to refresh the connection,
+    /// doubling from the first pause up to the longest one, with jitter.
+    pub fn retry_delay(&mut self, attempt: u32) -> Duration {
+        let delay = self
+            .connection_retry
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_retry);
+        let jitter = 1. + self.jitter * (2. * self.rng.next_f64() - 1.);
+        delay.mul_f64(jitter.max(0.))
+    }
+
+    /// Refresh the connection until successful, stopped, or longer than the maximum outage,
+    /// then write the outage, from the scheduled time of the failed reading, to the session log.
+    pub fn recover_connection<C: Clock>(
+        &mut self,
+        clock: &mut C,
+        dtr: DateTime<Local>,
+        interval: chrono::Duration,
+    ) -> Option<Outage> {
+        if self.connection_ok {
+            return None;
+        }
+        let mut attempt = 0;
+        while !self.connection_ok && !self.stop.is_stopped() {
+            if let Some(max_outage) = self.max_outage {
+                if (clock.now() - dtr).to_std().unwrap_or_default() > max_outage {
+                    self.stop.fail(&format!(
+                        "connection lost for more than {} s",
+                        max_outage.as_secs()
+                    ));
+                    break;
+                }
+            }
+            match self.transport.reconnect() {
+                Ok(_) => {
+                    println!("connection successful, resume logging");
+                    self.connection_ok = true;
+                    self.monitor.connection(true);
+                }
+                Err(e) => {
+                    let delay = self.retry_delay(attempt);
+                    println!(
+                        "connection failed, error {}, trying again in {:.0} s ...",
+                        e,
+                        delay.as_secs_f64()
+                    );
+                    self.events.info(
+                        "reconnect_failed",
+                        &[
+                            ("attempt", (attempt as usize + 1).into()),
+                            ("error", e.to_string().into()),
+                            ("retry_s", delay.as_secs_f64().into()),
+                        ],
+                    );
+                    sleep_unless_stopped(clock, delay, &self.stop);
+                    attempt = attempt.saturating_add(1);
+                }
+            }
+        }
+        let end = clock.now();
+        let outage = Outage {
+            start: dtr,
+            end,
+            slots_lost: 1 + (end - dtr).num_milliseconds() / interval.num_milliseconds(),
+            restored: self.connection_ok,
+        };
+        println!("{}", outage);
+        if let Some(session) = &self.session {
+            session.outage(&outage);
+        }
+        let fields = [
+            ("start", outage.start.into()),
+            ("end", outage.end.into()),
+            ("duration_s", (end - dtr).num_seconds().into()),
+            ("slots_lost", outage.slots_lost.into()),
+        ];
+        if outage.restored {
+            self.events.info("connection_restored", &fields);
+        } else {
+            self.events.error("connection_not_restored", &fields);
+        }
+        Some(outage)
+    }
+}
+
+/// Sleep in steps of at most 1 second to check the stop flag.
+fn sleep_unless_stopped<C: Clock>(clock: &mut C, duration: Duration, stop: &StopFlag) {
+    let end = clock.monotonic() + duration;
+    while !stop.is_stopped() {
+        match end.checked_sub(clock.monotonic()) {
+            Some(w) if w > Duration::from_secs(0) => clock.sleep(w.min(Duration::from_secs(1))),
+            _ => break,
+        }
+    }
+}
+
+/// Connection outage, from the scheduled time of the failed reading to the reconnection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outage {
+    pub start: DateTime<Local>,
+    pub end: DateTime<Local>,
+    /// Scheduled times without reading, the failed one included.
+    pub slots_lost: i64,
+    /// False if logging stopped before reconnecting.
+    pub restored: bool,
+}
+
+impl std::fmt::Display for Outage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "outage from {} to {}, {} s, {} slot(s) lost{}",
+            self.start.to_rfc3339_opts(SecondsFormat::Secs, false),
+            self.end.to_rfc3339_opts(SecondsFormat::Secs, false),
+            (self.end - self.start).num_seconds(),
+            self.slots_lost,
+            if self.restored { "" } else { ", not restored" }
+        )
+    }
+}
+
+/// Log the load at rounded times, writing one record per scheduled time to the sinks,
+/// e.g., one csv row. Readings that could not be taken are written with the error codes,
+/// while scheduled times that already passed are written as skipped.
+pub struct Logger<T: Transport, C: Clock> {
+    pub amplifier: Amplifier<T>,
+    pub clock: C,
+    pub schedule: Schedule,
+    pub verbose: bool,
+    /// Last datetime in the existing csv file, to write the missed times as skipped at start.
+    pub resume_after: Option<DateTime<Local>>,
+    /// Alert rules of the device, checked with each reading.
+    pub alerts: Alerts,
+    /// Name of the device in the records of the sinks.
+    pub device: String,
+    /// Destinations of the readings, e.g., the csv file.
+    pub sinks: Vec<Box<dyn Sink>>,
+    /// Time zone of the scheduled times written.
+    pub zone: Zone,
+    last_logged: Option<DateTime<Local>>,
+}
+
+impl<T: Transport, C: Clock> Logger<T, C> {
+    pub fn new(
+        transport: T,
+        clock: C,
+        command: Command,
+        interval: chrono::Duration,
+    ) -> Logger<T, C> {
+        let schedule = Schedule::new(interval, clock.now());
+        Logger {
+            amplifier: Amplifier::new(transport, command),
+            clock,
+            schedule,
+            verbose: false,
+            resume_after: None,
+            alerts: Alerts::none(),
+            device: String::from("dad"),
+            sinks: Vec::new(),
+            zone: Zone::default(),
+            last_logged: None,
+        }
+    }
+
+    /// Next scheduled reading time.
+    pub fn next(&self) -> DateTime<Local> {
+        self.schedule.next()
+    }
+
+    /// Flag to stop logging after the current reading.
+    pub fn stop_flag(&self) -> StopFlag {
+        self.amplifier.stop.clone()
+    }
+
+    /// Last scheduled time written to the sinks.
+    pub fn last_logged(&self) -> Option<DateTime<Local>> {
+        self.last_logged
+    }
+
+    /// Schedule the first reading at the next rounded time,
+    /// writing the times missed since the last datetime of the existing csv file as skipped.
+    pub fn start(&mut self) {
+        self.schedule.start(self.clock.now());
+        if let Some(last) = self.resume_after {
+            if self.schedule.start_after(last) {
+                print_clock_behind(&self.amplifier.events, last, self.next());
+            }
+            let missed = self.schedule.missed_since(last);
+            for dtr in missed.iter() {
+                let record = self.skipped_record(*dtr);
+                self.record(&record);
+            }
+            print_skipped(
+                &self.amplifier.events,
+                &missed,
+                "missed since the last reading",
+            );
+        }
+    }
+
+    /// Start and log until stopped, then flush the sinks to the disk.
+    pub fn run(&mut self) {
+        self.start();
+        let waited = self
+            .schedule
+            .wait_next(&mut self.clock, &self.amplifier.stop);
+        self.report_clock_jumps();
+        if waited {
+            println!("OK, logging ...");
+        }
+        while !self.amplifier.stop.is_stopped() {
+            self.step();
+        }
+        self.finish();
+    }
+
+    /// Flush and sync the sinks.
+    pub fn finish(&mut self) {
+        sync_sinks(&mut self.sinks, &self.amplifier.events);
+    }
+
+    /// Wait for the next scheduled time and log it,
+    /// then recover the connection and skip the scheduled times that already passed.
+    /// Return without logging if stopped while waiting.
+    pub fn step(&mut self) {
+        let waited = self
+            .schedule
+            .wait_next(&mut self.clock, &self.amplifier.stop);
+        self.report_clock_jumps();
+        if !waited {
+            return;
+        }
+        let dtr = self.schedule.advance();
+        self.log_at(dtr);
+        let deadline = self.next() - self.schedule.lead + self.schedule.offset();
+        self.amplifier
+            .check_config(&mut self.clock, &self.device, deadline);
+        self.amplifier.recover_connection(
+            &mut self.clock,
+            dtr + self.schedule.offset(),
+            self.schedule.interval,
+        );
+        self.skip_passed();
+    }
+
+    /// Read the load and write it to the sinks with the scheduled time.
+    pub fn log_at(&mut self, dtr: DateTime<Local>) {
+        let dtr_str = self
+            .zone
+            .fix(dtr)
+            .to_rfc3339_opts(SecondsFormat::Secs, false);
+        let (raw_reading, w, stats) = self.amplifier.read_sample(&mut self.clock, &dtr_str);
+        let record = Record {
+            device: self.device.clone(),
+            scheduled: self.zone.fix(dtr),
+            acquisition: self.amplifier.acquisition(),
+            load: w,
+            raw_reading: raw_reading.clone(),
+            stats,
+        };
+        self.record(&record);
+        if self.verbose {
+            println!(
+                "{}, logged load {}, raw reading {}; next at {}",
+                dtr_str,
+                w,
+                raw_reading,
+                self.next().to_rfc3339_opts(SecondsFormat::Secs, false)
+            );
+        }
+    }
+
+    /// Write the scheduled times that already passed as skipped, with a single summary line.
+    /// Clock jumps are handled first, to skip only with the wait policy.
+    pub fn skip_passed(&mut self) {
+        self.schedule.check_clock(&self.clock);
+        self.report_clock_jumps();
+        let mut passed = Vec::new();
+        while let Some(dtr) = self.schedule.advance_passed(self.schedule.now(&self.clock)) {
+            let record = self.skipped_record(dtr);
+            self.record(&record);
+            passed.push(dtr);
+        }
+        print_skipped(&self.amplifier.events, &passed, "already passed");
+    }
+
+    /// Record of a scheduled time written as skipped.
+    fn skipped_record(&self, dtr: DateTime<Local>) -> Record {
+        Record {
+            device: self.device.clone(),
+            scheduled: self.zone.fix(dtr),
+            acquisition: Acquisition::default(),
+            load: ERROR_FLT_SKIPPED,
+            raw_reading: String::from(ERROR_STR_SKIPPED),
+            stats: if self.amplifier.burst.is_some() {
+                empty_stats_csv()
+            } else {
+                String::new()
+            },
+        }
+    }
+
+    /// Update the status, check the alert rules, and write the sinks with the reading.
+    fn record(&mut self, record: &Record) {
+        let dtr = record.scheduled.with_timezone(&Local);
+        let (w, raw_reading) = (record.load, &record.raw_reading);
+        self.last_logged = Some(dtr);
+        self.amplifier.monitor.reading(dtr, w, raw_reading);
+        self.alerts.reading(dtr, w, raw_reading);
+        write_sinks(
+            &mut self.sinks,
+            std::slice::from_ref(record),
+            &self.amplifier.events,
+        );
+    }
+
+    fn report_clock_jumps(&mut self) {
+        report_clock_jumps(
+            self.schedule.take_jumps(),
+            &self.amplifier.events,
+            self.amplifier.session.as_ref(),
+        );
+    }
+}
+
+/// Print, write as events, and append to the session log the clock jumps.
+pub(crate) fn report_clock_jumps(
+    jumps: Vec<ClockJump>,
+    events: &EventLog,
+    session: Option<&SessionLog>,
+) {
+    for jump in jumps.iter() {
+        println!("warning, {}", jump);
+        events.warning(
+            "clock_jump",
+            &[
+                ("datetime", jump.at.into()),
+                (
+                    "jump_s",
+                    (jump.jump.num_milliseconds() as f64 / 1000.).into(),
+                ),
+                ("policy", jump.policy.to_string().into()),
+                ("next", jump.next.into()),
+            ],
+        );
+        if let Some(session) = session {
+            session.clock_jump(jump);
+        }
+    }
+}
+
+/// Print and write as event that the clock is behind the last logged time at start.
+pub(crate) fn print_clock_behind(events: &EventLog, last: DateTime<Local>, next: DateTime<Local>) {
+    println!(
+        "warning, the clock is behind the last reading at {}, waiting until {}",
+        last.to_rfc3339_opts(SecondsFormat::Secs, false),
+        next.to_rfc3339_opts(SecondsFormat::Secs, false)
+    );
+    events.warning(
+        "clock_behind",
+        &[("last", last.into()), ("next", next.into())],
+    );
+}
+
+/// Write the records of the same scheduled time to each sink, printing the errors.
+pub(crate) fn write_sinks(sinks: &mut [Box<dyn Sink>], records: &[Record], events: &EventLog) {
+    let loads: Vec<String> = records.iter().map(|r| r.load.to_string()).collect();
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.write_all(records) {
+            let dtr_str = records[0]
+                .scheduled
+                .to_rfc3339_opts(SecondsFormat::Secs, false);
+            println!(
+                "{}, could not write load {} to {}, error {}",
+                dtr_str,
+                loads.join(", "),
+                sink.name(),
+                e
+            );
+            write_failed(events, sink.name(), &dtr_str, &e);
+        }
+    }
+}
+
+pub(crate) fn sync_sinks(sinks: &mut [Box<dyn Sink>], events: &EventLog) {
+    for sink in sinks.iter_mut() {
+        match sink.sync() {
+            Ok(_) => println!("flushed {}", sink.name()),
+            Err(e) => {
+                println!("could not flush {}, error {}", sink.name(), e);
+                flush_failed(events, sink.name(), &e);
+            }
+        }
+    }
+}
+
+pub(crate) fn flush_failed(events: &EventLog, csv_name: &str, e: &std::io::Error) {
+    events.error(
+        "flush_failed",
+        &[("file", csv_name.into()), ("error", e.to_string().into())],
+    );
+}
+
+pub(crate) fn write_failed(events: &EventLog, csv_name: &str, dtr_str: &str, e: &std::io::Error) {
+    events.error(
+        "write_failed",
+        &[
+            ("file", csv_name.into()),
+            ("datetime", dtr_str.into()),
+            ("error", e.to_string().into()),
+        ],
+    );
+}
+
+/// Print one line and write one event for the skipped scheduled times, instead of one each.
+pub(crate) fn print_skipped(events: &EventLog, skipped: &[DateTime<Local>], reason: &str) {
+    if let (Some(first), Some(last)) = (skipped.first(), skipped.last()) {
+        events.warning(
+            "skipped",
+            &[
+                ("first", (*first).into()),
+                ("last", (*last).into()),
+                ("count", skipped.len().into()),
+                ("reason", reason.into()),
+            ],
+        );
+    }
+    match skipped {
+        [] => {}
+        [dtr] => println!(
+            "skipped reading at {}, {}",
+            dtr.to_rfc3339_opts(SecondsFormat::Secs, false),
+            reason
+        ),
+        [first, .., last] => println!(
+            "skipped {} readings from {} to {}, {}",
+            skipped.len(),
+            first.to_rfc3339_opts(SecondsFormat::Secs, false),
+            last.to_rfc3339_opts(SecondsFormat::Secs, false),
+            reason
+        ),
+    }
+}
+
+/// Header of the csv file of one device, with the statistics columns in burst mode
+/// and the acquisition columns if the acquisition times are written.
+pub fn csv_header(burst: bool, acquisition_times: bool) -> String {
+    let mut header = String::from(CSV_HEADER.trim_end());
+    if burst {
+        header.push_str(&format!(",{}", STATS_HEADER));
+    }
+    if acquisition_times {
+        header.push_str(&format!(",{}", ACQUISITION_HEADER));
+    }
+    header.push('\n');
+    header
+}
+
+pub(crate) fn with_suffix(csv_name: &str, suffix: &str, extension: Option<&str>) -> String {
+    let path = Path::new(csv_name);
+    let stem = path
+        .file_stem()
+        .and_then(|s| s.to_str())
+        .unwrap_or("loadcells");
+    let name = match extension {
+        Some(ext) => format!("{}_{}.{}", stem, suffix, ext),
+        None => format!("{}_{}", stem, suffix),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::session::read_sessions;
+    use crate::sink::CsvSink;
+    use std::collections::VecDeque;
+    use std::io::{Error, ErrorKind, Read, Result};
+
+    /// Clock that only advances when sleeping.
+    #[derive(Clone)]
+    pub(crate) struct FakeClock(pub(crate) DateTime<Local>);
+
+    impl Clock for FakeClock {
+        fn now(&self) -> DateTime<Local> {
+            self.0
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.0 += chrono::Duration::from_std(duration).unwrap();
+        }
+    }
+
+    /// Transport that answers each command with the next scripted reply,
+    /// an empty queue is a timeout.
+    #[derive(Default)]
+    pub(crate) struct FakeTransport {
+        replies: VecDeque<Result<Vec<u8>>>,
+        pending: Option<Result<Vec<u8>>>,
+        reconnections: usize,
+        failing_reconnections: usize,
+    }
+
+    impl FakeTransport {
+        pub(crate) fn with_replies(replies: Vec<Result<Vec<u8>>>) -> FakeTransport {
+            FakeTransport {
+                replies: replies.into_iter().collect(),
+                ..Default::default()
+            }
+        }
+    }
+
+    impl Transport for FakeTransport {
+        fn reconnect(&mut self) -> Result<()> {
+            if self.failing_reconnections > 0 {
+                self.failing_reconnections -= 1;
+                return Err(Error::new(ErrorKind::ConnectionRefused, "refused"));
+            }
+            self.reconnections += 1;
+            Ok(())
+        }
+    }
+
+    impl Read for FakeTransport {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            match self.pending.take() {
+                Some(Ok(r)) => {
+                    buf[..r.len()].copy_from_slice(&r);
+                    Ok(r.len())
+                }
+                Some(Err(e)) => Err(e),
+                None => Err(Error::new(ErrorKind::WouldBlock, "timeout")),
+            }
+        }
+    }
+
+    impl Write for FakeTransport {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.pending = self.replies.pop_front();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Csv file in memory, shared with its sink to read the rows back.
+    #[derive(Clone, Default)]
+    pub(crate) struct SharedCsv(Arc<Mutex<Vec<u8>>>);
+
+    impl SharedCsv {
+        /// Sink writing to the csv file.
+        pub(crate) fn sink(&self, acquisition_times: bool) -> Box<dyn Sink> {
+            let mut sink = CsvSink::new(self.clone(), "fake.csv");
+            sink.acquisition_times = acquisition_times;
+            Box::new(sink)
+        }
+
+        pub(crate) fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect()
+        }
+
+        /// Rows without the datetime column.
+        pub(crate) fn rows(&self) -> Vec<String> {
+            self.lines()
+                .iter()
+                .map(|l| l.split(',').skip(1).collect::<Vec<&str>>().join(","))
+                .collect()
+        }
+    }
+
+    impl Write for SharedCsv {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SyncWrite for SharedCsv {
+        fn sync(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Logger starting at 10:00:30 with a 1 minute interval, writing to the csv file.
+    fn fake_logger(replies: Vec<Result<Vec<u8>>>) -> (Logger<FakeTransport, FakeClock>, SharedCsv) {
+        let start = Local.with_ymd_and_hms(2021, 6, 1, 10, 0, 30).unwrap();
+        let mut logger = Logger::new(
+            FakeTransport::with_replies(replies),
+            FakeClock(start),
+            Command::Net,
+            chrono::Duration::minutes(1),
+        );
+        // the fake replies are the readings only
+        logger.amplifier.config_check = ConfigCheck::Off;
+        let csv = SharedCsv::default();
+        logger.sinks.push(csv.sink(false));
+        (logger, csv)
+    }
+
+    /// Sink keeping the records written.
+    struct FakeSink(Arc<Mutex<Vec<Record>>>);
+
+    impl Sink for FakeSink {
+        fn name(&self) -> &str {
+            "fake"
+        }
+
+        fn write(&mut self, record: &Record) -> Result<()> {
+            self.0.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+
+        fn sync(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn logs_readings_and_error_codes() {
+        let (mut logger, csv) = fake_logger(vec![
+            Ok(b"N +01234.5\r\n".to_vec()),
+            Ok(b"ERR\r\n".to_vec()),
+            Ok(vec![]),
+            Err(Error::new(ErrorKind::TimedOut, "timeout")),
+        ]);
+        logger.start();
+        assert_eq!(logger.next().second(), 0);
+        for _ in 0..4 {
+            logger.step();
+        }
+        assert_eq!(
+            csv.rows(),
+            vec![
+                "1234.5,N +01234.5",
+                "999995,ERR",
+                "999998,E+999998.",
+                "999999,E+999999.",
+            ]
+        );
+        assert_eq!(logger.amplifier.transport.reconnections, 2);
+    }
+
+    #[test]
+    fn discards_stale_frames() {
+        let (mut logger, csv) = fake_logger(vec![
+            Ok(b"G +01000.0\r\nN +01234.5\r\nN +01".to_vec()),
+            Ok(b"N +1234.5\r\n".to_vec()),
+        ]);
+        logger.start();
+        logger.step();
+        logger.step();
+        // the partial frame left from the first reply is drained before the second command
+        assert_eq!(csv.rows(), vec!["1234.5,N +01234.5", "999995,N +1234.5"]);
+        assert_eq!(logger.amplifier.transport.reconnections, 0);
+    }
+
+    #[test]
+    fn writes_acquisition_times() {
+        let (mut logger, csv) = fake_logger(vec![Ok(b"N +01234.5\r\n".to_vec()), Ok(vec![])]);
+        logger.sinks = vec![csv.sink(true)];
+        logger.start();
+        let first = logger.next();
+        logger.step();
+        logger.step();
+        let time = |s: i64| {
+            (first + chrono::Duration::seconds(s)).to_rfc3339_opts(SecondsFormat::Millis, false)
+        };
+        // the reply is read after the 2 seconds pause, and never arrives at the second time
+        assert_eq!(
+            csv.rows(),
+            vec![
+                format!("1234.5,N +01234.5,{},{},2.000", time(0), time(2)),
+                format!("999998,E+999998.,{},,", time(60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_passed_times_while_reconnecting() {
+        let (mut logger, csv) = fake_logger(vec![Ok(vec![]), Ok(b"N +01234.5\r\n".to_vec())]);
+        logger.amplifier.transport.failing_reconnections = 1;
+        logger.amplifier.connection_retry = Duration::from_secs(150);
+        logger.amplifier.jitter = 0.;
+        let events_name = std::env::temp_dir().join("flintec_lpp_reconnecting_events.jsonl");
+        let events_name = events_name.to_str().unwrap();
+        let _ = std::fs::remove_file(events_name);
+        logger.amplifier.events = EventLog::open(events_name).unwrap().for_device("dad");
+        logger.start();
+        let first = logger.next();
+        logger.step();
+        logger.step();
+        // the reconnection pause covers the following two scheduled times
+        assert_eq!(
+            csv.rows(),
+            vec![
+                "999998,E+999998.",
+                "999996,E+999996.",
+                "999996,E+999996.",
+                "1234.5,N +01234.5"
+            ]
+        );
+        assert_eq!(logger.next(), first + chrono::Duration::minutes(4));
+        let events = std::fs::read_to_string(events_name).unwrap();
+        std::fs::remove_file(events_name).unwrap();
+        let kinds: Vec<&str> = events
+            .lines()
+            .map(|l| {
+                l.split("\"event\":\"")
+                    .nth(1)
+                    .unwrap()
+                    .split('"')
+                    .next()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "connection_lost",
+                "reconnect_failed",
+                "connection_restored",
+                "skipped"
+            ]
+        );
+        assert!(events.contains("\"slots_lost\":3"));
+        assert!(events.contains("\"count\":2"));
+    }
+
+    #[test]
+    fn backs_off_and_gives_up_after_max_outage() {
+        let (mut logger, _) = fake_logger(vec![]);
+        let amplifier = &mut logger.amplifier;
+        amplifier.transport.failing_reconnections = 10;
+        amplifier.connection_retry = Duration::from_secs(30);
+        amplifier.max_retry = Duration::from_secs(60);
+        amplifier.max_outage = Some(Duration::from_secs(200));
+        amplifier.jitter = 0.;
+        let delays: Vec<u64> = (0..3).map(|a| amplifier.retry_delay(a).as_secs()).collect();
+        assert_eq!(delays, vec![30, 60, 60]);
+        amplifier.connection_ok = false;
+        let dtr = logger.clock.now();
+        let outage = amplifier
+            .recover_connection(&mut logger.clock, dtr, chrono::Duration::minutes(1))
+            .unwrap();
+        // attempts after 0, 30, 90, and 150 seconds, then gives up after 210 seconds
+        assert_eq!(outage.end, dtr + chrono::Duration::seconds(210));
+        assert_eq!(outage.slots_lost, 4);
+        assert!(!outage.restored);
+        assert!(amplifier.stop.is_failed());
+    }
+
+    #[test]
+    fn logs_burst_statistics() {
+        let (mut logger, csv) = fake_logger(vec![
+            Ok(b"N +01234.0\r\n".to_vec()),
+            Ok(b"ERR\r\n".to_vec()),
+            Ok(b"N +01236.0\r\n".to_vec()),
+        ]);
+        logger.amplifier.burst = Some(Burst {
+            count: 3,
+            window: Duration::from_secs(30),
+        });
+        logger.schedule.lead = chrono::Duration::seconds(15);
+        logger.start();
+        let first = logger.next();
+        logger.step();
+        assert_eq!(
+            csv.rows(),
+            vec!["1235,N +01234.0,1235.000,1.414,1234,1236,2"]
+        );
+        assert_eq!(logger.next(), first + chrono::Duration::minutes(1));
+    }
+
+    #[test]
+    fn writes_skipped_rows_with_all_the_columns() {
+        let (mut logger, csv) =
+            fake_logger((0..3).map(|_| Ok(b"N +01234.0\r\n".to_vec())).collect());
+        logger.amplifier.burst = Some(Burst {
+            count: 3,
+            window: Duration::from_secs(30),
+        });
+        logger.schedule.lead = chrono::Duration::seconds(15);
+        logger.sinks = vec![csv.sink(true)];
+        logger.resume_after = Some(Local.with_ymd_and_hms(2021, 6, 1, 9, 58, 0).unwrap());
+        logger.start();
+        logger.step();
+        let columns = csv_header(true, true).split(',').count();
+        let rows = csv.rows();
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].starts_with("999996,E+999996.,nan,"));
+        for row in rows.iter() {
+            // the datetime column is left out by rows
+            assert_eq!(row.split(',').count() + 1, columns, "{}", row);
+        }
+    }
+
+    #[test]
+    fn stops_between_readings() {
+        let (mut logger, csv) = fake_logger(vec![Ok(b"N +01234.5\r\n".to_vec())]);
+        let stop = logger.stop_flag();
+        logger.start();
+        logger.step();
+        stop.stop("SIGTERM");
+        stop.stop("SIGINT");
+        logger.step();
+        assert_eq!(csv.rows(), vec!["1234.5,N +01234.5"]);
+        assert_eq!(
+            logger.last_logged(),
+            Some(logger.next() - logger.schedule.interval)
+        );
+        assert_eq!(stop.reason(), Some(String::from("SIGTERM")));
+    }
+
+    #[test]
+    fn resumes_with_skipped_times() {
+        let path = std::env::temp_dir().join("flintec_lpp_resume.csv");
+        let header = CSV_HEADER;
+        let mut csv = String::from(header);
+        for m in [57, 58].iter() {
+            let dtr = Local.with_ymd_and_hms(2021, 6, 1, 9, *m, 0).unwrap();
+            let dtr_str = dtr.to_rfc3339_opts(SecondsFormat::Secs, false);
+            csv.push_str(&format!("{},1234.5,N +01234.5\n", dtr_str));
+        }
+        std::fs::write(&path, csv).unwrap();
+        let minute = chrono::Duration::minutes(1);
+        let midnight = Anchor::default();
+        let last = check_csvfile(&path, header, minute, midnight);
+        let other_interval = check_csvfile(&path, header, chrono::Duration::minutes(10), midnight);
+        let other_header = check_csvfile(
+            &path,
+            "datetime,a_load_kg,a_raw_reading\n",
+            minute,
+            midnight,
+        );
+        let other_anchor = check_csvfile(&path, header, minute, "00:00:30".parse().unwrap());
+
+        // the most common interval is checked, not the gap of a clock jump before the last row
+        let jumped = Local.with_ymd_and_hms(2021, 6, 1, 10, 7, 0).unwrap();
+        let mut csv = std::fs::read_to_string(&path).unwrap();
+        csv.push_str(&format!(
+            "{},1234.5,N +01234.5\n",
+            jumped.to_rfc3339_opts(SecondsFormat::Secs, false)
+        ));
+        std::fs::write(&path, csv).unwrap();
+        let after_jump = check_csvfile(&path, header, minute, midnight);
+        std::fs::remove_file(&path).unwrap();
+        let last = last.unwrap().unwrap();
+        assert_eq!(last, Local.with_ymd_and_hms(2021, 6, 1, 9, 58, 0).unwrap());
+        assert!(other_interval.is_err());
+        assert!(other_header.is_err());
+        assert!(other_anchor.is_err());
+        assert_eq!(after_jump, Ok(Some(jumped)));
+
+        // the logger restarts at 10:00:30, the first reading is at 10:01
+        let (mut logger, csv) = fake_logger(vec![Ok(b"N +01234.5\r\n".to_vec())]);
+        let records = Arc::new(Mutex::new(Vec::new()));
+        logger.sinks.push(Box::new(FakeSink(records.clone())));
+        logger.resume_after = Some(last);
+        logger.start();
+        logger.step();
+        assert_eq!(
+            csv.rows(),
+            vec!["999996,E+999996.", "999996,E+999996.", "1234.5,N +01234.5"]
+        );
+        let loads: Vec<f64> = records.lock().unwrap().iter().map(|r| r.load).collect();
+        assert_eq!(loads, vec![ERROR_FLT_SKIPPED, ERROR_FLT_SKIPPED, 1234.5]);
+    }
+
+    #[test]
+    fn reports_config_drift() {
+        let path = std::env::temp_dir().join("flintec_lpp_config_sessions.log");
+        let path = path.to_str().unwrap();
+        let _ = std::fs::remove_file(path);
+        let config = |identity: &str, firmware: &str| -> Vec<Result<Vec<u8>>> {
+            [identity, firmware]
+                .iter()
+                .map(|r| Ok(format!("{}\r\n", r).into_bytes()))
+                .collect()
+        };
+        let mut replies = vec![Ok(b"N +01234.5\r\n".to_vec())];
+        replies.extend(config("DAD141.1", "1.2"));
+        replies.push(Ok(b"N +01200.0\r\n".to_vec()));
+        replies.extend(config("ERR", "1.3"));
+        let (mut logger, csv) = fake_logger(replies);
+        logger.amplifier.config_check = ConfigCheck::Every(chrono::Duration::minutes(1));
+        logger.amplifier.session = Some(SessionLog::start(path, "test"));
+        logger.device = String::from("dad");
+        logger.start();
+        logger.step();
+        logger.step();
+        let sessions = read_sessions(path);
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(csv.rows(), vec!["1234.5,N +01234.5", "1200,N +01200.0"]);
+        let (_, config) = logger.amplifier.config.as_ref().unwrap();
+        assert_eq!(config.values[0], (Setting::Identity, None));
+        assert_eq!(
+            sessions[0].config_drifts,
+            vec!["dad firmware changed from 1.2 to 1.3"]
+        );
+    }
+
+    #[test]
+    fn stops_config_check_before_the_next_reading() {
+        let (mut logger, _) =
+            fake_logger(vec![Ok(b"DAD141.1\r\n".to_vec()), Ok(b"1.2\r\n".to_vec())]);
+        let amplifier = &mut logger.amplifier;
+        amplifier.config_check = ConfigCheck::Start;
+        let now = logger.clock.now();
+        amplifier.check_config(&mut logger.clock, "dad", now + chrono::Duration::seconds(5));
+        let (_, config) = amplifier.config.as_ref().unwrap();
+        assert!(config.is_empty());
+        assert_eq!(amplifier.transport.replies.len(), 2);
+
+        // the queries after the first one without reply are not sent
+        let (mut logger, _) = fake_logger(Vec::new());
+        let amplifier = &mut logger.amplifier;
+        amplifier.config_check = ConfigCheck::Start;
+        let now = logger.clock.now();
+        amplifier.check_config(&mut logger.clock, "dad", now + chrono::Duration::minutes(1));
+        assert!(amplifier.connection_ok());
+        assert_eq!(logger.clock.now() - now, chrono::Duration::seconds(2));
+    }
+
+    #[test]
+    fn repairs_a_truncated_last_line() {
+        let path = std::env::temp_dir().join("flintec_lpp_truncated.csv");
+        let name = path.to_str().unwrap();
+        let quarantine = truncated_name(name);
+        let _ = std::fs::remove_file(&quarantine);
+        let row = "2021-06-01T09:58:00+02:00,1234.5,N +01234.5\n";
+        std::fs::write(
+            name,
+            format!("{}{}2021-06-01T09:59:00+02:00,12", CSV_HEADER, row),
+        )
         .unwrap();
-    return file;
-}
-
-pub fn chrono_first_rounded(
-    datetime: DateTime<Local>,
-    rounding: chrono::Duration,
-) -> DateTime<Local> {
-    let offset: i64 = datetime.offset().local_minus_utc().into();
-    let local_sec = datetime.timestamp() + offset;
-    let rounding_sec = rounding.num_seconds();
-    let first_sec = rounding_sec * ((local_sec + rounding_sec) / rounding_sec) - offset;
-    let first_local = Local.timestamp(first_sec, 0);
-    first_local
+        let minute = chrono::Duration::minutes(1);
+        let (mut file, last) = open_csvfile(name, CSV_HEADER, minute, Anchor::default()).unwrap();
+        write_line(&mut file, "2021-06-01T10:00:00+02:00,1234.5,N +01234.5").unwrap();
+        let repaired = std::fs::read_to_string(name).unwrap();
+        let cut = std::fs::read_to_string(&quarantine).unwrap();
+        let timeload = crate::TimeLoad::from_csv(name);
+        std::fs::remove_file(name).unwrap();
+        std::fs::remove_file(&quarantine).unwrap();
+        let kept = DateTime::parse_from_rfc3339("2021-06-01T09:58:00+02:00").unwrap();
+        assert_eq!(last.unwrap(), kept);
+        assert_eq!(repaired.lines().count(), 3);
+        assert!(repaired.ends_with("10:00:00+02:00,1234.5,N +01234.5\n"));
+        assert_eq!(cut, "2021-06-01T09:59:00+02:00,12\n");
+        assert_eq!(timeload.load, vec![1234.5, 1234.5]);
+    }
 }
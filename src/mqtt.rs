@@ -0,0 +1,446 @@
+//! Publication of the readings to an MQTT broker.
+//!
+//! The readings are queued and published by a background thread with a minimal MQTT 3.1.1 client,
+//! QoS 0 or 1, so that the logger never waits for the broker.
+//! The queue keeps the readings while the broker is not reachable and drops the oldest when full.
+//!
+//! The client is written here instead of using rumqttc, whose blocking client still runs
+//! a tokio event loop on its own thread: the logger is synchronous, runs on small boards,
+//! and only publishes, which needs the CONNECT, PUBLISH, PUBACK, PINGREQ, and DISCONNECT
+//! packets, not the subscriptions, QoS 2, TLS, or persistence of a full client.
+use super::events::json_string;
+use super::sink::{Record, Sink};
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Connection and publication settings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MqttSettings {
+    /// Broker address, host:port.
+    pub broker: String,
+    /// Topic of the readings, where {device} is replaced by the device name.
+    pub topic: String,
+    /// Quality of service, 0 (at most once) or 1 (at least once).
+    pub qos: u8,
+    /// Retain the last reading of each topic, for the new subscribers.
+    pub retain: bool,
+    pub client_id: String,
+    /// Readings kept while the broker is not reachable.
+    pub queue_len: usize,
+    pub keep_alive: Duration,
+    /// Timeout of the connection, and of the acknowledgements with QoS 1.
+    pub timeout: Duration,
+    /// First pause between the attempts to connect, doubled after each failed attempt up to 1 minute.
+    pub retry: Duration,
+}
+
+impl MqttSettings {
+    pub fn new(broker: &str) -> MqttSettings {
+        MqttSettings {
+            broker: String::from(broker),
+            topic: String::from("flintec/{device}"),
+            qos: 1,
+            retain: true,
+            client_id: format!("flintec_log_{}", std::process::id()),
+            queue_len: 10000,
+            keep_alive: Duration::from_secs(60),
+            timeout: Duration::from_secs(10),
+            retry: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Reading to publish.
+#[derive(Debug, Clone, PartialEq)]
+struct Message {
+    topic: String,
+    payload: String,
+}
+
+/// JSON payload of the reading, the load is null for error codes.
+pub fn payload(record: &Record) -> String {
+    let datetime =
+        |d: DateTime<Local>| json_string(&d.to_rfc3339_opts(SecondsFormat::Millis, false));
+    let error_code = record.error_code();
+    let acquisition = &record.acquisition;
+    format!(
+        "{{\"device\":{},\"datetime\":{},\"sent_time\":{},\"read_time\":{},\"latency_s\":{},\
+        \"load_kg\":{},\"raw_reading\":{},\"error_code\":{}}}",
+        json_string(&record.device),
+        json_string(&record.scheduled.to_rfc3339_opts(SecondsFormat::Secs, false)),
+        acquisition.sent.map_or(String::from("null"), datetime),
+        acquisition.received.map_or(String::from("null"), datetime),
+        acquisition.latency().map_or(String::from("null"), |l| {
+            format!("{:.3}", l.num_milliseconds() as f64 / 1000.)
+        }),
+        error_code.map_or(record.load.to_string(), |_| String::from("null")),
+        json_string(&record.raw_reading),
+        error_code.map_or(String::from("null"), |c| c.to_string())
+    )
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    messages: VecDeque<Message>,
+    dropped: u64,
+    closed: bool,
+}
+
+/// Sink that publishes each reading to the broker, without blocking the logger.
+pub struct MqttSink {
+    settings: MqttSettings,
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    name: String,
+}
+
+impl MqttSink {
+    /// Start the publisher thread, which connects to the broker when the first reading is queued.
+    pub fn start(settings: MqttSettings) -> MqttSink {
+        let queue = Arc::new((Mutex::new(Queue::default()), Condvar::new()));
+        let name = format!("mqtt://{}", settings.broker);
+        let publisher = Publisher {
+            settings: settings.clone(),
+            queue: queue.clone(),
+            stream: None,
+            packet_id: 0,
+        };
+        std::thread::spawn(move || publisher.run());
+        MqttSink {
+            settings,
+            queue,
+            name,
+        }
+    }
+
+    /// Readings waiting to be published.
+    pub fn queued(&self) -> usize {
+        self.queue.0.lock().unwrap().messages.len()
+    }
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Queue the reading, dropping the oldest one if the queue is full.
+    fn write(&mut self, record: &Record) -> Result<()> {
+        let message = Message {
+            topic: self.settings.topic.replace("{device}", &record.device),
+            payload: payload(record),
+        };
+        let (queue, published) = &*self.queue;
+        let mut queue = queue.lock().unwrap();
+        if queue.messages.len() >= self.settings.queue_len.max(1) {
+            queue.messages.pop_front();
+            queue.dropped += 1;
+            if queue.dropped.is_power_of_two() {
+                println!(
+                    "mqtt queue full, dropped {} reading(s) in total",
+                    queue.dropped
+                );
+            }
+        }
+        queue.messages.push_back(message);
+        published.notify_one();
+        Ok(())
+    }
+
+    /// Nothing to flush, the queue is published in the background.
+    fn sync(&mut self) -> Result<()> {
+        let queued = self.queued();
+        if queued > 0 {
+            println!("{} reading(s) not yet published to {}", queued, self.name);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MqttSink {
+    fn drop(&mut self) {
+        let (queue, published) = &*self.queue;
+        queue.lock().unwrap().closed = true;
+        published.notify_one();
+    }
+}
+
+/// Background thread that publishes the queued readings.
+struct Publisher {
+    settings: MqttSettings,
+    queue: Arc<(Mutex<Queue>, Condvar)>,
+    stream: Option<TcpStream>,
+    packet_id: u16,
+}
+
+impl Publisher {
+    fn run(mut self) {
+        let mut attempt = 0;
+        let mut last_sent = Instant::now();
+        loop {
+            let message = {
+                let (queue, published) = &*self.queue;
+                let mut queue = queue.lock().unwrap();
+                while queue.messages.is_empty() && !queue.closed {
+                    let wait = self.settings.keep_alive / 2;
+                    queue = published.wait_timeout(queue, wait).unwrap().0;
+                    if queue.messages.is_empty() && last_sent.elapsed() >= wait {
+                        break;
+                    }
+                }
+                if queue.closed && (queue.messages.is_empty() || self.stream.is_none()) {
+                    break;
+                }
+                queue.messages.front().cloned()
+            };
+            let sent = match (&message, self.stream.is_some()) {
+                (Some(m), _) => self.connect().and_then(|_| self.publish(m)),
+                (None, true) => self.ping(),
+                (None, false) => continue,
+            };
+            match sent {
+                Ok(_) => {
+                    attempt = 0;
+                    last_sent = Instant::now();
+                    if let Some(m) = message {
+                        let mut queue = self.queue.0.lock().unwrap();
+                        if queue.messages.front() == Some(&m) {
+                            queue.messages.pop_front();
+                        }
+                    }
+                }
+                Err(e) => {
+                    if self.stream.take().is_some() || attempt == 0 {
+                        println!(
+                            "could not publish to mqtt broker {}, error {}",
+                            self.settings.broker, e
+                        );
+                    }
+                    let delay = self.settings.retry * 2u32.saturating_pow(attempt);
+                    std::thread::sleep(delay.min(Duration::from_secs(60)));
+                    attempt = attempt.saturating_add(1);
+                }
+            }
+        }
+        if let Some(mut stream) = self.stream.take() {
+            let _ = stream.write_all(&[0xE0, 0x00]);
+        }
+    }
+
+    /// Connect to the broker, if not connected, and wait for the acknowledgement.
+    fn connect(&mut self) -> Result<()> {
+        if self.stream.is_some() {
+            return Ok(());
+        }
+        let address = self
+            .settings
+            .broker
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "broker address not found"))?;
+        let mut stream = TcpStream::connect_timeout(&address, self.settings.timeout)?;
+        stream.set_read_timeout(Some(self.settings.timeout))?;
+        stream.set_write_timeout(Some(self.settings.timeout))?;
+        stream.set_nodelay(true)?;
+        stream.write_all(&connect_packet(
+            &self.settings.client_id,
+            self.settings.keep_alive.as_secs().min(u16::MAX as u64) as u16,
+        ))?;
+        let (header, body) = read_packet(&mut stream)?;
+        if header >> 4 != 2 || body.len() != 2 || body[1] != 0 {
+            return Err(Error::new(
+                ErrorKind::ConnectionRefused,
+                format!(
+                    "broker refused the connection, return code {:?}",
+                    body.get(1)
+                ),
+            ));
+        }
+        println!("mqtt connected to {}", self.settings.broker);
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    /// Publish the message and, with QoS 1, wait for its acknowledgement.
+    fn publish(&mut self, message: &Message) -> Result<()> {
+        self.packet_id = self.packet_id.wrapping_add(1).max(1);
+        let packet = publish_packet(
+            &message.topic,
+            message.payload.as_bytes(),
+            self.settings.qos,
+            self.settings.retain,
+            self.packet_id,
+        );
+        let stream = self.stream.as_mut().unwrap();
+        stream.write_all(&packet)?;
+        if self.settings.qos == 0 {
+            return Ok(());
+        }
+        loop {
+            let (header, body) = read_packet(stream)?;
+            if header >> 4 == 4 && body[..] == self.packet_id.to_be_bytes() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn ping(&mut self) -> Result<()> {
+        let stream = self.stream.as_mut().unwrap();
+        stream.write_all(&[0xC0, 0x00])?;
+        loop {
+            let (header, _) = read_packet(stream)?;
+            if header >> 4 == 13 {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Encode the remaining length of the fixed header.
+fn remaining_length(mut len: usize, packet: &mut Vec<u8>) {
+    loop {
+        let mut byte = (len % 128) as u8;
+        len /= 128;
+        if len > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if len == 0 {
+            break;
+        }
+    }
+}
+
+fn string(s: &[u8], packet: &mut Vec<u8>) {
+    packet.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    packet.extend_from_slice(s);
+}
+
+/// CONNECT with a clean session and the client identifier.
+fn connect_packet(client_id: &str, keep_alive: u16) -> Vec<u8> {
+    let mut body = Vec::new();
+    string(b"MQTT", &mut body);
+    body.push(4); // protocol level 3.1.1
+    body.push(0x02); // clean session
+    body.extend_from_slice(&keep_alive.to_be_bytes());
+    string(client_id.as_bytes(), &mut body);
+    let mut packet = vec![0x10];
+    remaining_length(body.len(), &mut packet);
+    packet.extend(body);
+    packet
+}
+
+fn publish_packet(topic: &str, payload: &[u8], qos: u8, retain: bool, packet_id: u16) -> Vec<u8> {
+    let mut body = Vec::new();
+    string(topic.as_bytes(), &mut body);
+    if qos > 0 {
+        body.extend_from_slice(&packet_id.to_be_bytes());
+    }
+    body.extend_from_slice(payload);
+    let mut packet = vec![0x30 | (qos.min(1) << 1) | retain as u8];
+    remaining_length(body.len(), &mut packet);
+    packet.extend(body);
+    packet
+}
+
+/// Read the next packet, returning the first byte of the fixed header and the rest of the packet.
+fn read_packet<R: Read>(reader: &mut R) -> Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8];
+    reader.read_exact(&mut byte)?;
+    let header = byte[0];
+    let mut len = 0usize;
+    for shift in 0..4 {
+        reader.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7F) as usize) << (7 * shift);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok((header, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ERROR_FLT_NONE;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Close the connections while down, then accept one client and acknowledge
+    /// the connection and the publications, returning the topic and payload
+    /// of the expected number of publications.
+    fn fake_broker(
+        listener: TcpListener,
+        down: Arc<AtomicBool>,
+        count: usize,
+    ) -> Vec<(String, String)> {
+        let mut stream = loop {
+            let (stream, _) = listener.accept().unwrap();
+            if !down.load(Ordering::SeqCst) {
+                break stream;
+            }
+        };
+        let (header, _) = read_packet(&mut stream).unwrap();
+        assert_eq!(header, 0x10);
+        stream.write_all(&[0x20, 0x02, 0x00, 0x00]).unwrap();
+        let mut published = Vec::new();
+        while published.len() < count {
+            let (header, body) = read_packet(&mut stream).unwrap();
+            assert_eq!(header, 0x33, "publish with QoS 1 and retain");
+            let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+            let topic = String::from_utf8(body[2..2 + topic_len].to_vec()).unwrap();
+            let id = &body[2 + topic_len..4 + topic_len];
+            let payload = String::from_utf8(body[4 + topic_len..].to_vec()).unwrap();
+            stream.write_all(&[0x40, 0x02, id[0], id[1]]).unwrap();
+            published.push((topic, payload));
+        }
+        published
+    }
+
+    #[test]
+    fn queues_while_the_broker_is_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = listener.local_addr().unwrap().to_string();
+        let down = Arc::new(AtomicBool::new(true));
+        let fake = {
+            let down = down.clone();
+            std::thread::spawn(move || fake_broker(listener, down, 2))
+        };
+        let mut settings = MqttSettings::new(&broker);
+        settings.retry = Duration::from_millis(50);
+        let mut sink = MqttSink::start(settings);
+        let start = Local.with_ymd_and_hms(2021, 6, 1, 10, 0, 0).unwrap();
+        for (i, load) in [1234.5, ERROR_FLT_NONE].iter().enumerate() {
+            let record = Record {
+                device: String::from("dad"),
+                scheduled: (start + chrono::Duration::minutes(i as i64)).fixed_offset(),
+                acquisition: Default::default(),
+                load: *load,
+                raw_reading: String::from("N +01234.5"),
+                stats: String::new(),
+            };
+            sink.write(&record).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(sink.queued(), 2);
+        down.store(false, Ordering::SeqCst);
+        let published = fake.join().unwrap();
+        assert_eq!(published[0].0, "flintec/dad");
+        assert_eq!(
+            published[0].1,
+            format!(
+                "{{\"device\":\"dad\",\"datetime\":\"{}\",\"sent_time\":null,\"read_time\":null,\"latency_s\":null,\"load_kg\":1234.5,\
+                \"raw_reading\":\"N +01234.5\",\"error_code\":null}}",
+                start.to_rfc3339_opts(SecondsFormat::Secs, false)
+            )
+        );
+        assert!(published[1]
+            .1
+            .ends_with("\"load_kg\":null,\"raw_reading\":\"N +01234.5\",\"error_code\":999998}"));
+    }
+}
@@ -0,0 +1,597 @@
+//! Logging of several amplifiers on the same schedule, to a wide csv file
+//! or to one csv file per device.
+use super::alert::Alerts;
+use super::burst::{empty_stats_csv, STATS_HEADER};
+use super::clock::Clock;
+use super::events::EventLog;
+use super::log::{
+    print_clock_behind, print_skipped, report_clock_jumps, sync_sinks, with_suffix, write_sinks,
+    Amplifier, StopFlag,
+};
+use super::protocol::Command;
+use super::schedule::{Schedule, Zone};
+use super::session::SessionLog;
+use super::sink::{Acquisition, Record, Sink, ACQUISITION_HEADER};
+use super::status::Monitor;
+use super::transport::{Endpoint, SerialSettings, Transport};
+use super::{ERROR_FLT_SKIPPED, ERROR_STR_SKIPPED};
+use chrono::prelude::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+/// One of the amplifiers logged together by the MultiLogger.
+#[derive(Debug, Clone)]
+pub struct Device {
+    pub name: String,
+    pub endpoint: Endpoint,
+    pub command: Command,
+}
+
+/// Read the list of devices from a csv file with name, address, port, and command,
+/// e.g., `lysimeter1,192.168.0.100,23,GN`.
+/// For serial connections, the address is the tty device and the port is the baud rate,
+/// e.g., `lysimeter2,/dev/ttyUSB0,9600,GA`.
+/// Empty lines and lines starting with # are ignored.
+pub fn read_devices<P>(fin: P) -> Vec<Device>
+where
+    P: AsRef<Path>,
+{
+    let file = File::open(fin).unwrap();
+    let buf = BufReader::new(file);
+    let mut devices: Vec<Device> = Vec::new();
+    for l in buf.lines() {
+        let l_unwrap = l.unwrap();
+        let l_trim = l_unwrap.trim();
+        if l_trim.is_empty() || l_trim.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = l_trim.split(',').map(|f| f.trim()).collect();
+        assert!(
+            fields.len() == 4,
+            "expected name, address, port, and command in device line: {}",
+            l_trim
+        );
+        let port = fields[2]
+            .parse::<u32>()
+            .unwrap_or_else(|e| panic!("invalid port in device line: {}, {}", l_trim, e));
+        let endpoint = if fields[1].starts_with('/') {
+            Endpoint::Serial(SerialSettings::new(fields[1], port, "none", "1"))
+        } else {
+            let ip: Ipv4Addr = fields[1].parse().expect("not a valid ip address");
+            Endpoint::Tcp(SocketAddrV4::new(ip, port as u16))
+        };
+        let command = fields[3].parse::<Command>().unwrap();
+        assert!(
+            !devices.iter().any(|d| d.name == fields[0]),
+            "duplicated device name {}",
+            fields[0]
+        );
+        devices.push(Device {
+            name: String::from(fields[0]),
+            endpoint,
+            command,
+        });
+    }
+    assert!(!devices.is_empty(), "no devices found");
+    devices
+}
+
+/// Header of the wide csv file, with load and raw reading columns for each device,
+/// followed by its statistics columns in burst mode and its acquisition columns if written.
+pub fn wide_csv_header(names: &[String], burst: bool, acquisition_times: bool) -> String {
+    let mut header = String::from("datetime");
+    for n in names.iter() {
+        header.push_str(&format!(",{}_load_kg,{}_raw_reading", n, n));
+        if burst {
+            for column in STATS_HEADER.split(',') {
+                header.push_str(&format!(",{}_{}", n, column));
+            }
+        }
+        if acquisition_times {
+            for column in ACQUISITION_HEADER.split(',') {
+                header.push_str(&format!(",{}_{}", n, column));
+            }
+        }
+    }
+    header.push('\n');
+    header
+}
+
+/// Name of the csv file for one device, e.g., loadcells_lysimeter1.csv.
+pub fn device_csv_name(csv_name: &str, device: &str) -> String {
+    let path = Path::new(csv_name);
+    let extension = path.extension().and_then(|e| e.to_str());
+    with_suffix(csv_name, device, extension)
+}
+
+/// Raw reading, load, and statistics columns of one device.
+type Sample = (String, f64, String);
+/// Device index, scheduled time, sample, and times of the command and of the reply.
+type Reply = (usize, DateTime<Local>, Sample, Acquisition);
+
+/// Log several amplifiers on the same schedule.
+/// Each amplifier is read in its own thread with its own connection state,
+/// so that a dead unit does not stall the others: readings that do not arrive
+/// before the next scheduled time are written as skipped.
+/// The readings of all the devices are written together to the sinks,
+/// e.g., to one wide csv file or to one csv file per device.
+pub struct MultiLogger<C: Clock> {
+    pub clock: C,
+    pub schedule: Schedule,
+    pub verbose: bool,
+    /// Last datetime in the existing wide csv file, or in the csv file of each device,
+    /// to write the missed times as skipped at start.
+    pub resume_after: Vec<Option<DateTime<Local>>>,
+    /// Event log of the skipped readings and the write errors,
+    /// the amplifiers have their own for the connection and reading errors.
+    pub events: EventLog,
+    /// Status of all the devices, for the HTTP endpoint.
+    pub monitor: Monitor,
+    /// Alert rules of all the devices, checked with each reading.
+    pub alerts: Alerts,
+    /// Destinations of the readings of all the devices, e.g., the csv files.
+    pub sinks: Vec<Box<dyn Sink>>,
+    /// Session log where the clock jumps are written.
+    pub session: Option<SessionLog>,
+    /// Time zone of the scheduled times written.
+    pub zone: Zone,
+    stop: StopFlag,
+    last_logged: Option<DateTime<Local>>,
+    names: Vec<String>,
+    skipped_stats: String,
+    /// Scheduled time and offset of the schedule, see `Schedule::offset`.
+    requests: Vec<Sender<(DateTime<Local>, chrono::Duration)>>,
+    replies: Receiver<Reply>,
+}
+
+impl<C> MultiLogger<C>
+where
+    C: Clock + Clone + Send + 'static,
+{
+    /// Spawn one thread per amplifier, sleeping with a clone of the clock and sharing the stop flag.
+    pub fn new<T: Transport + Send + 'static>(
+        amplifiers: Vec<(String, Amplifier<T>)>,
+        clock: C,
+        interval: chrono::Duration,
+        stop: StopFlag,
+    ) -> MultiLogger<C> {
+        let skipped_stats = if amplifiers.iter().any(|(_, a)| a.burst.is_some()) {
+            empty_stats_csv()
+        } else {
+            String::new()
+        };
+        let (reply_tx, replies) = channel::<Reply>();
+        let mut names = Vec::with_capacity(amplifiers.len());
+        let mut requests = Vec::with_capacity(amplifiers.len());
+        for (i, (name, mut amplifier)) in amplifiers.into_iter().enumerate() {
+            let (request_tx, request_rx) = channel::<(DateTime<Local>, chrono::Duration)>();
+            let reply_tx = reply_tx.clone();
+            let mut worker_clock = clock.clone();
+            let worker_name = name.clone();
+            let worker_skipped_stats = skipped_stats.clone();
+            amplifier.stop = stop.clone();
+            std::thread::spawn(move || {
+                // the burst of the next reading starts this long before its scheduled time
+                let lead = amplifier.burst.map_or(chrono::Duration::zero(), |b| {
+                    chrono::Duration::from_std(b.lead()).unwrap()
+                });
+                for (dtr, offset) in request_rx {
+                    let dtr_str = dtr.to_rfc3339_opts(SecondsFormat::Secs, false);
+                    let wall = dtr + offset;
+                    let (sample, acquisition) = if worker_clock.now() >= wall + interval {
+                        println!(
+                            "{}, skipping reading at {} because it has already passed",
+                            worker_name, dtr_str
+                        );
+                        amplifier.events.warning(
+                            "skipped",
+                            &[
+                                ("first", dtr.into()),
+                                ("last", dtr.into()),
+                                ("count", 1usize.into()),
+                                ("reason", "already passed".into()),
+                            ],
+                        );
+                        let sample = (
+                            String::from(ERROR_STR_SKIPPED),
+                            ERROR_FLT_SKIPPED,
+                            worker_skipped_stats.clone(),
+                        );
+                        (sample, Acquisition::default())
+                    } else {
+                        let label = format!("{} {}", worker_name, dtr_str);
+                        let sample = amplifier.read_sample(&mut worker_clock, &label);
+                        (sample, amplifier.acquisition())
+                    };
+                    if reply_tx.send((i, dtr, sample, acquisition)).is_err() {
+                        break;
+                    }
+                    let deadline = wall + interval - lead;
+                    amplifier.check_config(&mut worker_clock, &worker_name, deadline);
+                    amplifier.recover_connection(&mut worker_clock, wall, interval);
+                }
+            });
+            names.push(name);
+            requests.push(request_tx);
+        }
+        let schedule = Schedule::new(interval, clock.now());
+        MultiLogger {
+            clock,
+            schedule,
+            verbose: false,
+            resume_after: Vec::new(),
+            events: EventLog::disabled(),
+            monitor: Monitor::disabled(),
+            alerts: Alerts::none(),
+            sinks: Vec::new(),
+            session: None,
+            zone: Zone::default(),
+            stop,
+            last_logged: None,
+            names,
+            skipped_stats,
+            requests,
+            replies,
+        }
+    }
+
+    /// Flag to stop logging after the current readings.
+    pub fn stop_flag(&self) -> StopFlag {
+        self.stop.clone()
+    }
+
+    /// Last scheduled time written to the sinks.
+    pub fn last_logged(&self) -> Option<DateTime<Local>> {
+        self.last_logged
+    }
+
+    /// Names of the devices, in the order of the columns.
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// Start and log until stopped, then flush the sinks to the disk.
+    pub fn run(&mut self) {
+        self.start();
+        let waited = self.schedule.wait_next(&mut self.clock, &self.stop);
+        self.report_clock_jumps();
+        if waited {
+            println!("OK, logging {} devices ...", self.names.len());
+        }
+        while !self.stop.is_stopped() {
+            self.step();
+        }
+        self.finish();
+    }
+
+    /// Schedule the first reading at the next rounded time,
+    /// writing the times missed since the last datetime of each existing csv file as skipped,
+    /// for all the devices of the wide csv file or for the device of its own csv file.
+    pub fn start(&mut self) {
+        self.schedule.start(self.clock.now());
+        if let Some(last) = self.resume_after.iter().flatten().max() {
+            if self.schedule.start_after(*last) {
+                print_clock_behind(&self.events, *last, self.schedule.next());
+            }
+        }
+        let skipped = vec![self.skipped(); self.names.len()];
+        let not_read = vec![Acquisition::default(); self.names.len()];
+        for i in 0..self.resume_after.len() {
+            if let Some(last) = self.resume_after[i] {
+                let missed = self.schedule.missed_since(last);
+                let devices = if self.resume_after.len() == self.names.len() {
+                    i..i + 1
+                } else {
+                    0..self.names.len()
+                };
+                for dtr in missed.iter() {
+                    let records = self.records(*dtr, &skipped, &not_read);
+                    self.record(devices.clone(), &records[devices.clone()]);
+                }
+                print_skipped(&self.events, &missed, "missed since the last reading");
+            }
+        }
+    }
+
+    /// Flush and sync the sinks.
+    pub fn finish(&mut self) {
+        sync_sinks(&mut self.sinks, &self.events);
+    }
+
+    /// Wait for the next scheduled time, request the reading to all the devices,
+    /// and write the readings that arrive before the following scheduled time.
+    /// Return without logging if stopped while waiting.
+    pub fn step(&mut self) {
+        let waited = self.schedule.wait_next(&mut self.clock, &self.stop);
+        self.report_clock_jumps();
+        if !waited {
+            return;
+        }
+        let dtr = self.schedule.advance();
+        let offset = self.schedule.offset();
+        for (request, name) in self.requests.iter().zip(self.names.iter()) {
+            if request.send((dtr, offset)).is_err() {
+                println!("warning, the thread of device {} is not running", name);
+            }
+        }
+        let mut readings: Vec<Option<Sample>> = vec![None; self.names.len()];
+        let mut acquisitions = vec![Acquisition::default(); self.names.len()];
+        while readings.iter().any(|r| r.is_none()) {
+            let deadline =
+                self.schedule.next() - self.schedule.lead - self.schedule.now(&self.clock);
+            match self
+                .replies
+                .recv_timeout(deadline.to_std().unwrap_or(Duration::from_secs(0)))
+            {
+                Ok((i, t, sample, acquisition)) if t == dtr => {
+                    readings[i] = Some(sample);
+                    acquisitions[i] = acquisition;
+                }
+                Ok(_) => {} // late reading, already written as skipped
+                Err(_) => break,
+            }
+        }
+        let readings: Vec<Sample> = readings
+            .into_iter()
+            .zip(self.names.iter())
+            .map(|(r, name)| {
+                r.unwrap_or_else(|| {
+                    println!("{}, no reading before the next scheduled time", name);
+                    self.events.warning(
+                        "no_reading",
+                        &[("device", name.as_str().into()), ("datetime", dtr.into())],
+                    );
+                    self.skipped()
+                })
+            })
+            .collect();
+        self.write_readings(dtr, &readings, &acquisitions);
+        let skipped = vec![self.skipped(); self.names.len()];
+        let not_read = vec![Acquisition::default(); self.names.len()];
+        self.schedule.check_clock(&self.clock);
+        self.report_clock_jumps();
+        // the replies were waited for until the next time, which has passed only once the one
+        // after it is due, as in the threads of the amplifiers
+        let now = self.schedule.now(&self.clock) - self.schedule.interval;
+        let mut passed = Vec::new();
+        while let Some(dtr) = self.schedule.advance_passed(now) {
+            self.write_readings(dtr, &skipped, &not_read);
+            passed.push(dtr);
+        }
+        print_skipped(&self.events, &passed, "already passed");
+    }
+
+    fn report_clock_jumps(&mut self) {
+        report_clock_jumps(
+            self.schedule.take_jumps(),
+            &self.events,
+            self.session.as_ref(),
+        );
+    }
+
+    fn skipped(&self) -> Sample {
+        (
+            String::from(ERROR_STR_SKIPPED),
+            ERROR_FLT_SKIPPED,
+            self.skipped_stats.clone(),
+        )
+    }
+
+    /// Records of the readings of all the devices at the scheduled time.
+    fn records(
+        &self,
+        dtr: DateTime<Local>,
+        readings: &[Sample],
+        acquisitions: &[Acquisition],
+    ) -> Vec<Record> {
+        readings
+            .iter()
+            .zip(acquisitions.iter())
+            .zip(self.names.iter())
+            .map(|(((raw_reading, w, stats), acquisition), name)| Record {
+                device: name.clone(),
+                scheduled: self.zone.fix(dtr),
+                acquisition: *acquisition,
+                load: *w,
+                raw_reading: raw_reading.clone(),
+                stats: stats.clone(),
+            })
+            .collect()
+    }
+
+    fn write_readings(
+        &mut self,
+        dtr: DateTime<Local>,
+        readings: &[Sample],
+        acquisitions: &[Acquisition],
+    ) {
+        let records = self.records(dtr, readings, acquisitions);
+        self.record(0..records.len(), &records);
+        if self.verbose {
+            let loads: Vec<String> = records
+                .iter()
+                .map(|r| format!("{} {}", r.device, r.load))
+                .collect();
+            println!(
+                "{}, logged load {}",
+                dtr.to_rfc3339_opts(SecondsFormat::Secs, false),
+                loads.join(", ")
+            );
+        }
+    }
+
+    /// Update the status, check the alert rules, and write the sinks
+    /// with the records of the devices in the range.
+    fn record(&mut self, devices: std::ops::Range<usize>, records: &[Record]) {
+        for (i, record) in devices.zip(records.iter()) {
+            let dtr = record.scheduled.with_timezone(&Local);
+            let (w, raw_reading) = (record.load, &record.raw_reading);
+            self.last_logged = Some(dtr);
+            self.monitor.for_device(i).reading(dtr, w, raw_reading);
+            self.alerts
+                .for_device(&self.names[i])
+                .reading(dtr, w, raw_reading);
+        }
+        write_sinks(&mut self.sinks, records, &self.events);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::burst::Burst;
+    use crate::clock::SystemClock;
+    use crate::config::ConfigCheck;
+    use crate::log::tests::{FakeClock, FakeTransport, SharedCsv};
+    use crate::log::Timing;
+    use std::io::{Error, ErrorKind, Read, Result, Write};
+
+    /// Transport of an amplifier that never answers in time.
+    struct Hung;
+
+    impl Read for Hung {
+        fn read(&mut self, _buf: &mut [u8]) -> Result<usize> {
+            std::thread::sleep(Duration::from_secs(1));
+            Err(Error::new(ErrorKind::TimedOut, "timeout"))
+        }
+    }
+
+    impl Write for Hung {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Transport for Hung {
+        fn reconnect(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn multi_logger_writes_wide_rows() {
+        let start = Local.with_ymd_and_hms(2021, 6, 1, 10, 0, 30).unwrap();
+        let good = FakeTransport::with_replies(vec![
+            Ok(b"N +01234.5\r\n".to_vec()),
+            Ok(b"N +01235.5\r\n".to_vec()),
+        ]);
+        let dead = FakeTransport::with_replies(vec![Ok(vec![]), Ok(b"N +00042.0\r\n".to_vec())]);
+        let amplifiers = vec![
+            (String::from("a"), Amplifier::new(good, Command::Net)),
+            (String::from("b"), Amplifier::new(dead, Command::Net)),
+        ]
+        .into_iter()
+        .map(|(name, mut amplifier)| {
+            // the fake replies are the readings only
+            amplifier.config_check = ConfigCheck::Off;
+            (name, amplifier)
+        })
+        .collect();
+        let mut logger = MultiLogger::new(
+            amplifiers,
+            FakeClock(start),
+            chrono::Duration::minutes(1),
+            StopFlag::new(),
+        );
+        let csv = SharedCsv::default();
+        logger.sinks.push(csv.sink(false));
+        logger.schedule.start(start);
+        logger.step();
+        logger.step();
+        assert_eq!(
+            csv.rows(),
+            vec![
+                "1234.5,N +01234.5,999998,E+999998.",
+                "1235.5,N +01235.5,42,N +00042.0"
+            ]
+        );
+        assert_eq!(
+            wide_csv_header(logger.names(), false, false),
+            "datetime,a_load_kg,a_raw_reading,b_load_kg,b_raw_reading\n"
+        );
+        assert_eq!(
+            device_csv_name("out/loadcells.csv", "a"),
+            "out/loadcells_a.csv"
+        );
+    }
+
+    #[test]
+    fn writes_skipped_rows_with_all_the_columns() {
+        let start = Local.with_ymd_and_hms(2021, 6, 1, 10, 0, 30).unwrap();
+        let bursting = |reply: &[u8]| {
+            let replies = (0..3).map(|_| Ok(reply.to_vec())).collect();
+            let mut amplifier = Amplifier::new(FakeTransport::with_replies(replies), Command::Net);
+            amplifier.burst = Some(Burst {
+                count: 3,
+                window: Duration::from_secs(30),
+            });
+            amplifier
+        };
+        let amplifiers = vec![
+            (String::from("a"), bursting(b"N +01234.0\r\n")),
+            (String::from("b"), bursting(b"N +00042.0\r\n")),
+        ];
+        let mut logger = MultiLogger::new(
+            amplifiers,
+            FakeClock(start),
+            chrono::Duration::minutes(1),
+            StopFlag::new(),
+        );
+        let csv = SharedCsv::default();
+        logger.sinks.push(csv.sink(true));
+        logger.schedule.lead = chrono::Duration::seconds(15);
+        logger.resume_after = vec![Some(Local.with_ymd_and_hms(2021, 6, 1, 9, 58, 0).unwrap())];
+        logger.start();
+        logger.step();
+        let columns = wide_csv_header(logger.names(), true, true)
+            .split(',')
+            .count();
+        let rows = csv.lines();
+        assert_eq!(rows.len(), 3);
+        assert!(rows[0].contains(",999996,E+999996.,nan,"));
+        for row in rows.iter() {
+            assert_eq!(row.split(',').count(), columns, "{}", row);
+        }
+    }
+
+    #[test]
+    fn waits_for_a_hung_device_without_skipping_the_next_time() {
+        let replies = (0..3).map(|_| Ok(b"N +01234.5\r\n".to_vec())).collect();
+        let transports: Vec<Box<dyn Transport + Send>> = vec![
+            Box::new(FakeTransport::with_replies(replies)),
+            Box::new(Hung),
+        ];
+        let timing = Timing {
+            write_read_pause: Duration::from_millis(1),
+            drain: Duration::from_millis(1),
+            ..Timing::default()
+        };
+        let amplifiers = transports
+            .into_iter()
+            .zip(["a", "b"].iter())
+            .map(|(t, name)| {
+                let mut amplifier = Amplifier::new(t, Command::Net);
+                amplifier.set_timing(&timing);
+                amplifier.config_check = ConfigCheck::Off;
+                (String::from(*name), amplifier)
+            })
+            .collect();
+        let interval = chrono::Duration::milliseconds(200);
+        let mut logger = MultiLogger::new(amplifiers, SystemClock, interval, StopFlag::new());
+        let csv = SharedCsv::default();
+        logger.sinks.push(csv.sink(false));
+        logger.schedule.start(SystemClock.now());
+        logger.step();
+        logger.step();
+        // the replies of b are waited for until the next time, which is still read for a
+        assert_eq!(csv.rows(), vec!["1234.5,N +01234.5,999996,E+999996."; 2]);
+    }
+}
@@ -1,11 +1,12 @@
-use super::VERSION;
+use super::process::{parse_selection, selection_args};
+use super::{Selection, VERSION};
 use clap::{App, Arg};
 use std::path::PathBuf;
 
 /// Takes the CLI arguments that control the plotting of the load time series.
-pub fn parse_cli() -> (PathBuf, PathBuf) {
+pub fn parse_cli() -> (PathBuf, PathBuf, Selection) {
     let arg_csvin = Arg::with_name("input_csvfile")
-        .help("name for the csv file")
+        .help("name for the csv file or SQLite database")
         .short("f")
         .long("csvfile")
         .takes_value(true)
@@ -22,6 +23,7 @@ pub fn parse_cli() -> (PathBuf, PathBuf) {
         .about("cli app to plot the load time series")
         .arg(arg_csvin)
         .arg(arg_svgout)
+        .args(&selection_args())
         .get_matches();
     let csvin = PathBuf::from(cli_args.value_of("input_csvfile").unwrap_or_default());
     let svgout = match cli_args.value_of("output_svgfile") {
@@ -32,5 +34,5 @@ pub fn parse_cli() -> (PathBuf, PathBuf) {
             svgout
         }
     };
-    return (csvin, svgout);
+    (csvin, svgout, parse_selection(&cli_args))
 }
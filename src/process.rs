@@ -1,9 +1,10 @@
-use super::VERSION;
+use super::{is_database, Selection, VERSION};
 use chrono::prelude::*;
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches};
 use std::path::PathBuf;
 
 /// Takes the CLI arguments to set the processing parameters.
+#[allow(clippy::type_complexity)]
 pub fn parse_cli() -> (
     PathBuf,
     PathBuf,
@@ -17,9 +18,10 @@ pub fn parse_cli() -> (
     Option<PathBuf>,
     Option<(NaiveTime, NaiveTime)>,
     i32,
+    Selection,
 ) {
     let arg_in_raw_data = Arg::with_name("in_raw_data")
-        .help("name for the input csv file with the data to process")
+        .help("name for the input csv file or SQLite database with the data to process")
         .short("f")
         .long("inrawdata")
         .takes_value(true)
@@ -98,11 +100,16 @@ pub fn parse_cli() -> (
         .arg(arg_bad_datetimes)
         .arg(arg_bad_time_interval)
         .arg(ard_timezone)
+        .args(&selection_args())
         .get_matches();
 
     let csvin = PathBuf::from(cli_args.value_of("in_raw_data").unwrap());
     let csvout = match cli_args.value_of("out_proc_data") {
         Some(p) => PathBuf::from(p),
+        None if is_database(&csvin) => {
+            let stem = csvin.file_stem().unwrap().to_str().unwrap();
+            csvin.with_file_name(format!("{}_processed.csv", stem))
+        }
         None => PathBuf::from(csvin.to_str().unwrap().replace(".csv", "_processed.csv")),
     };
     let side = cli_args
@@ -140,8 +147,7 @@ pub fn parse_cli() -> (
         .unwrap_or_default()
         .parse::<f64>()
         .unwrap();
-    let bad_datetimes: Option<PathBuf> =
-        cli_args.value_of("bad_datetimes").map(|f| PathBuf::from(f));
+    let bad_datetimes: Option<PathBuf> = cli_args.value_of("bad_datetimes").map(PathBuf::from);
     let bad_time_interval: Option<(NaiveTime, NaiveTime)> =
         match cli_args.values_of("bad_time_interval") {
             Some(mut ti) => {
@@ -157,7 +163,7 @@ pub fn parse_cli() -> (
         .parse::<i32>()
         .unwrap();
 
-    return (
+    (
         csvin,
         csvout,
         side,
@@ -170,5 +176,40 @@ pub fn parse_cli() -> (
         bad_datetimes,
         bad_time_interval,
         timezone,
-    );
+        parse_selection(&cli_args),
+    )
+}
+
+/// Arguments to select the device and time range of the readings.
+pub fn selection_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("device")
+            .help("device to read from the SQLite database")
+            .long("device")
+            .takes_value(true)
+            .default_value("dad"),
+        Arg::with_name("from")
+            .help("first datetime to read, RFC 3339, e.g., 2021-06-01T00:00:00+02:00")
+            .long("from")
+            .takes_value(true),
+        Arg::with_name("to")
+            .help("last datetime to read, RFC 3339")
+            .long("to")
+            .takes_value(true),
+    ]
+}
+
+/// Device and time range of the readings from the arguments of `selection_args`.
+pub fn parse_selection(cli_args: &ArgMatches) -> Selection {
+    let datetime = |name: &str| {
+        cli_args.value_of(name).map(|d| {
+            DateTime::parse_from_rfc3339(d)
+                .unwrap_or_else(|e| panic!("invalid {} datetime {}, error {}", name, d, e))
+        })
+    };
+    Selection {
+        device: String::from(cli_args.value_of("device").unwrap_or_default()),
+        from: datetime("from"),
+        to: datetime("to"),
+    }
 }
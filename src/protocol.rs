@@ -0,0 +1,384 @@
+//! ASCII protocol of the Flintec DAD 141.1 digital amplifier.
+//!
+//! Commands are two letters followed by enter (e.g., `GN\n`).
+//! Weight replies use the 10-byte DAD frame:
+//! a 2-character descriptor (kind of value and status), the sign,
+//! and the 7-character value with the decimal separator in any position,
+//! e.g., `N +01234.5` or `GM-0012.34`.
+use std::fmt;
+use std::str::FromStr;
+
+/// Length of the standard DAD weight frame, without terminator.
+pub const FRAME_LEN: usize = 10;
+
+/// Error frame sent by the amplifier when it cannot measure or refuses the command.
+pub const ERROR_FRAME: &str = "ERR";
+
+/// Commands of the DAD 141.1 ASCII protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// GN, get the net load.
+    Net,
+    /// GG, get the gross load.
+    Gross,
+    /// GA, get the average of 128 net readings over 1 second.
+    Average,
+    /// GT, get the stored tare.
+    GetTare,
+    /// GS, get the status of the amplifier.
+    Status,
+    /// ST, set the tare to the current gross load.
+    Tare,
+    /// CT, clear the tare.
+    ClearTare,
+    /// SZ, set the zero to the current gross load.
+    Zero,
+    /// ID, get the identity of the amplifier.
+    Identify,
+    /// IV, get the firmware version.
+    Version,
+}
+
+impl Command {
+    /// All the known commands, in the order of the manual.
+    pub const ALL: [Command; 10] = [
+        Command::Net,
+        Command::Gross,
+        Command::Average,
+        Command::GetTare,
+        Command::Status,
+        Command::Tare,
+        Command::ClearTare,
+        Command::Zero,
+        Command::Identify,
+        Command::Version,
+    ];
+
+    /// Two-letter mnemonic sent to the amplifier.
+    pub fn mnemonic(&self) -> &'static str {
+        match self {
+            Command::Net => "GN",
+            Command::Gross => "GG",
+            Command::Average => "GA",
+            Command::GetTare => "GT",
+            Command::Status => "GS",
+            Command::Tare => "ST",
+            Command::ClearTare => "CT",
+            Command::Zero => "SZ",
+            Command::Identify => "ID",
+            Command::Version => "IV",
+        }
+    }
+
+    /// Short description of the command.
+    pub fn help(&self) -> &'static str {
+        match self {
+            Command::Net => "get the net load",
+            Command::Gross => "get the gross load",
+            Command::Average => "get the average net load (128 readings over 1 sec)",
+            Command::GetTare => "get the stored tare",
+            Command::Status => "get the amplifier status",
+            Command::Tare => "set the tare to the current gross load",
+            Command::ClearTare => "clear the tare",
+            Command::Zero => "set the zero to the current gross load",
+            Command::Identify => "get the amplifier identity",
+            Command::Version => "get the firmware version",
+        }
+    }
+
+    /// Bytes to write on the connection, mnemonic and enter.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!("{}\n", self.mnemonic()).into_bytes()
+    }
+
+    /// True if the reply is a weight frame that can be logged.
+    pub fn is_reading(&self) -> bool {
+        matches!(
+            self,
+            Command::Net | Command::Gross | Command::Average | Command::GetTare
+        )
+    }
+
+    /// Kind of the weight frame expected as reply, none for the other commands.
+    pub fn reply_kind(&self) -> Option<Kind> {
+        match self {
+            Command::Net => Some(Kind::Net),
+            Command::Gross => Some(Kind::Gross),
+            Command::Average => Some(Kind::Average),
+            Command::GetTare => Some(Kind::Tare),
+            _ => None,
+        }
+    }
+
+    /// True if the command changes the state of the amplifier.
+    pub fn is_state_changing(&self) -> bool {
+        matches!(self, Command::Tare | Command::ClearTare | Command::Zero)
+    }
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.mnemonic())
+    }
+}
+
+impl FromStr for Command {
+    type Err = ParseError;
+
+    /// Parse the mnemonic, ignoring capitalization and surrounding spaces.
+    fn from_str(s: &str) -> Result<Command, ParseError> {
+        let s = s.trim().to_uppercase();
+        Command::ALL
+            .iter()
+            .find(|c| c.mnemonic() == s)
+            .copied()
+            .ok_or(ParseError::Command(s))
+    }
+}
+
+/// Settings read to record the identity of the amplifier.
+/// The calibration parameters, e.g., the span load of function 2.1 and the span signal
+/// of function 2.3, the overload limit of menu 3.1, and the decimals are set from the menu
+/// of the amplifier and are deliberately not read, as no command to read them over
+/// the connection is documented; the decimals of each reading are in its raw reading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Setting {
+    /// ID, identity of the amplifier.
+    Identity,
+    /// IV, firmware version.
+    Firmware,
+}
+
+impl Setting {
+    /// All the settings, in the order they are read.
+    pub const ALL: [Setting; 2] = [Setting::Identity, Setting::Firmware];
+
+    /// Name of the setting in the metadata log.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Setting::Identity => "identity",
+            Setting::Firmware => "firmware",
+        }
+    }
+
+    /// Command that reads the setting.
+    pub fn command(&self) -> Command {
+        match self {
+            Setting::Identity => Command::Identify,
+            Setting::Firmware => Command::Version,
+        }
+    }
+
+    /// Bytes to write on the connection, command and enter.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.command().to_bytes()
+    }
+}
+
+/// Errors from parsing commands and replies.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParseError {
+    /// Unknown command mnemonic.
+    Command(String),
+    /// Reply too short to contain descriptor, sign, and value.
+    Length(String),
+    /// Missing or invalid sign after the descriptor.
+    Sign(String),
+    /// Value field is not a number.
+    Value(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Command(s) => write!(f, "unknown command '{}'", s),
+            ParseError::Length(s) => write!(f, "reply too short '{}'", s),
+            ParseError::Sign(s) => write!(f, "invalid sign in reply '{}'", s),
+            ParseError::Value(s) => write!(f, "invalid value in reply '{}'", s),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Kind of value, first character of the descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Net,
+    Gross,
+    Average,
+    Tare,
+    Other(char),
+}
+
+impl From<char> for Kind {
+    fn from(c: char) -> Kind {
+        match c {
+            'N' => Kind::Net,
+            'G' => Kind::Gross,
+            'A' => Kind::Average,
+            'T' => Kind::Tare,
+            o => Kind::Other(o),
+        }
+    }
+}
+
+/// Status flags, second character of the descriptor:
+/// ' ' or 'S' stable, 'M' motion, 'O' overload, 'U' underload.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Status {
+    pub motion: bool,
+    pub overload: bool,
+    pub underload: bool,
+}
+
+impl Status {
+    pub fn is_stable(&self) -> bool {
+        !(self.motion || self.overload || self.underload)
+    }
+}
+
+impl From<char> for Status {
+    fn from(c: char) -> Status {
+        Status {
+            motion: c == 'M',
+            overload: c == 'O',
+            underload: c == 'U',
+        }
+    }
+}
+
+/// A decoded weight frame.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Reading {
+    pub kind: Kind,
+    pub status: Status,
+    /// Signed load value.
+    pub value: f64,
+    /// Number of digits after the decimal separator.
+    pub decimals: usize,
+}
+
+impl Reading {
+    /// Parse a reply, ignoring the trailing terminator and spaces.
+    /// The value field may be padded with spaces and the decimal separator
+    /// may be in any position, or missing for integer values.
+    pub fn parse(reply: &str) -> Result<Reading, ParseError> {
+        let reply = reply.trim_end();
+        let mut chars = reply.chars();
+        let (kind, status, sign) = match (chars.next(), chars.next(), chars.next()) {
+            (Some(k), Some(s), Some(sign)) => (Kind::from(k), Status::from(s), sign),
+            _ => return Err(ParseError::Length(reply.to_string())),
+        };
+        let sign = match sign {
+            '+' => 1.,
+            '-' => -1.,
+            _ => return Err(ParseError::Sign(reply.to_string())),
+        };
+        let field: String = chars.filter(|c| *c != ' ').collect();
+        if field.is_empty() || !field.chars().all(|c| c.is_ascii_digit() || c == '.') {
+            return Err(ParseError::Value(reply.to_string()));
+        }
+        let value: f64 = field
+            .parse()
+            .map_err(|_| ParseError::Value(reply.to_string()))?;
+        let decimals = field.find('.').map(|p| field.len() - p - 1).unwrap_or(0);
+        Ok(Reading {
+            kind,
+            status,
+            value: sign * value,
+            decimals,
+        })
+    }
+}
+
+impl FromStr for Reading {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Reading, ParseError> {
+        Reading::parse(s)
+    }
+}
+
+impl fmt::Display for Reading {
+    /// Format as a 10-byte DAD frame.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::Net => 'N',
+            Kind::Gross => 'G',
+            Kind::Average => 'A',
+            Kind::Tare => 'T',
+            Kind::Other(c) => c,
+        };
+        let status = if self.status.motion {
+            'M'
+        } else if self.status.overload {
+            'O'
+        } else if self.status.underload {
+            'U'
+        } else {
+            ' '
+        };
+        let sign = if self.value < 0. { '-' } else { '+' };
+        let value = format!(
+            "{:07.decimals$}",
+            self.value.abs(),
+            decimals = self.decimals
+        );
+        write!(f, "{}{}{}{}", kind, status, sign, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_from_str() {
+        assert_eq!("gn".parse::<Command>(), Ok(Command::Net));
+        assert_eq!(" GA ".parse::<Command>(), Ok(Command::Average));
+        assert!("XX".parse::<Command>().is_err());
+        assert_eq!(Command::Net.to_bytes(), b"GN\n".to_vec());
+    }
+
+    #[test]
+    fn reading_parse() {
+        let r = Reading::parse("N +01234.5\r\n").unwrap();
+        assert_eq!(r.kind, Kind::Net);
+        assert!(r.status.is_stable());
+        assert_eq!(r.value, 1234.5);
+        assert_eq!(r.decimals, 1);
+        let r = Reading::parse("GM-0012.34").unwrap();
+        assert_eq!(r.kind, Kind::Gross);
+        assert!(r.status.motion);
+        assert_eq!(r.value, -12.34);
+        assert_eq!(r.decimals, 2);
+        let r = Reading::parse("AO+   1234").unwrap();
+        assert!(r.status.overload);
+        assert_eq!(r.value, 1234.);
+        assert_eq!(r.decimals, 0);
+    }
+
+    #[test]
+    fn reading_parse_errors() {
+        assert!(matches!(Reading::parse("N"), Err(ParseError::Length(_))));
+        assert!(matches!(
+            Reading::parse("N 01234.5"),
+            Err(ParseError::Sign(_))
+        ));
+        assert!(matches!(
+            Reading::parse("N +12a4.5"),
+            Err(ParseError::Value(_))
+        ));
+        assert!(matches!(Reading::parse("N +"), Err(ParseError::Value(_))));
+    }
+
+    #[test]
+    fn reading_display_roundtrip() {
+        for frame in ["N +01234.5", "GM-0012.34", "A +0001234"].iter() {
+            let r = Reading::parse(frame).unwrap();
+            assert_eq!(r.to_string(), *frame);
+            assert_eq!(r.to_string().len(), FRAME_LEN);
+        }
+    }
+}
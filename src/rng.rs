@@ -0,0 +1,40 @@
+//! Pseudo-random numbers for the retry jitter of the logger and the simulated readings.
+//!
+//! Not suitable for cryptography.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Small xorshift generator, enough for noise, fault injection, and retry jitter.
+pub(crate) struct XorShift(u64);
+
+impl XorShift {
+    pub(crate) fn from_time() -> XorShift {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        XorShift(nanos | 1)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub(crate) fn next_u8(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    /// Uniform in [0, 1).
+    pub(crate) fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Standard normal, Box-Muller.
+    pub(crate) fn next_gaussian(&mut self) -> f64 {
+        let u1 = 1. - self.next_f64();
+        let u2 = self.next_f64();
+        (-2. * u1.ln()).sqrt() * (2. * std::f64::consts::PI * u2).cos()
+    }
+}
@@ -0,0 +1,396 @@
+use super::integrity::{last_hash, HashChain};
+use super::log::{open_csvfile, with_suffix, write_line, SyncWrite};
+use super::schedule::{Anchor, Zone};
+use chrono::prelude::*;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::File;
+use std::io::{Error, Result, Write};
+use std::time::{Duration, Instant};
+
+/// How often the logger switches to a new csv file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rotation {
+    Never,
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Rotation {
+    /// First day of the period that contains the datetime, in its time zone, weeks start on Monday.
+    pub fn period_start<Tz: TimeZone>(&self, datetime: DateTime<Tz>) -> NaiveDate {
+        let date = datetime.date_naive();
+        match self {
+            Rotation::Never | Rotation::Daily => date,
+            Rotation::Weekly => {
+                date - chrono::Duration::days(date.weekday().num_days_from_monday() as i64)
+            }
+            Rotation::Monthly => date.with_day(1).unwrap(),
+        }
+    }
+
+    /// Date pattern added to file names without one.
+    fn default_pattern(&self) -> Option<&'static str> {
+        match self {
+            Rotation::Never => None,
+            Rotation::Daily => Some("{%Y-%m-%d}"),
+            Rotation::Weekly => Some("{%G-W%V}"),
+            Rotation::Monthly => Some("{%Y-%m}"),
+        }
+    }
+}
+
+impl std::str::FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Rotation, String> {
+        match s.to_lowercase().as_str() {
+            "never" => Ok(Rotation::Never),
+            "daily" => Ok(Rotation::Daily),
+            "weekly" => Ok(Rotation::Weekly),
+            "monthly" => Ok(Rotation::Monthly),
+            r => Err(format!(
+                "invalid rotation {}, use never, daily, weekly, or monthly",
+                r
+            )),
+        }
+    }
+}
+
+/// When the rows are synced to the disk, besides at rotation and exit.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FsyncPolicy {
+    /// After each row, so that a power loss loses at most the row being written.
+    #[default]
+    Always,
+    /// After a row at most once per period, to spare the SD card with short intervals.
+    Every(Duration),
+    /// Only at rotation and exit, leaving it to the system.
+    Never,
+}
+
+impl std::str::FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<FsyncPolicy, String> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(FsyncPolicy::Always),
+            "never" => Ok(FsyncPolicy::Never),
+            p => match p.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => Ok(FsyncPolicy::Every(Duration::from_secs(seconds))),
+                _ => Err(format!(
+                    "invalid fsync policy {}, use always, never, or a period in seconds",
+                    p
+                )),
+            },
+        }
+    }
+}
+
+/// Replace the {device} placeholder of the template with the device name.
+pub fn with_device(template: &str, device: &str) -> String {
+    template.replace("{device}", device)
+}
+
+/// Name of the file from the template, replacing each {%...} placeholder
+/// with the start of the period formatted with the chrono specifiers, e.g.,
+/// `site_{%Y-%m}.csv` becomes `site_2021-06.csv` for monthly rotation.
+/// Templates without placeholders get the default one of the rotation before the extension.
+pub fn file_name(template: &str, rotation: Rotation, period: NaiveDate) -> String {
+    let template = match rotation.default_pattern() {
+        Some(p) if !template.contains("{%") => {
+            let extension = std::path::Path::new(template)
+                .extension()
+                .and_then(|e| e.to_str());
+            with_suffix(template, p, extension)
+        }
+        _ => String::from(template),
+    };
+    let start = period.and_hms_opt(0, 0, 0).unwrap();
+    let mut name = String::with_capacity(template.len());
+    let mut rest = template.as_str();
+    while let Some(i) = rest.find("{%") {
+        let end = match rest[i..].find('}') {
+            Some(e) => i + e,
+            None => break,
+        };
+        name.push_str(&rest[..i]);
+        name.push_str(&start.format(&rest[i + 1..end]).to_string());
+        rest = &rest[end + 1..];
+    }
+    name.push_str(rest);
+    name
+}
+
+/// Name without the placeholders and their leading separator,
+/// e.g., for the session log of all the rotated files.
+pub fn base_name(template: &str) -> String {
+    let mut name = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find('{') {
+        let end = match rest[i..].find('}') {
+            Some(e) => i + e,
+            None => break,
+        };
+        name.push_str(rest[..i].trim_end_matches(['_', '-']));
+        rest = &rest[end + 1..];
+    }
+    name.push_str(rest);
+    name
+}
+
+/// Csv file that switches to a new file, with its own header, at the start of each period.
+/// The switch happens before writing the row of the first scheduled time of the new period,
+/// so that no reading is lost at the boundary.
+/// The closed files can be compressed with gzip in the background.
+pub struct RotatingCsv {
+    template: String,
+    header: String,
+    rotation: Rotation,
+    interval: chrono::Duration,
+    /// Anchor of the reading times, to check the existing files.
+    anchor: Anchor,
+    /// Time zone of the periods, the one of the scheduled times written.
+    zone: Zone,
+    pub compress: bool,
+    pub fsync: FsyncPolicy,
+    /// Hash chain of the rows, if the header has the hash column.
+    chain: Option<HashChain>,
+    /// Bytes of the row being written, when chaining.
+    line: Vec<u8>,
+    name: String,
+    period: NaiveDate,
+    file: File,
+    synced: Instant,
+    sync_failed: bool,
+}
+
+impl RotatingCsv {
+    /// Open the file of the current period in the time zone as `prepare_csvfile_with_header`,
+    /// returning the last datetime already written to resume logging.
+    pub fn open(
+        template: &str,
+        header: &str,
+        rotation: Rotation,
+        interval: chrono::Duration,
+        anchor: Anchor,
+        zone: Zone,
+        now: DateTime<Local>,
+    ) -> (RotatingCsv, Option<DateTime<Local>>) {
+        let period = rotation.period_start(zone.fix(now));
+        let name = file_name(template, rotation, period);
+        let (file, last) = match open_csvfile(&name, header, interval, anchor) {
+            Ok(opened) => opened,
+            Err(e) => panic!("{}", e),
+        };
+        let csv = RotatingCsv {
+            template: String::from(template),
+            header: String::from(header),
+            rotation,
+            interval,
+            anchor,
+            zone,
+            compress: false,
+            fsync: FsyncPolicy::default(),
+            chain: None,
+            line: Vec::new(),
+            name,
+            period,
+            file,
+            synced: Instant::now(),
+            sync_failed: false,
+        };
+        (csv, last)
+    }
+
+    /// Name of the current file.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Append to each row its hash chained to the last one of the file, keyed with the key,
+    /// if any, the first row written also covers the parameters of the session.
+    pub fn chain(&mut self, session: &str, key: Option<Vec<u8>>) -> Result<()> {
+        let last = last_hash(&self.name)?;
+        self.chain = Some(HashChain::new(&self.header, last, session, key));
+        Ok(())
+    }
+
+    /// Write the complete rows with their hash, keeping the chain if a row cannot be written.
+    fn write_chained(&mut self, buf: &[u8]) -> Result<()> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            let row: Vec<u8> = self.line.drain(..=end).collect();
+            let row = String::from_utf8_lossy(&row[..end]).into_owned();
+            let chain = self.chain.as_mut().unwrap();
+            let hash = chain.hash(&row);
+            write_line(&mut self.file, &format!("{},{}", row, hash))?;
+            chain.written(hash);
+        }
+        Ok(())
+    }
+
+    /// Sync the rows to the disk if due by the policy, warning on the first failure only.
+    fn sync_due(&mut self) {
+        let due = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Every(period) => self.synced.elapsed() >= period,
+            FsyncPolicy::Never => false,
+        };
+        if !due {
+            return;
+        }
+        match self.file.sync_data() {
+            Ok(_) => {
+                self.synced = Instant::now();
+                self.sync_failed = false;
+            }
+            Err(e) => {
+                if !self.sync_failed {
+                    println!("could not sync csvfile {}, error {}", self.name, e);
+                }
+                self.sync_failed = true;
+            }
+        }
+    }
+}
+
+impl Write for RotatingCsv {
+    /// Write the bytes and sync them once a row is complete, if due by the fsync policy.
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = if self.chain.is_some() {
+            self.write_chained(buf)?;
+            buf.len()
+        } else {
+            self.file.write(buf)?
+        };
+        if buf[..written].contains(&b'\n') {
+            self.sync_due();
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.file.flush()
+    }
+}
+
+impl SyncWrite for RotatingCsv {
+    fn sync(&mut self) -> Result<()> {
+        self.file.sync()
+    }
+
+    /// Truncate the part of the row written before an error, so that the retry starts a new line.
+    fn write_row(&mut self, row: &str) -> Result<()> {
+        let len = self.file.metadata()?.len();
+        write_line(self, row).inspect_err(|_| {
+            self.line.clear();
+            let _ = self.file.set_len(len);
+        })
+    }
+
+    fn rotate(&mut self, dtr: DateTime<Local>) -> Result<()> {
+        if self.rotation == Rotation::Never {
+            return Ok(());
+        }
+        let period = self.rotation.period_start(self.zone.fix(dtr));
+        if period == self.period {
+            return Ok(());
+        }
+        let name = file_name(&self.template, self.rotation, period);
+        let (file, _) =
+            open_csvfile(&name, &self.header, self.interval, self.anchor).map_err(Error::other)?;
+        if let Some(chain) = self.chain.as_mut() {
+            chain.switch(&self.header, last_hash(&name)?);
+        }
+        self.file.sync()?;
+        let closed = std::mem::replace(&mut self.name, name);
+        self.file = file;
+        self.period = period;
+        self.synced = Instant::now();
+        println!("rotated csvfile {} to {}", closed, self.name);
+        if self.compress {
+            std::thread::spawn(move || match compress_file(&closed) {
+                Ok(gz) => println!("compressed csvfile {} to {}", closed, gz),
+                Err(e) => println!("could not compress csvfile {}, error {}", closed, e),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Compress the file with gzip, removing the original once the compressed file is synced.
+pub fn compress_file(name: &str) -> Result<String> {
+    let gz_name = format!("{}.gz", name);
+    let mut input = File::open(name)?;
+    let output = File::create(&gz_name)?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    std::io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    std::fs::remove_file(name)?;
+    Ok(gz_name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_files_from_templates() {
+        let date = NaiveDate::from_ymd_opt(2021, 6, 3).unwrap();
+        let dtr = Local.from_local_datetime(&date.and_hms_opt(12, 0, 0).unwrap());
+        let dtr = dtr.unwrap();
+        let template = with_device("site_{device}_{%Y-%m}.csv", "dad");
+        let monthly = Rotation::Monthly.period_start(dtr);
+        assert_eq!(
+            file_name(&template, Rotation::Monthly, monthly),
+            "site_dad_2021-06.csv"
+        );
+        let weekly = Rotation::Weekly.period_start(dtr);
+        assert_eq!(weekly, NaiveDate::from_ymd_opt(2021, 5, 31).unwrap());
+        assert_eq!(
+            file_name("loadcells.csv", Rotation::Weekly, weekly),
+            "loadcells_2021-W22.csv"
+        );
+        assert_eq!(base_name("site_{device}_{%Y-%m}.csv"), "site.csv");
+    }
+
+    #[test]
+    fn rotates_at_the_period_boundary() {
+        let dir = std::env::temp_dir();
+        let template = dir.join("flintec_lpp_rotation_{%Y-%m-%d}.csv");
+        let template = template.to_str().unwrap();
+        let header = "datetime,load_kg,raw_reading\n";
+        let first = Local.with_ymd_and_hms(2021, 6, 1, 23, 59, 0).unwrap();
+        let minute = chrono::Duration::minutes(1);
+        let (mut csv, _) = RotatingCsv::open(
+            template,
+            header,
+            Rotation::Daily,
+            minute,
+            Anchor::default(),
+            Zone::Local,
+            first,
+        );
+        for dtr in [first, first + minute].iter() {
+            csv.rotate(*dtr).unwrap();
+            writeln!(csv, "{},1234.5,N +01234.5", dtr.to_rfc3339()).unwrap();
+        }
+        csv.sync().unwrap();
+        let names = ["2021-06-01", "2021-06-02"];
+        let contents: Vec<String> = names
+            .iter()
+            .map(|d| {
+                let name = template.replace("{%Y-%m-%d}", d);
+                let content = std::fs::read_to_string(&name).unwrap();
+                std::fs::remove_file(&name).unwrap();
+                content
+            })
+            .collect();
+        for content in contents.iter() {
+            assert!(content.starts_with(header));
+            assert_eq!(content.lines().count(), 2);
+        }
+    }
+}
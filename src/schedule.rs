@@ -0,0 +1,695 @@
+//! Reading times of the logger: the time zone of the rows, the anchor of the grid,
+//! and the schedule repeating every interval across clock jumps.
+use super::clock::{Clock, ClockJump, ClockPolicy, ClockWatch};
+use super::log::StopFlag;
+use chrono::prelude::*;
+use std::time::Duration;
+
+/// Time zone of the scheduled times written to the csv files.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Zone {
+    /// Local time, whose offset changes with daylight saving time.
+    #[default]
+    Local,
+    /// Fixed offset, e.g., UTC or the standard time of the site all year round.
+    Fixed(FixedOffset),
+}
+
+impl std::str::FromStr for Zone {
+    type Err = String;
+
+    /// Parse local, utc (or Z), an offset ±HH:MM, or an offset in hours, e.g., -8.
+    fn from_str(s: &str) -> Result<Zone, String> {
+        let invalid = || {
+            format!(
+                "invalid time zone {}, use local, utc, ±HH:MM, or hours relative to UTC",
+                s
+            )
+        };
+        let seconds = match s.to_lowercase().as_str() {
+            "local" => return Ok(Zone::Local),
+            "utc" | "z" => 0,
+            o => match o.parse::<i32>() {
+                Ok(h) => h * 3600,
+                Err(_) => {
+                    let sign = match o.chars().next() {
+                        Some('+') => 1,
+                        Some('-') => -1,
+                        _ => return Err(invalid()),
+                    };
+                    let t = NaiveTime::parse_from_str(&o[1..], "%H:%M").map_err(|_| invalid())?;
+                    sign * t.num_seconds_from_midnight() as i32
+                }
+            },
+        };
+        FixedOffset::east_opt(seconds)
+            .map(Zone::Fixed)
+            .ok_or_else(invalid)
+    }
+}
+
+impl Zone {
+    /// The datetime with the offset of the zone, to be written.
+    pub fn fix(&self, datetime: DateTime<Local>) -> DateTime<FixedOffset> {
+        match self {
+            Zone::Local => datetime.fixed_offset(),
+            Zone::Fixed(offset) => datetime.with_timezone(offset),
+        }
+    }
+}
+
+/// Time the reading times are rounded to, as anchor + n * interval.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Anchor {
+    /// Local time of the day.
+    Local(NaiveTime),
+    /// UTC time of the day.
+    Utc(NaiveTime),
+    /// Time of the day at a fixed offset, unaffected by daylight saving time.
+    Fixed(NaiveTime, FixedOffset),
+    /// Fixed datetime.
+    At(DateTime<FixedOffset>),
+}
+
+impl Default for Anchor {
+    /// Local midnight.
+    fn default() -> Anchor {
+        Anchor::Local(NaiveTime::MIN)
+    }
+}
+
+impl std::str::FromStr for Anchor {
+    type Err = String;
+
+    /// Parse HH:MM[:SS] as local time, HH:MM[:SS]Z as UTC time, or a RFC 3339 datetime.
+    fn from_str(s: &str) -> Result<Anchor, String> {
+        let parse_time = |t: &str| {
+            NaiveTime::parse_from_str(t, "%H:%M:%S")
+                .or_else(|_| NaiveTime::parse_from_str(t, "%H:%M"))
+                .map_err(|e| format!("invalid anchor {}, {}", s, e))
+        };
+        if let Ok(datetime) = DateTime::parse_from_rfc3339(s) {
+            Ok(Anchor::At(datetime))
+        } else if let Some(t) = s.strip_suffix('Z').or_else(|| s.strip_suffix('z')) {
+            Ok(Anchor::Utc(parse_time(t)?))
+        } else {
+            Ok(Anchor::Local(parse_time(s)?))
+        }
+    }
+}
+
+impl Anchor {
+    /// Time from the last reading time of the grid to the datetime, zero on the grid.
+    /// Local times of the day are compared on the local time, with the offset of the datetime
+    /// or the other one of the zone, as a schedule running across a change of daylight saving
+    /// time keeps its interval, and the times skipped by it are shifted forward by the gap.
+    pub fn remainder<Tz: TimeZone>(
+        &self,
+        datetime: DateTime<Tz>,
+        interval: chrono::Duration,
+    ) -> chrono::Duration {
+        let interval_ms = interval.num_milliseconds();
+        let remainder = |reference: NaiveDateTime, datetime: NaiveDateTime| {
+            let ms = (datetime - reference)
+                .num_milliseconds()
+                .rem_euclid(interval_ms);
+            chrono::Duration::milliseconds(ms)
+        };
+        match self {
+            Anchor::Local(t) => {
+                let utc = datetime.naive_utc();
+                let half_year = chrono::Duration::days(183);
+                [utc, utc - half_year, utc + half_year]
+                    .iter()
+                    .map(|u| utc + datetime.timezone().offset_from_utc_datetime(u).fix())
+                    .map(|local| remainder(ANCHOR_EPOCH.and_time(*t), local))
+                    .min()
+                    .unwrap()
+            }
+            _ => {
+                let previous = self.first_after(datetime.clone() - interval, interval);
+                remainder(previous.naive_utc(), datetime.naive_utc())
+            }
+        }
+    }
+
+    /// Time of the day of a local anchor in the zone instead,
+    /// so that the rounding is done on the grid of the zone.
+    pub fn in_zone(self, zone: Zone) -> Anchor {
+        match (self, zone) {
+            (Anchor::Local(t), Zone::Fixed(offset)) => Anchor::Fixed(t, offset),
+            (anchor, _) => anchor,
+        }
+    }
+
+    /// First reading time after the datetime, rounded to the anchor.
+    /// Times of the day are anchored on `ANCHOR_EPOCH`, so that the reading times are on
+    /// the same grid on any day, also with intervals that are not divisors of 1 day.
+    pub fn first_after<Tz: TimeZone>(
+        &self,
+        datetime: DateTime<Tz>,
+        interval: chrono::Duration,
+    ) -> DateTime<Tz> {
+        let interval_ms = interval.num_milliseconds();
+        let after = |reference: NaiveDateTime, now: NaiveDateTime| {
+            let n = (now - reference).num_milliseconds().div_euclid(interval_ms) + 1;
+            reference + chrono::Duration::milliseconds(n * interval_ms)
+        };
+        match self {
+            Anchor::Local(t) => {
+                // on the local times, so that the times of the day stay with daylight saving time,
+                // from the earlier local time of the hour repeated at its end, if coming
+                let day_after = datetime.naive_utc() + chrono::Duration::days(1);
+                let offset = datetime
+                    .timezone()
+                    .offset_from_utc_datetime(&day_after)
+                    .fix();
+                let repeated = datetime.naive_utc() + offset;
+                let start = datetime.naive_local().min(repeated);
+                let mut next = after(ANCHOR_EPOCH.and_time(*t), start);
+                loop {
+                    if let Some(d) = local_after(next, &datetime) {
+                        // a time shifted by the gap is kept unless one of the grid comes first
+                        let mut later = next + interval;
+                        while later < d.naive_local() {
+                            let on_grid =
+                                local_after(later, &datetime).filter(|e| e.naive_local() == later);
+                            if let Some(e) = on_grid {
+                                return e;
+                            }
+                            later += interval;
+                        }
+                        return d;
+                    }
+                    next += interval;
+                }
+            }
+            Anchor::Utc(t) => {
+                let next = after(ANCHOR_EPOCH.and_time(*t), datetime.naive_utc());
+                datetime.timezone().from_utc_datetime(&next)
+            }
+            Anchor::Fixed(t, offset) => {
+                let now = datetime.with_timezone(offset).naive_local();
+                let next = after(ANCHOR_EPOCH.and_time(*t), now);
+                let next = offset.from_local_datetime(&next).unwrap();
+                next.with_timezone(&datetime.timezone())
+            }
+            Anchor::At(d) => {
+                let next = after(d.naive_utc(), datetime.naive_utc());
+                datetime.timezone().from_utc_datetime(&next)
+            }
+        }
+    }
+}
+
+/// Date the times of the day of the anchors are taken on.
+const ANCHOR_EPOCH: NaiveDate = match NaiveDate::from_ymd_opt(2000, 1, 1) {
+    Some(d) => d,
+    None => panic!("invalid anchor epoch"),
+};
+
+/// Local time of the naive datetime if after the given one: the first one after it
+/// when ambiguous at the end of daylight saving time, and shifted forward by the gap
+/// when skipped at its start, e.g., 1 hour, or 30 minutes on Lord Howe Island.
+fn local_after<Tz: TimeZone>(naive: NaiveDateTime, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+    let tz = after.timezone();
+    let local = match tz.from_local_datetime(&naive) {
+        chrono::LocalResult::Single(d) => Some(d),
+        chrono::LocalResult::Ambiguous(earliest, latest) => {
+            Some(if earliest > *after { earliest } else { latest })
+        }
+        chrono::LocalResult::None => {
+            // with the offset before the gap, the time is after it by the length of the gap
+            let before = tz
+                .offset_from_local_datetime(&(naive - chrono::Duration::days(1)))
+                .earliest()?
+                .fix();
+            let utc = naive - chrono::Duration::seconds(before.local_minus_utc().into());
+            Some(tz.from_utc_datetime(&utc))
+        }
+    };
+    local.filter(|d| d > after)
+}
+
+/// Interval in the largest whole unit, e.g., 90 second(s), 7 minute(s), or 2 hour(s).
+pub fn format_interval(interval: chrono::Duration) -> String {
+    let seconds = interval.num_seconds();
+    if seconds % 3600 == 0 {
+        format!("{} hour(s)", seconds / 3600)
+    } else if seconds % 60 == 0 {
+        format!("{} minute(s)", seconds / 60)
+    } else {
+        format!("{} second(s)", seconds)
+    }
+}
+
+pub fn chrono_first_rounded(
+    datetime: DateTime<Local>,
+    rounding: chrono::Duration,
+) -> DateTime<Local> {
+    let offset: i64 = datetime.offset().local_minus_utc().into();
+    let local_sec = datetime.timestamp() + offset;
+    let rounding_sec = rounding.num_seconds();
+    let first_sec = rounding_sec * ((local_sec + rounding_sec) / rounding_sec) - offset;
+    Local.timestamp_opt(first_sec, 0).unwrap()
+}
+
+/// Rounded reading times, repeating every interval.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub interval: chrono::Duration,
+    pub anchor: Anchor,
+    /// Wake up this long before each scheduled time, e.g., to centre a burst of readings.
+    pub lead: chrono::Duration,
+    pub clock_policy: ClockPolicy,
+    next: DateTime<Local>,
+    /// Sum of the clock jumps ignored with the flag policy.
+    offset: chrono::Duration,
+    watch: Option<ClockWatch>,
+    jumps: Vec<ClockJump>,
+}
+
+impl Schedule {
+    pub fn new(interval: chrono::Duration, now: DateTime<Local>) -> Schedule {
+        Schedule {
+            interval,
+            anchor: Anchor::default(),
+            lead: chrono::Duration::zero(),
+            clock_policy: ClockPolicy::default(),
+            next: now,
+            offset: chrono::Duration::zero(),
+            watch: None,
+            jumps: Vec::new(),
+        }
+    }
+
+    /// Next scheduled reading time.
+    pub fn next(&self) -> DateTime<Local> {
+        self.next
+    }
+
+    /// Schedule the first reading at the next time rounded to the anchor.
+    pub fn start(&mut self, now: DateTime<Local>) {
+        self.next = self.anchor.first_after(now, self.interval);
+        println!(
+            "starting at: {}, and then repeating from {} every {}",
+            self.next.to_rfc3339_opts(SecondsFormat::Secs, false),
+            (self.next + self.interval).to_rfc3339_opts(SecondsFormat::Secs, false),
+            format_interval(self.interval)
+        );
+    }
+
+    /// Move the next scheduled time after the last logged time if the clock is behind it,
+    /// e.g., at boot before NTP syncs, returning true if moved.
+    pub fn start_after(&mut self, last: DateTime<Local>) -> bool {
+        if self.next > last {
+            return false;
+        }
+        self.next = self.anchor.first_after(last, self.interval);
+        true
+    }
+
+    /// Sum of the clock jumps ignored with the flag policy,
+    /// the wall-clock time of the scheduled time dtr is dtr + offset.
+    pub fn offset(&self) -> chrono::Duration {
+        self.offset
+    }
+
+    /// Current time on the schedule, the wall-clock time less the jumps ignored with the flag policy.
+    pub fn now<C: Clock>(&self, clock: &C) -> DateTime<Local> {
+        clock.now() - self.offset
+    }
+
+    /// Check the wall clock against the monotonic clock and handle a jump with the policy,
+    /// keeping it to be reported with `take_jumps`.
+    pub fn check_clock<C: Clock>(&mut self, clock: &C) {
+        let jump = match &mut self.watch {
+            Some(watch) => match watch.check(clock) {
+                Some(jump) => jump,
+                None => return,
+            },
+            None => {
+                self.watch = Some(ClockWatch::new(clock));
+                return;
+            }
+        };
+        match self.clock_policy {
+            ClockPolicy::Flag => self.offset += jump,
+            ClockPolicy::Reanchor if jump > chrono::Duration::zero() => {
+                let first = self.anchor.first_after(self.now(clock), self.interval);
+                self.next = self.next.max(first);
+            }
+            _ => {}
+        }
+        self.jumps.push(ClockJump {
+            at: clock.now(),
+            jump,
+            policy: self.clock_policy,
+            next: self.next,
+        });
+    }
+
+    /// Clock jumps handled since the last call.
+    pub fn take_jumps(&mut self) -> Vec<ClockJump> {
+        std::mem::take(&mut self.jumps)
+    }
+
+    /// Sleep until the next scheduled reading, less the lead, in steps of at most 1 second
+    /// to check the stop flag and the clock. Return false if stopped before the scheduled time.
+    pub fn wait_next<C: Clock>(&mut self, clock: &mut C, stop: &StopFlag) -> bool {
+        loop {
+            if stop.is_stopped() {
+                return false;
+            }
+            self.check_clock(clock);
+            let wait = match (self.next - self.lead - self.now(clock)).to_std() {
+                Ok(w) if w > Duration::from_secs(0) => w,
+                _ => return true,
+            };
+            clock.sleep(wait.min(Duration::from_secs(1)));
+        }
+    }
+
+    /// Return the next scheduled time and move to the following one.
+    pub fn advance(&mut self) -> DateTime<Local> {
+        let dtr = self.next;
+        self.next += self.interval;
+        dtr
+    }
+
+    /// Scheduled times after the last logged time and before the next scheduled time,
+    /// e.g., missed while the logger was not running.
+    pub fn missed_since(&self, last: DateTime<Local>) -> Vec<DateTime<Local>> {
+        let mut missed = Vec::new();
+        let mut dtr = last + self.interval;
+        while dtr < self.next {
+            missed.push(dtr);
+            dtr += self.interval;
+        }
+        missed
+    }
+
+    /// Advance if the next scheduled time already passed, returning the passed time.
+    /// The time is the one on the schedule, see `now`.
+    pub fn advance_passed(&mut self, now: DateTime<Local>) -> Option<DateTime<Local>> {
+        if self.next <= now {
+            Some(self.advance())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Clock whose wall-clock time can jump, unlike its monotonic time.
+    struct JumpingClock {
+        wall: DateTime<Local>,
+        monotonic: Duration,
+    }
+
+    impl Clock for JumpingClock {
+        fn now(&self) -> DateTime<Local> {
+            self.wall
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.wall += chrono::Duration::from_std(duration).unwrap();
+            self.monotonic += duration;
+        }
+
+        fn monotonic(&self) -> Duration {
+            self.monotonic
+        }
+    }
+
+    /// Zone with daylight saving time from 2021-03-28 to 2021-10-31, both changes at
+    /// 02:00 standard time, with the standard and summer offsets in seconds.
+    #[derive(Debug, Clone, Copy)]
+    struct DstZone<const STD: i32, const DST: i32>;
+
+    /// Central Europe.
+    type Cet = DstZone<3600, 7200>;
+
+    /// Lord Howe Island, whose clocks change by 30 minutes, on the dates of Central Europe.
+    type LordHowe = DstZone<37800, 39600>;
+
+    impl<const STD: i32, const DST: i32> DstZone<STD, DST> {
+        fn changes() -> (NaiveDateTime, NaiveDateTime) {
+            let at = |m, d| {
+                NaiveDate::from_ymd_opt(2021, m, d)
+                    .unwrap()
+                    .and_hms_opt(2, 0, 0)
+                    .unwrap()
+                    - chrono::Duration::seconds(STD.into())
+            };
+            (at(3, 28), at(10, 31))
+        }
+
+        fn at(naive: &str) -> DateTime<Self> {
+            let naive = naive.parse::<NaiveDateTime>().unwrap();
+            DstZone.from_local_datetime(&naive).earliest().unwrap()
+        }
+    }
+
+    impl<const STD: i32, const DST: i32> TimeZone for DstZone<STD, DST> {
+        type Offset = FixedOffset;
+
+        fn from_offset(_offset: &FixedOffset) -> Self {
+            DstZone
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> chrono::LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_local_datetime(
+            &self,
+            local: &NaiveDateTime,
+        ) -> chrono::LocalResult<FixedOffset> {
+            let valid: Vec<FixedOffset> = [DST, STD]
+                .iter()
+                .map(|s| FixedOffset::east_opt(*s).unwrap())
+                .filter(|o| self.offset_from_utc_datetime(&(*local - *o)) == *o)
+                .collect();
+            match valid[..] {
+                [] => chrono::LocalResult::None,
+                [o] => chrono::LocalResult::Single(o),
+                [earliest, latest] => chrono::LocalResult::Ambiguous(earliest, latest),
+                _ => unreachable!(),
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            let (spring, fall) = Self::changes();
+            let summer = (spring..fall).contains(utc);
+            FixedOffset::east_opt(if summer { DST } else { STD }).unwrap()
+        }
+    }
+
+    #[test]
+    fn handles_clock_jumps() {
+        let start = Local.with_ymd_and_hms(2021, 6, 1, 10, 0, 30).unwrap();
+        let minute = chrono::Duration::minutes(1);
+        let stop = StopFlag::new();
+        let passed_after_jump = |policy: ClockPolicy, jump: i64| {
+            let mut clock = JumpingClock {
+                wall: start,
+                monotonic: Duration::from_secs(1000),
+            };
+            let mut schedule = Schedule::new(minute, clock.now());
+            schedule.clock_policy = policy;
+            schedule.start(clock.now());
+            assert!(schedule.wait_next(&mut clock, &stop));
+            let first = schedule.advance();
+            clock.wall += chrono::Duration::seconds(jump);
+            schedule.check_clock(&clock);
+            let jumps = schedule.take_jumps();
+            assert_eq!(jumps.len(), 1);
+            assert_eq!(jumps[0].jump, chrono::Duration::seconds(jump));
+            let mut passed = Vec::new();
+            while let Some(dtr) = schedule.advance_passed(schedule.now(&clock)) {
+                passed.push(dtr);
+            }
+            assert!(schedule.wait_next(&mut clock, &stop));
+            (first, passed, schedule.advance(), clock.now())
+        };
+        // a forward jump of 5 minutes
+        let (first, passed, next, _) = passed_after_jump(ClockPolicy::Reanchor, 300);
+        assert!(passed.is_empty());
+        assert_eq!(next, first + minute * 6);
+        let (first, passed, next, _) = passed_after_jump(ClockPolicy::Wait, 300);
+        assert_eq!(passed.len(), 5);
+        assert_eq!(next, first + minute * 6);
+        let (first, passed, next, now) = passed_after_jump(ClockPolicy::Flag, 300);
+        assert!(passed.is_empty());
+        assert_eq!(next, first + minute);
+        assert_eq!(now, next + chrono::Duration::seconds(300));
+        // a backward jump of 5 minutes waits for the clock, or keeps the schedule with flag
+        let (first, passed, next, now) = passed_after_jump(ClockPolicy::Reanchor, -300);
+        assert!(passed.is_empty());
+        assert_eq!((next, now), (first + minute, first + minute));
+        let (first, _, next, now) = passed_after_jump(ClockPolicy::Flag, -300);
+        assert_eq!(next, first + minute);
+        assert_eq!(now, next - chrono::Duration::seconds(300));
+        // the clock behind the last logged time at start
+        let mut schedule = Schedule::new(minute, start);
+        schedule.start(start);
+        assert!(schedule.start_after(start + minute * 10));
+        assert_eq!(
+            schedule.next(),
+            start + chrono::Duration::seconds(30) + minute * 10
+        );
+    }
+
+    #[test]
+    fn schedules_in_a_fixed_zone_across_dst() {
+        assert_eq!("local".parse::<Zone>(), Ok(Zone::Local));
+        let cet = FixedOffset::east_opt(3600).unwrap();
+        assert_eq!("+01:00".parse::<Zone>(), Ok(Zone::Fixed(cet)));
+        assert_eq!("1".parse::<Zone>(), Ok(Zone::Fixed(cet)));
+        assert_eq!(
+            "UTC".parse::<Zone>(),
+            Ok(Zone::Fixed(FixedOffset::east_opt(0).unwrap()))
+        );
+        assert!("CET".parse::<Zone>().is_err());
+        // spring-forward and fall-back nights of Central Europe, at 01:00 UTC,
+        // logged in standard time: the times keep the offset and the grid of the zone
+        let zone = Zone::Fixed(cet);
+        for night in ["2021-03-28T00:40:00Z", "2021-10-31T00:40:00Z"].iter() {
+            let now = DateTime::parse_from_rfc3339(night)
+                .unwrap()
+                .with_timezone(&Local);
+            let mut schedule = Schedule::new(chrono::Duration::minutes(45), now);
+            schedule.anchor = "00:00".parse::<Anchor>().unwrap().in_zone(zone);
+            schedule.start(now);
+            let times: Vec<String> = (0..4)
+                .map(|_| {
+                    zone.fix(schedule.advance())
+                        .to_rfc3339_opts(SecondsFormat::Secs, false)
+                })
+                .collect();
+            let date = &night[..10];
+            assert_eq!(
+                times,
+                ["02:15:00", "03:00:00", "03:45:00", "04:30:00"]
+                    .iter()
+                    .map(|t| format!("{}T{}+01:00", date, t))
+                    .collect::<Vec<String>>()
+            );
+        }
+    }
+
+    #[test]
+    fn rounds_to_anchors() {
+        let now = Local.with_ymd_and_hms(2021, 6, 1, 10, 0, 30).unwrap();
+        let midnight = Anchor::default();
+        let first = midnight.first_after(now, chrono::Duration::seconds(5));
+        assert_eq!(first, now + chrono::Duration::seconds(5));
+        let first = midnight.first_after(now, chrono::Duration::seconds(90));
+        assert_eq!(first.time(), NaiveTime::from_hms_opt(10, 1, 30).unwrap());
+        let anchor: Anchor = "08:30:15".parse().unwrap();
+        let seven = chrono::Duration::minutes(7);
+        let first = anchor.first_after(now, seven);
+        assert_eq!(first.time(), NaiveTime::from_hms_opt(10, 7, 15).unwrap());
+        // a restart on another day stays on the same grid
+        let next_day = anchor.first_after(now + chrono::Duration::days(1), seven);
+        assert_eq!((next_day - first).num_seconds() % seven.num_seconds(), 0);
+        assert_eq!(anchor.remainder(next_day, seven), chrono::Duration::zero());
+        let anchor: Anchor = "2021-06-01T09:00:10+00:00".parse().unwrap();
+        let first = anchor.first_after(now, chrono::Duration::minutes(1));
+        assert_eq!(first.second(), 10);
+        assert_eq!(
+            "00:00Z".parse::<Anchor>().unwrap(),
+            Anchor::Utc(NaiveTime::MIN)
+        );
+        assert!("25:00".parse::<Anchor>().is_err());
+        assert_eq!(
+            format_interval(chrono::Duration::seconds(90)),
+            "90 second(s)"
+        );
+    }
+
+    #[test]
+    fn shifts_local_times_by_the_gap() {
+        fn local<Tz: TimeZone>(naive: &str, after: &DateTime<Tz>) -> Option<String> {
+            let naive = naive.parse::<NaiveDateTime>().unwrap();
+            local_after(naive, after).map(|d| d.fixed_offset().to_rfc3339())
+        }
+        let some = |d: &str| Some(String::from(d));
+        // skipped at the start of daylight saving time, shifted by the offsets around the gap
+        let after = Cet::at("2021-03-28T01:50:00");
+        let shifted = local("2021-03-28T02:15:00", &after);
+        assert_eq!(shifted, some("2021-03-28T03:15:00+02:00"));
+        let after = LordHowe::at("2021-03-28T01:50:00");
+        let shifted = local("2021-03-28T02:15:00", &after);
+        assert_eq!(shifted, some("2021-03-28T02:45:00+11:00"));
+        // twice at its end, the first one after the given time
+        let after = LordHowe::at("2021-10-31T01:50:00");
+        let first = local("2021-10-31T02:15:00", &after);
+        assert_eq!(first, some("2021-10-31T02:15:00+11:00"));
+        let after = LordHowe::at("2021-10-31T01:50:00") + chrono::Duration::minutes(30);
+        let second = local("2021-10-31T02:15:00", &after);
+        assert_eq!(second, some("2021-10-31T02:15:00+10:30"));
+        let after = Cet::at("2021-10-31T02:50:00") + chrono::Duration::hours(1);
+        assert_eq!(local("2021-10-31T02:15:00", &after), None);
+    }
+
+    #[test]
+    fn rounds_to_local_anchors_across_dst() {
+        let rfc = |d: DateTime<Cet>| d.fixed_offset().to_rfc3339();
+        let midnight = Anchor::default();
+        let minutes = chrono::Duration::minutes(45);
+        // spring-forward night, 02:15 is skipped and 03:00 is the first time of the grid
+        let first = midnight.first_after(Cet::at("2021-03-28T01:40:00"), minutes);
+        assert_eq!(rfc(first), "2021-03-28T03:00:00+02:00");
+        // a time of the day in the gap is shifted forward by it
+        let half_past_two: Anchor = "02:30".parse().unwrap();
+        let day = chrono::Duration::days(1);
+        let first = half_past_two.first_after(Cet::at("2021-03-27T12:00:00"), day);
+        assert_eq!(rfc(first), "2021-03-28T03:30:00+02:00");
+        let first = half_past_two.first_after(first, day);
+        assert_eq!(rfc(first), "2021-03-29T02:30:00+02:00");
+        // fall-back night, 02:15 comes twice, the first one after the datetime is kept
+        let summer = Cet::at("2021-10-31T02:20:00");
+        let first = midnight.first_after(summer, minutes);
+        assert_eq!(rfc(first), "2021-10-31T02:15:00+01:00");
+        let winter = summer + chrono::Duration::hours(1);
+        let first = midnight.first_after(winter, minutes);
+        assert_eq!(rfc(first), "2021-10-31T03:00:00+01:00");
+        let first = midnight.first_after(Cet::at("2021-10-31T02:10:00"), minutes);
+        assert_eq!(rfc(first), "2021-10-31T02:15:00+02:00");
+    }
+
+    #[test]
+    fn checks_local_anchors_on_the_local_time() {
+        let midnight = Anchor::default();
+        let remainder = |at: &str, interval| midnight.remainder(Cet::at(at), interval);
+        let day = chrono::Duration::days(1);
+        let zero = chrono::Duration::zero();
+        assert_eq!(remainder("2021-03-29T00:00:00", day), zero);
+        assert_eq!(
+            remainder("2021-03-29T00:30:00", day),
+            chrono::Duration::minutes(30)
+        );
+        // 00:00 in standard time, after a schedule ran across the spring-forward night
+        assert_eq!(remainder("2021-03-29T01:00:00", day), zero);
+        // 7 minutes are not a divisor of 1 hour, the grid is kept on the local time
+        let seven = chrono::Duration::minutes(7);
+        assert_eq!(remainder("2021-06-01T12:07:00", seven), zero);
+        assert_eq!(
+            remainder("2021-06-01T12:08:00", seven),
+            chrono::Duration::minutes(1)
+        );
+        // skipped at 02:15 and shifted forward, or reached by a schedule from 01:30
+        let minutes = chrono::Duration::minutes(45);
+        assert_eq!(remainder("2021-03-28T03:15:00", minutes), zero);
+        assert_eq!(remainder("2021-03-28T03:00:00", minutes), zero);
+    }
+}
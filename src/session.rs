@@ -0,0 +1,173 @@
+//! Sidecar log of the logging sessions, with the start and stop markers,
+//! the outages, the clock jumps, and the configuration drifts.
+use super::clock::ClockJump;
+use super::log::{with_suffix, Outage};
+use chrono::prelude::*;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// Name of the sidecar session log of the csv file, e.g., loadcells_sessions.log.
+pub fn session_log_name(csv_name: &str) -> String {
+    with_suffix(csv_name, "sessions", Some("log"))
+}
+
+/// Sidecar log of the logging sessions, one line per event:
+/// datetime, start or stop, and the details (CLI parameters or stop reason).
+/// A start without the following stop marks a session that ended without a clean shutdown,
+/// e.g., a crash or a power loss.
+#[derive(Debug, Clone)]
+pub struct SessionLog {
+    path: String,
+}
+
+impl SessionLog {
+    /// Append the start marker with the given parameters.
+    pub fn start(path: &str, parameters: &str) -> SessionLog {
+        let session = SessionLog {
+            path: String::from(path),
+        };
+        session.append("start", parameters);
+        session
+    }
+
+    /// Append the stop marker with the reason.
+    pub fn stop(&self, reason: &str) {
+        self.append("stop", reason);
+    }
+
+    /// Append the summary of a connection outage.
+    pub fn outage(&self, outage: &Outage) {
+        self.append("outage", &outage.to_string());
+    }
+
+    /// Append the description of a clock jump.
+    pub fn clock_jump(&self, jump: &ClockJump) {
+        self.append("clock_jump", &jump.to_string());
+    }
+
+    /// Append the description of a change of the amplifier configuration.
+    pub fn config_drift(&self, drift: &str) {
+        self.append("config_drift", drift);
+    }
+
+    fn append(&self, event: &str, details: &str) {
+        let now = Local::now().to_rfc3339_opts(SecondsFormat::Secs, false);
+        let appended = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.path)
+            .and_then(|mut f| {
+                writeln!(f, "{},{},{}", now, event, details)?;
+                f.sync_all()
+            });
+        match appended {
+            Ok(_) => println!("session {} marker written to {}", event, self.path),
+            Err(e) => println!(
+                "could not write session {} marker to {}, error {}",
+                event, self.path, e
+            ),
+        }
+    }
+}
+
+/// A logging session read from the sidecar session log.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub start: DateTime<FixedOffset>,
+    pub parameters: String,
+    /// Time and reason of the clean shutdown, none if the logger crashed or was killed.
+    pub stop: Option<(DateTime<FixedOffset>, String)>,
+    /// Summaries of the connection outages.
+    pub outages: Vec<String>,
+    /// Descriptions of the clock jumps.
+    pub clock_jumps: Vec<String>,
+    /// Descriptions of the changes of the amplifier configuration.
+    pub config_drifts: Vec<String>,
+}
+
+/// Read the sessions from the sidecar session log.
+pub fn read_sessions<P>(fin: P) -> Vec<Session>
+where
+    P: AsRef<Path>,
+{
+    let file = File::open(fin).unwrap();
+    let buf = BufReader::new(file);
+    let mut sessions: Vec<Session> = Vec::new();
+    for l in buf.lines() {
+        let l_unwrap = match l {
+            Ok(l_ok) => l_ok,
+            Err(l_err) => {
+                println!("Err, could not read/unwrap line {}", l_err);
+                continue;
+            }
+        };
+        let mut l_split = l_unwrap.splitn(3, ',');
+        let (datetime, event, details) = match (l_split.next(), l_split.next(), l_split.next()) {
+            (Some(d), Some(e), Some(r)) => (d, e, r),
+            _ => {
+                println!("Could not parse session line: {}", l_unwrap);
+                continue;
+            }
+        };
+        let datetime = match DateTime::parse_from_rfc3339(datetime) {
+            Ok(d) => d,
+            Err(e) => {
+                println!("Could not parse datetime: {}, error {}", datetime, e);
+                continue;
+            }
+        };
+        match event {
+            "start" => sessions.push(Session {
+                start: datetime,
+                parameters: String::from(details),
+                stop: None,
+                outages: Vec::new(),
+                clock_jumps: Vec::new(),
+                config_drifts: Vec::new(),
+            }),
+            "stop" => match sessions.last_mut() {
+                Some(s) if s.stop.is_none() => s.stop = Some((datetime, String::from(details))),
+                _ => println!("found stop marker without start at {}", datetime),
+            },
+            "outage" => match sessions.last_mut() {
+                Some(s) => s.outages.push(String::from(details)),
+                None => println!("found outage without start at {}", datetime),
+            },
+            "clock_jump" => match sessions.last_mut() {
+                Some(s) => s.clock_jumps.push(String::from(details)),
+                None => println!("found clock jump without start at {}", datetime),
+            },
+            "config_drift" => match sessions.last_mut() {
+                Some(s) => s.config_drifts.push(String::from(details)),
+                None => println!("found config drift without start at {}", datetime),
+            },
+            _ => println!("Unknown session event: {}", event),
+        }
+    }
+    sessions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_sessions() {
+        let path = std::env::temp_dir().join("flintec_lpp_sessions.log");
+        let _ = std::fs::remove_file(&path);
+        let session = SessionLog::start(path.to_str().unwrap(), "flintec_log -m 1");
+        session.stop("SIGINT");
+        SessionLog::start(path.to_str().unwrap(), "flintec_log -m 2");
+        let sessions = read_sessions(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0].parameters, "flintec_log -m 1");
+        assert_eq!(sessions[0].stop.as_ref().unwrap().1, "SIGINT");
+        assert!(sessions[1].stop.is_none());
+        assert_eq!(
+            session_log_name("data/loadcells.csv"),
+            "data/loadcells_sessions.log"
+        );
+    }
+}
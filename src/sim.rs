@@ -0,0 +1,363 @@
+use super::protocol::{Command, Kind, Reading, Status, ERROR_FRAME};
+use super::rng::XorShift;
+use super::TimeLoad;
+use super::VERSION;
+use clap::{App, Arg};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Number of readings averaged by the amplifier for GA.
+pub const AVERAGE_READINGS: usize = 128;
+
+/// Faults that the simulator can inject in place of a regular reply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Reply only after the given delay, e.g., past the logger timeout.
+    Delay(Duration),
+    /// Close the connection without replying.
+    Drop,
+    /// Reply with bytes that are not valid UTF-8.
+    Garbage,
+    /// Reply with the amplifier error frame.
+    Error,
+}
+
+impl std::str::FromStr for Fault {
+    type Err = String;
+
+    /// Parse `delay[:seconds]`, `drop`, `garbage`, or `error`.
+    fn from_str(s: &str) -> Result<Fault, String> {
+        let mut split = s.trim().splitn(2, ':');
+        match (split.next(), split.next()) {
+            (Some("delay"), None) => Ok(Fault::Delay(Duration::from_secs(20))),
+            (Some("delay"), Some(secs)) => secs
+                .parse::<u64>()
+                .map(|secs| Fault::Delay(Duration::from_secs(secs)))
+                .map_err(|e| format!("invalid delay '{}', {}", secs, e)),
+            (Some("drop"), None) => Ok(Fault::Drop),
+            (Some("garbage"), None) => Ok(Fault::Garbage),
+            (Some("error"), None) => Ok(Fault::Error),
+            _ => Err(format!("unknown fault '{}'", s)),
+        }
+    }
+}
+
+/// Source of the simulated gross load.
+#[derive(Debug, Clone)]
+pub enum Signal {
+    /// Constant load with a daily sine, a linear drift, and gaussian noise.
+    Model {
+        load: f64,
+        amplitude: f64,
+        period: Duration,
+        drift_per_day: f64,
+        noise: f64,
+    },
+    /// Replay the valid load values of a csv file, one per reading, in a loop.
+    Replay(Vec<f64>),
+}
+
+impl Signal {
+    /// Read the csv in the format written by flintec_log or flintec_process,
+    /// skipping NAN and error values.
+    pub fn from_csv(fin: PathBuf, max_valid: f64) -> Signal {
+        let timeload = TimeLoad::from_csv(&fin);
+        let loads: Vec<f64> = timeload
+            .load
+            .into_iter()
+            .filter(|l| !l.is_nan() && *l <= max_valid)
+            .collect();
+        assert!(
+            !loads.is_empty(),
+            "no valid load values to replay in {}",
+            fin.display()
+        );
+        Signal::Replay(loads)
+    }
+}
+
+/// Simulated DAD 141.1 amplifier, answering the protocol commands.
+pub struct Simulator {
+    signal: Signal,
+    decimals: usize,
+    start: Instant,
+    replay_index: usize,
+    zero: f64,
+    tare: f64,
+    rng: XorShift,
+}
+
+/// Reply of the simulator to a command.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Bytes to send back, terminator included.
+    Send(Vec<u8>),
+    /// Send the bytes after the delay.
+    Delayed(Duration, Vec<u8>),
+    /// Close the connection.
+    Drop,
+}
+
+impl Simulator {
+    pub fn new(signal: Signal, decimals: usize) -> Simulator {
+        Simulator {
+            signal,
+            decimals,
+            start: Instant::now(),
+            replay_index: 0,
+            zero: 0.,
+            tare: 0.,
+            rng: XorShift::from_time(),
+        }
+    }
+
+    /// Gross load after zeroing, as measured now.
+    fn gross(&mut self) -> f64 {
+        let raw = match &self.signal {
+            Signal::Model {
+                load,
+                amplitude,
+                period,
+                drift_per_day,
+                noise,
+            } => {
+                let elapsed = self.start.elapsed().as_secs_f64();
+                let phase = 2. * std::f64::consts::PI * elapsed / period.as_secs_f64();
+                load + amplitude * phase.sin()
+                    + drift_per_day * elapsed / 86400.
+                    + noise * self.rng.next_gaussian()
+            }
+            Signal::Replay(loads) => {
+                let l = loads[self.replay_index % loads.len()];
+                self.replay_index += 1;
+                l
+            }
+        };
+        raw - self.zero
+    }
+
+    fn frame(&self, kind: Kind, value: f64) -> Vec<u8> {
+        let reading = Reading {
+            kind,
+            status: Status::default(),
+            value,
+            decimals: self.decimals,
+        };
+        format!("{}\r\n", reading).into_bytes()
+    }
+
+    /// Answer a command line as received, without terminator.
+    pub fn reply(&mut self, line: &str) -> Vec<u8> {
+        let cmd = match line.parse::<Command>() {
+            Ok(cmd) => cmd,
+            Err(_) => return format!("{}\r\n", ERROR_FRAME).into_bytes(),
+        };
+        match cmd {
+            Command::Net => {
+                let net = self.gross() - self.tare;
+                self.frame(Kind::Net, net)
+            }
+            Command::Gross => {
+                let gross = self.gross();
+                self.frame(Kind::Gross, gross)
+            }
+            Command::Average => {
+                let n = match self.signal {
+                    Signal::Model { .. } => AVERAGE_READINGS,
+                    Signal::Replay(_) => 1,
+                };
+                let sum: f64 = (0..n).map(|_| self.gross()).sum();
+                self.frame(Kind::Average, sum / n as f64 - self.tare)
+            }
+            Command::GetTare => self.frame(Kind::Tare, self.tare),
+            Command::Status => b"S\r\n".to_vec(),
+            Command::Tare => {
+                self.tare = self.gross();
+                b"OK\r\n".to_vec()
+            }
+            Command::ClearTare => {
+                self.tare = 0.;
+                b"OK\r\n".to_vec()
+            }
+            Command::Zero => {
+                self.zero += self.gross();
+                b"OK\r\n".to_vec()
+            }
+            Command::Identify => b"DAD141.1 SIM\r\n".to_vec(),
+            Command::Version => format!("{}\r\n", VERSION.unwrap_or("unknown")).into_bytes(),
+        }
+    }
+
+    /// Answer a command line, replacing the reply with the fault if any.
+    pub fn reply_with_fault(&mut self, line: &str, fault: Option<Fault>) -> Reply {
+        let bytes = self.reply(line);
+        match fault {
+            None => Reply::Send(bytes),
+            Some(Fault::Delay(d)) => Reply::Delayed(d, bytes),
+            Some(Fault::Drop) => Reply::Drop,
+            Some(Fault::Garbage) => {
+                let mut garbage: Vec<u8> = (0..8).map(|_| 0x80 | self.rng.next_u8()).collect();
+                garbage.extend_from_slice(b"\r\n");
+                Reply::Send(garbage)
+            }
+            Some(Fault::Error) => Reply::Send(format!("{}\r\n", ERROR_FRAME).into_bytes()),
+        }
+    }
+
+    /// Draw a fault with the given probability, choosing uniformly among the faults.
+    pub fn draw_fault(&mut self, probability: f64, faults: &[Fault]) -> Option<Fault> {
+        if faults.is_empty() || self.rng.next_f64() >= probability {
+            return None;
+        }
+        let i = (self.rng.next_f64() * faults.len() as f64) as usize;
+        Some(faults[i.min(faults.len() - 1)])
+    }
+}
+
+/// Takes the CLI arguments to control the simulator.
+pub fn parse_cli_sim() -> (String, Signal, usize, f64, Vec<Fault>, bool) {
+    let arg_address = Arg::with_name("address")
+        .help("local address and port to listen on")
+        .short("a")
+        .long("address")
+        .takes_value(true)
+        .default_value("127.0.0.1:2323");
+    let arg_load = Arg::with_name("load")
+        .help("mean load of the signal model, in kg")
+        .long("load")
+        .takes_value(true)
+        .default_value("15000");
+    let arg_amplitude = Arg::with_name("amplitude")
+        .help("amplitude of the sine of the signal model, in kg")
+        .long("amplitude")
+        .takes_value(true)
+        .default_value("5");
+    let arg_period = Arg::with_name("period")
+        .help("period of the sine of the signal model, in minutes")
+        .long("period")
+        .takes_value(true)
+        .default_value("1440");
+    let arg_drift = Arg::with_name("drift")
+        .help("linear drift of the signal model, in kg per day")
+        .long("drift")
+        .allow_hyphen_values(true)
+        .takes_value(true)
+        .default_value("-10");
+    let arg_noise = Arg::with_name("noise")
+        .help("standard deviation of the noise of the signal model, in kg")
+        .long("noise")
+        .takes_value(true)
+        .default_value("0.5");
+    let arg_replay = Arg::with_name("replay")
+        .help("csv file with the load values to replay instead of the signal model")
+        .short("r")
+        .long("replay")
+        .takes_value(true);
+    let arg_decimals = Arg::with_name("decimals")
+        .help("number of decimals in the replies")
+        .long("decimals")
+        .takes_value(true)
+        .possible_values(&["0", "1", "2", "3"])
+        .default_value("1");
+    let arg_fault_prob = Arg::with_name("fault_prob")
+        .help("probability of injecting a fault in each reply")
+        .long("fault_prob")
+        .takes_value(true)
+        .default_value("0");
+    let arg_faults = Arg::with_name("faults")
+        .help("faults to inject randomly: delay[:seconds], drop, garbage, error")
+        .long_help(
+            "faults to inject randomly: delay[:seconds], drop, garbage, error; \
+            faults can also be injected on demand by typing them on stdin, \
+            they then apply to the next reply",
+        )
+        .long("faults")
+        .takes_value(true)
+        .multiple(true)
+        .use_delimiter(true)
+        .default_value("delay,drop,garbage,error");
+    let arg_verbose = Arg::with_name("verbose")
+        .help("print verbose information")
+        .short("v")
+        .long("verbose")
+        .takes_value(false)
+        .required(false);
+    let cli_args = App::new("Flintec_sim")
+        .version(VERSION.unwrap_or("unknown"))
+        .author("Luca Peruzzo")
+        .about("cli app to simulate the DAD 141.1 amplifier over tcp")
+        .arg(arg_address)
+        .arg(arg_load)
+        .arg(arg_amplitude)
+        .arg(arg_period)
+        .arg(arg_drift)
+        .arg(arg_noise)
+        .arg(arg_replay)
+        .arg(arg_decimals)
+        .arg(arg_fault_prob)
+        .arg(arg_faults)
+        .arg(arg_verbose)
+        .get_matches();
+    let val_address = String::from(cli_args.value_of("address").unwrap_or_default());
+    let parse_f64 = |name: &str| -> f64 {
+        cli_args
+            .value_of(name)
+            .unwrap_or_default()
+            .parse::<f64>()
+            .unwrap()
+    };
+    let val_signal = match cli_args.value_of("replay") {
+        Some(f) => Signal::from_csv(PathBuf::from(f), 999994.),
+        None => Signal::Model {
+            load: parse_f64("load"),
+            amplitude: parse_f64("amplitude"),
+            period: Duration::from_secs_f64(parse_f64("period") * 60.),
+            drift_per_day: parse_f64("drift"),
+            noise: parse_f64("noise"),
+        },
+    };
+    let val_decimals = cli_args
+        .value_of("decimals")
+        .unwrap_or_default()
+        .parse::<usize>()
+        .unwrap();
+    let val_fault_prob = parse_f64("fault_prob");
+    let val_faults: Vec<Fault> = cli_args
+        .values_of("faults")
+        .unwrap_or_default()
+        .map(|f| f.parse::<Fault>().unwrap())
+        .collect();
+    let val_verbose: bool = cli_args.is_present("verbose");
+    (
+        val_address,
+        val_signal,
+        val_decimals,
+        val_fault_prob,
+        val_faults,
+        val_verbose,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_parse_as_readings() {
+        let signal = Signal::Model {
+            load: 15000.,
+            amplitude: 5.,
+            period: Duration::from_secs(86400),
+            drift_per_day: 0.,
+            noise: 0.5,
+        };
+        let mut sim = Simulator::new(signal, 1);
+        for cmd in ["GN", "gg", "GA", "GT"].iter() {
+            let reply = String::from_utf8(sim.reply(cmd)).unwrap();
+            let reading = Reading::parse(&reply).unwrap();
+            assert!((reading.value - 15000.).abs() < 10. || reading.kind == Kind::Tare);
+        }
+        assert_eq!(sim.reply("XX"), b"ERR\r\n".to_vec());
+        assert_eq!(sim.reply_with_fault("GN", Some(Fault::Drop)), Reply::Drop);
+    }
+}
@@ -0,0 +1,444 @@
+//! Storage of the readings written by the logger.
+//!
+//! The logger writes each reading the same way to any number of sinks: its csv files,
+//! through the rotation, spool, and hash chain, the MQTT broker or, with the sqlite feature,
+//! a SQLite database that `TimeLoad::from_sqlite` reads back for processing and plotting.
+use super::burst::is_error_code;
+use super::events::EventLog;
+use super::log::SyncWrite;
+use chrono::prelude::*;
+use std::io::{Error, Result};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use std::time::{Duration, Instant};
+
+/// Columns of the acquisition times, after the statistics columns in burst mode.
+pub const ACQUISITION_HEADER: &str = "sent_time,received_time,latency_s";
+
+/// Wall-clock times of the command and of the reply of one reading,
+/// from the first command to the last reply in burst mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Acquisition {
+    /// Time when the command was sent, none if it could not be sent or the reading was skipped.
+    pub sent: Option<DateTime<Local>>,
+    /// Time when the reply arrived, none without reply.
+    pub received: Option<DateTime<Local>>,
+}
+
+impl Acquisition {
+    /// Time from the command to the reply, including the pause before reading the reply.
+    pub fn latency(&self) -> Option<chrono::Duration> {
+        match (self.sent, self.received) {
+            (Some(s), Some(r)) => Some(r - s),
+            _ => None,
+        }
+    }
+
+    /// Acquisition columns with a leading comma, empty for the missing times.
+    pub fn to_csv(&self) -> String {
+        let time = |t: Option<DateTime<Local>>| {
+            t.map_or(String::new(), |t| {
+                t.to_rfc3339_opts(SecondsFormat::Millis, false)
+            })
+        };
+        let latency = self.latency().map_or(String::new(), |l| {
+            format!("{:.3}", l.num_milliseconds() as f64 / 1000.)
+        });
+        format!(",{},{},{}", time(self.sent), time(self.received), latency)
+    }
+}
+
+/// Reading of one device at one scheduled time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub device: String,
+    /// Scheduled time, with the offset of the time zone of the logger.
+    pub scheduled: DateTime<FixedOffset>,
+    /// Times of the command and of the reply.
+    pub acquisition: Acquisition,
+    pub load: f64,
+    pub raw_reading: String,
+    /// Statistics columns in burst mode, with a leading comma, or empty.
+    pub stats: String,
+}
+
+impl Record {
+    /// Error code written in place of the load, none for valid readings.
+    pub fn error_code(&self) -> Option<i64> {
+        if is_error_code(self.load) {
+            Some(self.load as i64)
+        } else {
+            None
+        }
+    }
+
+    /// Load, raw reading, and statistics columns with a leading comma,
+    /// the columns of the device in the wide csv file.
+    pub fn to_csv_columns(&self) -> String {
+        format!(",{},{}{}", self.load, self.raw_reading, self.stats)
+    }
+}
+
+/// Destination of the readings.
+pub trait Sink: Send {
+    /// Name of the file or database, for the messages.
+    fn name(&self) -> &str;
+
+    /// Write the reading.
+    fn write(&mut self, record: &Record) -> Result<()>;
+
+    /// Write the readings of all the devices at the same scheduled time,
+    /// by default each one, returning the last error.
+    fn write_all(&mut self, records: &[Record]) -> Result<()> {
+        let mut written = Ok(());
+        for record in records.iter() {
+            if let Err(e) = self.write(record) {
+                written = Err(e);
+            }
+        }
+        written
+    }
+
+    /// Flush the readings to the disk.
+    fn sync(&mut self) -> Result<()>;
+}
+
+impl<S: Sink + ?Sized> Sink for Box<S> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn write(&mut self, record: &Record) -> Result<()> {
+        (**self).write(record)
+    }
+
+    fn write_all(&mut self, records: &[Record]) -> Result<()> {
+        (**self).write_all(records)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        (**self).sync()
+    }
+}
+
+/// Sink shared with another thread, e.g., to flush it when forced to exit.
+#[derive(Clone)]
+pub struct SharedSink {
+    name: String,
+    sink: Arc<Mutex<Box<dyn Sink>>>,
+}
+
+impl SharedSink {
+    pub fn new(sink: Box<dyn Sink>) -> SharedSink {
+        SharedSink {
+            name: String::from(sink.name()),
+            sink: Arc::new(Mutex::new(sink)),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Box<dyn Sink>> {
+        self.sink.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Flush the readings to the disk, unless the sink is still busy after the timeout,
+    /// e.g., writing to a disk that hangs.
+    pub fn try_sync(&self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        loop {
+            match self.sink.try_lock() {
+                Ok(mut sink) => return sink.sync(),
+                Err(TryLockError::Poisoned(e)) => return e.into_inner().sync(),
+                Err(TryLockError::WouldBlock) if start.elapsed() < timeout => {
+                    std::thread::sleep(Duration::from_millis(10))
+                }
+                Err(TryLockError::WouldBlock) => {
+                    return Err(Error::other(format!("still busy after {:?}", timeout)))
+                }
+            }
+        }
+    }
+}
+
+impl Sink for SharedSink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, record: &Record) -> Result<()> {
+        self.lock().write(record)
+    }
+
+    fn write_all(&mut self, records: &[Record]) -> Result<()> {
+        self.lock().write_all(records)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.lock().sync()
+    }
+}
+
+/// Csv file of the logger, one row per scheduled time with the columns of its device,
+/// or with the columns of all the devices in the wide format.
+pub struct CsvSink<W: SyncWrite> {
+    pub csvfile: W,
+    name: String,
+    /// Device of the csv file, none to write all the devices.
+    pub device: Option<String>,
+    /// Write the acquisition columns after the columns of each device.
+    pub acquisition_times: bool,
+    /// Event log of the failed rotations.
+    pub events: EventLog,
+}
+
+impl<W: SyncWrite> CsvSink<W> {
+    pub fn new(csvfile: W, name: &str) -> CsvSink<W> {
+        CsvSink {
+            csvfile,
+            name: String::from(name),
+            device: None,
+            acquisition_times: false,
+            events: EventLog::disabled(),
+        }
+    }
+
+    /// Row of the records of the device, without the newline, none if there are none.
+    pub fn to_row(&self, records: &[Record]) -> Option<String> {
+        let mut records = records
+            .iter()
+            .filter(|r| self.device.as_ref().is_none_or(|d| *d == r.device))
+            .peekable();
+        let mut row = records
+            .peek()?
+            .scheduled
+            .to_rfc3339_opts(SecondsFormat::Secs, false);
+        for record in records {
+            row.push_str(&record.to_csv_columns());
+            if self.acquisition_times {
+                row.push_str(&record.acquisition.to_csv());
+            }
+        }
+        Some(row)
+    }
+
+    /// Switch to the file of the scheduled time, if rotating,
+    /// keep writing to the current file in case of errors.
+    fn rotate(&mut self, dtr: DateTime<Local>) {
+        if let Err(e) = self.csvfile.rotate(dtr) {
+            println!(
+                "could not rotate csvfile {} at {}, error {}",
+                self.name,
+                dtr.to_rfc3339_opts(SecondsFormat::Secs, false),
+                e
+            );
+            self.events.error(
+                "rotate_failed",
+                &[
+                    ("file", self.name.as_str().into()),
+                    ("datetime", dtr.into()),
+                    ("error", e.to_string().into()),
+                ],
+            );
+        }
+    }
+}
+
+impl<W: SyncWrite + Send> Sink for CsvSink<W> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn write(&mut self, record: &Record) -> Result<()> {
+        self.write_all(std::slice::from_ref(record))
+    }
+
+    /// Write one row with the records of the device, or of all the devices.
+    fn write_all(&mut self, records: &[Record]) -> Result<()> {
+        let row = match self.to_row(records) {
+            Some(row) => row,
+            None => return Ok(()),
+        };
+        self.rotate(records[0].scheduled.with_timezone(&Local));
+        self.csvfile.write_row(&row)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.csvfile.sync()
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteSink;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use super::{Record, Sink};
+    use crate::TimeLoad;
+    use chrono::prelude::*;
+    use rusqlite::{params, Connection};
+    use std::io::{Error, Result};
+    use std::path::Path;
+
+    /// One row per device and scheduled time, the load is null for error codes.
+    const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS readings (
+        device TEXT NOT NULL,
+        scheduled TEXT NOT NULL,
+        scheduled_unix INTEGER NOT NULL,
+        read_time TEXT,
+        load_kg REAL,
+        raw_reading TEXT NOT NULL,
+        error_code INTEGER,
+        PRIMARY KEY (device, scheduled_unix)
+    )";
+
+    /// SQLite database of the readings of all the devices.
+    pub struct SqliteSink {
+        connection: Connection,
+        name: String,
+    }
+
+    impl SqliteSink {
+        /// Open the database, creating the readings table if needed.
+        pub fn open(path: &str) -> Result<SqliteSink> {
+            let connection = Connection::open(path).map_err(Error::other)?;
+            connection
+                .execute_batch(&format!("PRAGMA journal_mode = WAL; {};", SCHEMA))
+                .map_err(Error::other)?;
+            Ok(SqliteSink {
+                connection,
+                name: String::from(path),
+            })
+        }
+    }
+
+    impl Sink for SqliteSink {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        /// Insert the reading, replacing the previous one of the device at the same scheduled time.
+        fn write(&mut self, record: &Record) -> Result<()> {
+            let error_code = record.error_code();
+            self.connection
+                .execute(
+                    "INSERT OR REPLACE INTO readings
+                    (device, scheduled, scheduled_unix, read_time, load_kg, raw_reading, error_code)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        record.device,
+                        record.scheduled.to_rfc3339_opts(SecondsFormat::Secs, false),
+                        record.scheduled.timestamp(),
+                        record
+                            .acquisition
+                            .received
+                            .map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, false)),
+                        error_code.map_or(Some(record.load), |_| None),
+                        record.raw_reading,
+                        error_code,
+                    ],
+                )
+                .map(|_| ())
+                .map_err(Error::other)
+        }
+
+        /// Each insert is committed, nothing to flush.
+        fn sync(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TimeLoad {
+        /// Initiate a TimeLoad from the readings of the device in the SQLite database,
+        /// optionally from and to the given datetimes, included.
+        /// Error codes are loaded as load, as from the csv files, to be checked afterwards.
+        pub fn from_sqlite<P>(
+            fin: P,
+            device: &str,
+            from: Option<DateTime<FixedOffset>>,
+            to: Option<DateTime<FixedOffset>>,
+        ) -> TimeLoad
+        where
+            P: AsRef<Path>,
+        {
+            let connection = Connection::open(fin).unwrap();
+            let mut statement = connection
+                .prepare(
+                    "SELECT scheduled, COALESCE(load_kg, error_code) FROM readings
+                    WHERE device = ?1 AND scheduled_unix >= ?2 AND scheduled_unix <= ?3
+                    ORDER BY scheduled_unix",
+                )
+                .unwrap();
+            let from = from.map_or(i64::MIN, |f| f.timestamp());
+            let to = to.map_or(i64::MAX, |t| t.timestamp());
+            let rows = statement
+                .query_map(params![device, from, to], |r| {
+                    Ok((r.get::<_, String>(0)?, r.get::<_, Option<f64>>(1)?))
+                })
+                .unwrap();
+            let mut timeload = TimeLoad::new(10000);
+            for row in rows {
+                let (datetime, load) = match row {
+                    Ok(r) => r,
+                    Err(e) => {
+                        println!("Err, could not read row {}", e);
+                        continue;
+                    }
+                };
+                let parsed_datetime = match DateTime::parse_from_rfc3339(&datetime) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        println!("Could not parse datetime: {}, error {}", datetime, e);
+                        continue;
+                    }
+                };
+                timeload.time.push(parsed_datetime);
+                timeload.load.push(load.unwrap_or(f64::NAN));
+            }
+            timeload
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::ERROR_FLT_NONE;
+
+        #[test]
+        fn writes_and_loads_readings() {
+            let name = std::env::temp_dir().join("flintec_lpp_sink.sqlite");
+            let name = name.to_str().unwrap();
+            let _ = std::fs::remove_file(name);
+            let mut sink = SqliteSink::open(name).unwrap();
+            let start = Local.with_ymd_and_hms(2021, 6, 1, 10, 0, 0).unwrap();
+            for (i, (device, load)) in [("dad", 1234.5), ("mum", 1.), ("dad", ERROR_FLT_NONE)]
+                .iter()
+                .enumerate()
+            {
+                let record = Record {
+                    device: String::from(*device),
+                    scheduled: (start + chrono::Duration::minutes(i as i64)).fixed_offset(),
+                    acquisition: Default::default(),
+                    load: *load,
+                    raw_reading: String::from("N +01234.5"),
+                    stats: String::new(),
+                };
+                sink.write(&record).unwrap();
+            }
+            let from = Some(start.fixed_offset());
+            let dad = TimeLoad::from_sqlite(name, "dad", from, None);
+            assert_eq!(dad.load, vec![1234.5, ERROR_FLT_NONE]);
+            assert_eq!(
+                dad.time[1],
+                (start + chrono::Duration::minutes(2)).fixed_offset()
+            );
+            let later = Some((start + chrono::Duration::minutes(1)).fixed_offset());
+            assert_eq!(
+                TimeLoad::from_sqlite(name, "dad", later, None).load.len(),
+                1
+            );
+            drop(sink);
+            std::fs::remove_file(name).unwrap();
+            for suffix in ["-wal", "-shm"].iter() {
+                let _ = std::fs::remove_file(format!("{}{}", name, suffix));
+            }
+        }
+    }
+}
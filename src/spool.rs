@@ -0,0 +1,284 @@
+//! Rows kept in memory while the csv file cannot be written, e.g., when the SD card is full.
+//!
+//! The rows that could not be written are queued, and copied to a fallback file if any,
+//! then written in order to the csv file as soon as writing works again.
+use super::events::EventLog;
+use super::log::{write_line, SyncWrite};
+use chrono::prelude::*;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Result, Write};
+
+/// Csv file that keeps the rows it cannot write, up to a limit, and writes them later in order.
+pub struct Spool<W: SyncWrite> {
+    inner: W,
+    name: String,
+    header: String,
+    /// Longest queue, the oldest rows are lost beyond.
+    pub max_rows: usize,
+    /// File where the queued rows are also written, in case the logger stops before writing them.
+    pub fallback: Option<String>,
+    pub events: EventLog,
+    /// Scheduled time and row, without the newline.
+    queue: VecDeque<(Option<DateTime<Local>>, String)>,
+    line: Vec<u8>,
+    dtr: Option<DateTime<Local>>,
+    fallback_file: Option<File>,
+    /// Rows queued and rows lost since the csv file could not be written.
+    delayed: usize,
+    lost: usize,
+}
+
+impl<W: SyncWrite> Spool<W> {
+    /// Wrap the csv file with the given name and header, the header is written to the fallback file.
+    pub fn new(inner: W, name: &str, header: &str) -> Spool<W> {
+        Spool {
+            inner,
+            name: String::from(name),
+            header: String::from(header),
+            max_rows: 10000,
+            fallback: None,
+            events: EventLog::disabled(),
+            queue: VecDeque::new(),
+            line: Vec::new(),
+            dtr: None,
+            fallback_file: None,
+            delayed: 0,
+            lost: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Rows waiting to be written.
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Queue the row and write the queue, losing the oldest row if the queue is too long.
+    fn push(&mut self, row: String) {
+        self.queue.push_back((self.dtr, row));
+        if let Err(e) = self.write_queue() {
+            self.delay(e);
+            if self.queue.len() > self.max_rows.max(1) {
+                self.queue.pop_front();
+                if self.lost == 0 {
+                    println!(
+                        "warning, more than {} rows not written to {}, losing the oldest ones",
+                        self.max_rows, self.name
+                    );
+                }
+                self.lost += 1;
+            }
+        }
+    }
+
+    /// Write the queued rows in order, stopping at the first error.
+    fn write_queue(&mut self) -> Result<()> {
+        while let Some((dtr, row)) = self.queue.front().cloned() {
+            let written = match dtr {
+                Some(d) => self.inner.rotate(d),
+                None => Ok(()),
+            }
+            .and_then(|_| self.inner.write_row(&row));
+            match written {
+                Ok(_) => {
+                    self.queue.pop_front();
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        if self.delayed > 0 {
+            println!(
+                "writing to {} again, {} row(s) delayed, {} lost",
+                self.name, self.delayed, self.lost
+            );
+            self.events.info(
+                "write_resumed",
+                &[
+                    ("file", self.name.as_str().into()),
+                    ("delayed", self.delayed.into()),
+                    ("lost", self.lost.into()),
+                ],
+            );
+            self.delayed = 0;
+            self.lost = 0;
+        }
+        Ok(())
+    }
+
+    /// Count the last queued row as delayed and copy it to the fallback file.
+    fn delay(&mut self, e: Error) {
+        let copied = self.fallback_write();
+        self.delayed += 1;
+        if self.delayed == 1 {
+            println!(
+                "could not write to {}, error {}, keeping the rows in memory{}",
+                self.name,
+                e,
+                match (&self.fallback, copied) {
+                    (Some(f), true) => format!(" and in {}", f),
+                    _ => String::new(),
+                }
+            );
+            self.events.error(
+                "write_delayed",
+                &[
+                    ("file", self.name.as_str().into()),
+                    ("error", e.to_string().into()),
+                    ("fallback", copied.into()),
+                ],
+            );
+        }
+    }
+
+    /// Append the last queued row to the fallback file, returning true if written.
+    fn fallback_write(&mut self) -> bool {
+        let fallback = match &self.fallback {
+            Some(f) => f,
+            None => return false,
+        };
+        if self.fallback_file.is_none() {
+            let exists = std::path::Path::new(fallback).exists();
+            let opened = OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(fallback)
+                .and_then(|mut f| {
+                    if !exists {
+                        f.write_all(self.header.as_bytes())?;
+                    }
+                    Ok(f)
+                });
+            match opened {
+                Ok(f) => self.fallback_file = Some(f),
+                Err(e) => {
+                    println!("could not open fallback file {}, error {}", fallback, e);
+                    return false;
+                }
+            }
+        }
+        let row = &self.queue.back().unwrap().1;
+        let file = self.fallback_file.as_mut().unwrap();
+        write_line(file, row).and_then(|_| file.sync_data()).is_ok()
+    }
+}
+
+impl<W: SyncWrite> Write for Spool<W> {
+    /// Take the bytes of the rows, queue each complete row and write the queue.
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.line.extend_from_slice(buf);
+        while let Some(end) = self.line.iter().position(|b| *b == b'\n') {
+            let row: Vec<u8> = self.line.drain(..=end).collect();
+            let row = String::from_utf8_lossy(&row[..end]).into_owned();
+            self.push(row);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.write_queue()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: SyncWrite> SyncWrite for Spool<W> {
+    /// Write the queue and sync the csv file, failing if rows are still queued.
+    fn sync(&mut self) -> Result<()> {
+        if self.write_queue().is_err() {
+            self.events.error(
+                "rows_not_written",
+                &[
+                    ("file", self.name.as_str().into()),
+                    ("count", self.queue.len().into()),
+                    ("lost", self.lost.into()),
+                    ("fallback", self.fallback_file.is_some().into()),
+                ],
+            );
+            return Err(Error::other(format!(
+                "{} row(s) not written{}",
+                self.queue.len(),
+                match &self.fallback {
+                    Some(f) if self.fallback_file.is_some() => format!(", kept in {}", f),
+                    _ => String::new(),
+                }
+            )));
+        }
+        self.inner.sync()
+    }
+
+    /// Keep the scheduled time to rotate before writing the row.
+    fn rotate(&mut self, dtr: DateTime<Local>) -> Result<()> {
+        self.dtr = Some(dtr);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::ErrorKind;
+
+    /// Output that fails while full, after writing part of the row.
+    #[derive(Default)]
+    struct Disk {
+        rows: Vec<u8>,
+        full: bool,
+    }
+
+    impl Write for Disk {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            if self.full && buf.len() < 2 {
+                return Err(Error::new(ErrorKind::StorageFull, "no space left"));
+            }
+            let part = if self.full { buf.len() / 2 } else { buf.len() };
+            self.rows.write(&buf[..part])
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SyncWrite for Disk {
+        fn sync(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        fn write_row(&mut self, row: &str) -> Result<()> {
+            let len = self.rows.len();
+            write_line(self, row).inspect_err(|_| {
+                self.rows.truncate(len);
+            })
+        }
+    }
+
+    #[test]
+    fn writes_the_queued_rows_in_order() {
+        let fallback = std::env::temp_dir().join("flintec_lpp_spool_fallback.csv");
+        let fallback = fallback.to_str().unwrap();
+        let _ = std::fs::remove_file(fallback);
+        let mut spool = Spool::new(Disk::default(), "disk.csv", "datetime,load_kg\n");
+        spool.max_rows = 3;
+        spool.fallback = Some(String::from(fallback));
+        writeln!(spool, "1,1").unwrap();
+        spool.inner.full = true;
+        for i in 2..6 {
+            writeln!(spool, "{},{}", i, i).unwrap();
+        }
+        assert_eq!(spool.queued(), 3);
+        assert!(spool.sync().is_err());
+        spool.inner.full = false;
+        write!(spool, "6,").unwrap();
+        writeln!(spool, "6").unwrap();
+        spool.sync().unwrap();
+        // the oldest queued row is lost, the fallback file has all the queued ones
+        let rows = String::from_utf8(spool.get_ref().rows.clone()).unwrap();
+        assert_eq!(rows, "1,1\n3,3\n4,4\n5,5\n6,6\n");
+        let copied = std::fs::read_to_string(fallback).unwrap();
+        std::fs::remove_file(fallback).unwrap();
+        assert_eq!(copied, "datetime,load_kg\n2,2\n3,3\n4,4\n5,5\n");
+    }
+}
@@ -0,0 +1,360 @@
+//! Status of the running logger, served over a small local HTTP endpoint.
+//!
+//! `GET /status` returns the last reading, the last valid reading time, the connection state,
+//! and the counts of each error code of every device, with the uptime, as JSON;
+//! `GET /metrics` returns the same in the Prometheus text format.
+use super::burst::is_error_code;
+use super::events::json_string;
+use super::{
+    ERROR_FLT_GENERAL, ERROR_FLT_INVALID, ERROR_FLT_NONE, ERROR_FLT_PARSE, ERROR_FLT_SKIPPED,
+};
+use chrono::prelude::*;
+use std::io::{BufRead, BufReader, Result, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Error codes, with their names in the status and metrics.
+const ERROR_CODES: [(&str, f64); 5] = [
+    ("general", ERROR_FLT_GENERAL),
+    ("none", ERROR_FLT_NONE),
+    ("invalid", ERROR_FLT_INVALID),
+    ("skipped", ERROR_FLT_SKIPPED),
+    ("parse", ERROR_FLT_PARSE),
+];
+
+/// Status of one device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceStatus {
+    pub name: String,
+    pub connected: bool,
+    /// Scheduled time, load, and raw reading of the last row, errors included.
+    pub last: Option<(DateTime<Local>, f64, String)>,
+    /// Scheduled time of the last valid reading.
+    pub last_valid: Option<DateTime<Local>>,
+    pub readings: u64,
+    /// Count of each error code, in the order of the error codes.
+    pub errors: [u64; 5],
+}
+
+impl DeviceStatus {
+    fn new(name: &str) -> DeviceStatus {
+        DeviceStatus {
+            name: String::from(name),
+            connected: true,
+            last: None,
+            last_valid: None,
+            readings: 0,
+            errors: [0; 5],
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Board {
+    started: DateTime<Local>,
+    devices: Vec<DeviceStatus>,
+}
+
+/// Status of the devices, shared by the logger threads and the HTTP server.
+/// The disabled monitor, the default, discards the updates.
+#[derive(Debug, Clone, Default)]
+pub struct Monitor {
+    board: Option<Arc<Mutex<Board>>>,
+    device: usize,
+}
+
+impl Monitor {
+    /// Monitor of the devices, in the order of the names.
+    pub fn new(names: &[String], started: DateTime<Local>) -> Monitor {
+        let devices = names.iter().map(|n| DeviceStatus::new(n)).collect();
+        Monitor {
+            board: Some(Arc::new(Mutex::new(Board { started, devices }))),
+            device: 0,
+        }
+    }
+
+    /// Monitor that discards the updates.
+    pub fn disabled() -> Monitor {
+        Monitor::default()
+    }
+
+    /// Same monitor, updating the device with the index.
+    pub fn for_device(&self, device: usize) -> Monitor {
+        Monitor {
+            board: self.board.clone(),
+            device,
+        }
+    }
+
+    fn update<F: FnOnce(&mut DeviceStatus)>(&self, f: F) {
+        if let Some(board) = &self.board {
+            if let Some(d) = board.lock().unwrap().devices.get_mut(self.device) {
+                f(d);
+            }
+        }
+    }
+
+    /// Count the reading written for the scheduled time.
+    pub fn reading(&self, dtr: DateTime<Local>, w: f64, raw_reading: &str) {
+        self.update(|d| {
+            d.readings += 1;
+            match ERROR_CODES.iter().position(|(_, code)| *code == w) {
+                Some(i) => d.errors[i] += 1,
+                None => d.last_valid = Some(dtr),
+            }
+            d.last = Some((dtr, w, String::from(raw_reading)));
+        });
+    }
+
+    /// Set the connection state.
+    pub fn connection(&self, connected: bool) {
+        self.update(|d| d.connected = connected);
+    }
+
+    /// Copy of the status of the devices.
+    pub fn devices(&self) -> Vec<DeviceStatus> {
+        match &self.board {
+            Some(board) => board.lock().unwrap().devices.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    fn uptime(&self, now: DateTime<Local>) -> i64 {
+        match &self.board {
+            Some(board) => (now - board.lock().unwrap().started).num_seconds(),
+            None => 0,
+        }
+    }
+
+    /// Status as a JSON object.
+    pub fn to_json(&self, now: DateTime<Local>) -> String {
+        let datetime = |d: &Option<DateTime<Local>>| match d {
+            Some(d) => json_string(&d.to_rfc3339_opts(SecondsFormat::Secs, false)),
+            None => String::from("null"),
+        };
+        let devices: Vec<String> = self
+            .devices()
+            .iter()
+            .map(|d| {
+                let (last_datetime, last_load, last_raw) = match &d.last {
+                    Some((dtr, w, raw)) => (datetime(&Some(*dtr)), w.to_string(), json_string(raw)),
+                    None => (String::from("null"), String::from("null"), String::from("null")),
+                };
+                let errors: Vec<String> = ERROR_CODES
+                    .iter()
+                    .zip(d.errors.iter())
+                    .map(|((name, _), count)| format!("\"{}\":{}", name, count))
+                    .collect();
+                format!(
+                    "{{\"name\":{},\"connected\":{},\"last_datetime\":{},\"last_load_kg\":{},\
+                    \"last_raw_reading\":{},\"last_valid_datetime\":{},\"readings\":{},\"errors\":{{{}}}}}",
+                    json_string(&d.name),
+                    d.connected,
+                    last_datetime,
+                    last_load,
+                    last_raw,
+                    datetime(&d.last_valid),
+                    d.readings,
+                    errors.join(",")
+                )
+            })
+            .collect();
+        format!(
+            "{{\"uptime_s\":{},\"devices\":[{}]}}",
+            self.uptime(now),
+            devices.join(",")
+        )
+    }
+
+    /// Status in the Prometheus text format.
+    pub fn to_prometheus(&self, now: DateTime<Local>) -> String {
+        let devices = self.devices();
+        let mut page = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            page.push_str(&format!(
+                "# HELP {} {}\n# TYPE {} {}\n",
+                name, help, name, kind
+            ));
+            for (labels, value) in samples {
+                page.push_str(&format!("{}{} {}\n", name, labels, value));
+            }
+        };
+        let label = |d: &DeviceStatus| format!("{{device=\"{}\"}}", prometheus_label(&d.name));
+        metric(
+            "flintec_uptime_seconds",
+            "gauge",
+            "Time since the logger started.",
+            vec![(String::new(), self.uptime(now).to_string())],
+        );
+        metric(
+            "flintec_connected",
+            "gauge",
+            "1 if the connection to the amplifier is ok.",
+            devices
+                .iter()
+                .map(|d| (label(d), (d.connected as u8).to_string()))
+                .collect(),
+        );
+        metric(
+            "flintec_load_kg",
+            "gauge",
+            "Load of the last reading, absent after an error code.",
+            devices
+                .iter()
+                .filter_map(|d| match &d.last {
+                    Some((_, w, _)) if !is_error_code(*w) => Some((label(d), w.to_string())),
+                    _ => None,
+                })
+                .collect(),
+        );
+        metric(
+            "flintec_last_valid_timestamp_seconds",
+            "gauge",
+            "Scheduled time of the last valid reading, as unix time.",
+            devices
+                .iter()
+                .filter_map(|d| d.last_valid.map(|t| (label(d), t.timestamp().to_string())))
+                .collect(),
+        );
+        metric(
+            "flintec_readings_total",
+            "counter",
+            "Readings written, errors included.",
+            devices
+                .iter()
+                .map(|d| (label(d), d.readings.to_string()))
+                .collect(),
+        );
+        let mut errors = Vec::new();
+        for d in devices.iter() {
+            for ((code, _), count) in ERROR_CODES.iter().zip(d.errors.iter()) {
+                errors.push((
+                    format!(
+                        "{{device=\"{}\",code=\"{}\"}}",
+                        prometheus_label(&d.name),
+                        code
+                    ),
+                    count.to_string(),
+                ));
+            }
+        }
+        metric(
+            "flintec_errors_total",
+            "counter",
+            "Readings written as error codes, by code.",
+            errors,
+        );
+        page
+    }
+}
+
+/// Escape the label value for the Prometheus text format.
+fn prometheus_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Status code, content type, and body of the response to the path.
+pub fn route(
+    path: &str,
+    monitor: &Monitor,
+    now: DateTime<Local>,
+) -> (&'static str, &'static str, String) {
+    match path {
+        "/" | "/status" => ("200 OK", "application/json", monitor.to_json(now)),
+        "/metrics" => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            monitor.to_prometheus(now),
+        ),
+        _ => ("404 Not Found", "text/plain", String::from("not found\n")),
+    }
+}
+
+/// Serve the status on the address, in a background thread, one request at a time.
+pub fn serve(address: SocketAddr, monitor: Monitor) -> Result<()> {
+    let listener = TcpListener::bind(address)?;
+    println!(
+        "serving the status on http://{}/status and /metrics",
+        address
+    );
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let handled = stream.and_then(|s| respond(s, &monitor));
+            if let Err(e) = handled {
+                println!("could not answer the status request, error {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Answer the GET request with the status, reading the request line and the headers.
+fn respond(stream: TcpStream, monitor: &Monitor) -> Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+    let mut parts = request.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => route(path, monitor, Local::now()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            String::from("only GET is supported\n"),
+        ),
+    };
+    let mut stream = reader.into_inner();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_status_and_metrics() {
+        let started = Local.with_ymd_and_hms(2021, 6, 1, 10, 0, 0).unwrap();
+        let names = vec![String::from("dad"), String::from("mum")];
+        let monitor = Monitor::new(&names, started);
+        let dad = monitor.for_device(0);
+        let dtr = started + chrono::Duration::minutes(1);
+        dad.reading(dtr, 1234.5, "N +01234.5");
+        dad.reading(
+            dtr + chrono::Duration::minutes(1),
+            ERROR_FLT_NONE,
+            "E+999998.",
+        );
+        dad.connection(false);
+        let now = started + chrono::Duration::minutes(3);
+        let json = monitor.to_json(now);
+        assert!(json
+            .starts_with("{\"uptime_s\":180,\"devices\":[{\"name\":\"dad\",\"connected\":false,"));
+        assert!(json.contains("\"last_load_kg\":999998,"));
+        assert!(json.contains(&format!(
+            "\"last_valid_datetime\":\"{}\",\"readings\":2,\"errors\":{{\"general\":0,\"none\":1,",
+            dtr.to_rfc3339_opts(SecondsFormat::Secs, false)
+        )));
+        let metrics = monitor.to_prometheus(now);
+        assert!(metrics.contains("flintec_connected{device=\"dad\"} 0\n"));
+        assert!(metrics.contains("flintec_connected{device=\"mum\"} 1\n"));
+        assert!(metrics.contains("flintec_errors_total{device=\"dad\",code=\"none\"} 1\n"));
+        assert!(!metrics.contains("flintec_load_kg{device=\"dad\"}"));
+        assert_eq!(route("/nothing", &monitor, now).0, "404 Not Found");
+    }
+}
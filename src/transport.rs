@@ -0,0 +1,297 @@
+use serialport::{DataBits, Parity, SerialPort, StopBits};
+use std::io::{Read, Result, Write};
+use std::net::{SocketAddrV4, TcpStream};
+use std::time::Duration;
+
+/// Connection to the amplifier, used by the logger to send commands and read replies.
+pub trait Transport: Read + Write {
+    /// Refresh the connection, e.g., after an IO error or a missing reply.
+    fn reconnect(&mut self) -> Result<()>;
+
+    /// Change the read timeout, e.g., to drain stale bytes without waiting for the full timeout.
+    fn set_timeout(&mut self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn reconnect(&mut self) -> Result<()> {
+        (**self).reconnect()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        (**self).set_timeout(timeout)
+    }
+}
+
+/// Where the amplifier is connected, as selected from the CLI.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Tcp(SocketAddrV4),
+    Serial(SerialSettings),
+}
+
+impl Endpoint {
+    /// Open the connection with the given timeout for reading and writing.
+    pub fn connect(&self, timeout: Duration) -> Result<Box<dyn Transport + Send>> {
+        match self {
+            Endpoint::Tcp(socket) => Ok(Box::new(TcpTransport::connect(*socket, timeout)?)),
+            Endpoint::Serial(settings) => {
+                Ok(Box::new(SerialTransport::open(settings.clone(), timeout)?))
+            }
+        }
+    }
+}
+
+/// Transport that opens the endpoint on each reconnection,
+/// so that an amplifier that is not reachable at start is retried like a lost connection.
+pub struct LazyTransport {
+    endpoint: Endpoint,
+    timeout: Duration,
+    inner: Option<Box<dyn Transport + Send>>,
+}
+
+impl LazyTransport {
+    /// Try to connect once, keeping the transport disconnected in case of errors.
+    pub fn connect(endpoint: Endpoint, timeout: Duration) -> LazyTransport {
+        let inner = match endpoint.connect(timeout) {
+            Ok(t) => {
+                println!("connected to {}", endpoint);
+                Some(t)
+            }
+            Err(e) => {
+                println!("could not connect to {}, error {}", endpoint, e);
+                None
+            }
+        };
+        LazyTransport {
+            endpoint,
+            timeout,
+            inner,
+        }
+    }
+
+    fn inner(&mut self) -> Result<&mut Box<dyn Transport + Send>> {
+        self.inner
+            .as_mut()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotConnected, "not connected"))
+    }
+}
+
+impl Transport for LazyTransport {
+    fn reconnect(&mut self) -> Result<()> {
+        self.inner = None;
+        self.inner = Some(self.endpoint.connect(self.timeout)?);
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.inner()?.set_timeout(timeout)
+    }
+}
+
+impl Read for LazyTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.inner()?.read(buf)
+    }
+}
+
+impl Write for LazyTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.inner()?.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner()?.flush()
+    }
+}
+
+impl std::fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Endpoint::Tcp(socket) => write!(f, "socket {}", socket),
+            Endpoint::Serial(s) => write!(
+                f,
+                "serial {} ({} baud, parity {}, {} stop bit(s))",
+                s.path, s.baud_rate, s.parity, s.stop_bits
+            ),
+        }
+    }
+}
+
+/// Telnet connection over TCP, with the same timeout for reading and writing.
+pub struct TcpTransport {
+    socket: SocketAddrV4,
+    timeout: Duration,
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    pub fn connect(socket: SocketAddrV4, timeout: Duration) -> Result<TcpTransport> {
+        let stream = TcpTransport::open(socket, timeout)?;
+        Ok(TcpTransport {
+            socket,
+            timeout,
+            stream,
+        })
+    }
+
+    fn open(socket: SocketAddrV4, timeout: Duration) -> Result<TcpStream> {
+        let stream = TcpStream::connect(socket)?;
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+}
+
+impl Transport for TcpTransport {
+    fn reconnect(&mut self) -> Result<()> {
+        self.stream = TcpTransport::open(self.socket, self.timeout)?;
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.stream.set_read_timeout(Some(timeout))
+    }
+}
+
+impl Read for TcpTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TcpTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// Settings of the RS-232/RS-485 line, always with 8 data bits.
+#[derive(Debug, Clone)]
+pub struct SerialSettings {
+    pub path: String,
+    pub baud_rate: u32,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl SerialSettings {
+    /// Parse the parity (none, odd, even) and the stop bits (1, 2) as given from the CLI.
+    pub fn new(path: &str, baud_rate: u32, parity: &str, stop_bits: &str) -> SerialSettings {
+        let parity = match parity {
+            "none" => Parity::None,
+            "odd" => Parity::Odd,
+            "even" => Parity::Even,
+            p => panic!("invalid parity {}, use none, odd, or even", p),
+        };
+        let stop_bits = match stop_bits {
+            "1" => StopBits::One,
+            "2" => StopBits::Two,
+            s => panic!("invalid stop bits {}, use 1 or 2", s),
+        };
+        SerialSettings {
+            path: String::from(path),
+            baud_rate,
+            parity,
+            stop_bits,
+        }
+    }
+}
+
+/// Serial connection to a tty device, opened with exclusive access.
+pub struct SerialTransport {
+    settings: SerialSettings,
+    timeout: Duration,
+    port: Option<Box<dyn SerialPort>>,
+}
+
+impl SerialTransport {
+    pub fn open(settings: SerialSettings, timeout: Duration) -> Result<SerialTransport> {
+        let port = SerialTransport::open_port(&settings, timeout)?;
+        Ok(SerialTransport {
+            settings,
+            timeout,
+            port: Some(port),
+        })
+    }
+
+    fn open_port(settings: &SerialSettings, timeout: Duration) -> Result<Box<dyn SerialPort>> {
+        let port = serialport::new(&settings.path, settings.baud_rate)
+            .data_bits(DataBits::Eight)
+            .parity(settings.parity)
+            .stop_bits(settings.stop_bits)
+            .timeout(timeout)
+            .open()?;
+        Ok(port)
+    }
+
+    fn port(&mut self) -> Result<&mut Box<dyn SerialPort>> {
+        self.port.as_mut().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::NotConnected, "serial port not open")
+        })
+    }
+}
+
+impl Transport for SerialTransport {
+    /// Close the port first to release the exclusive lock.
+    fn reconnect(&mut self) -> Result<()> {
+        self.port = None;
+        self.port = Some(SerialTransport::open_port(&self.settings, self.timeout)?);
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.port()?.set_timeout(timeout)?;
+        Ok(())
+    }
+}
+
+impl Read for SerialTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.port()?.read(buf)
+    }
+}
+
+impl Write for SerialTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.port()?.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.port()?.flush()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::protocol::{Command, Reading};
+    use serialport::TTYPort;
+
+    #[test]
+    fn serial_over_pseudo_terminal() {
+        let (mut amplifier, slave) = TTYPort::pair().unwrap();
+        let path = slave.name().unwrap();
+        let settings = SerialSettings::new(&path, 9600, "none", "1");
+        let endpoint = Endpoint::Serial(settings);
+        let mut transport = endpoint.connect(Duration::from_secs(1)).unwrap();
+        let cmd = Command::Net.to_bytes();
+        transport.write_all(&cmd).unwrap();
+        let mut received = vec![0; cmd.len()];
+        amplifier.read_exact(&mut received).unwrap();
+        assert_eq!(received, cmd);
+        amplifier.write_all(b"N +01234.5\r\n").unwrap();
+        let mut buffer = [0; 32];
+        let u = transport.read(&mut buffer).unwrap();
+        let reading = Reading::parse(std::str::from_utf8(&buffer[..u]).unwrap()).unwrap();
+        assert_eq!(reading.value, 1234.5);
+        transport.reconnect().unwrap();
+    }
+}